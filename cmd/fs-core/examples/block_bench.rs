@@ -0,0 +1,92 @@
+//! Random 4 KiB reads and writes on a device through each block backend.
+//!
+//!     cargo run --release --example block_bench -- /dev/loop0
+//!
+//! Overwrites the device.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use clap::Parser;
+use fs_core::block::{BlockDevice, BlockStore, UringStore};
+use fs_core::config::{IoBackend, IoMode};
+
+const BLOCK_SIZE: usize = 4096;
+
+#[derive(Parser)]
+struct Args {
+    /// Device or image file to benchmark on
+    device: String,
+
+    /// Threads doing IO at once
+    #[arg(long, default_value_t = 16)]
+    threads: usize,
+
+    /// Reads, then writes, per thread
+    #[arg(long, default_value_t = 20000)]
+    ops: usize,
+
+    #[arg(long, value_enum, default_value_t = IoMode::Direct)]
+    io_mode: IoMode,
+}
+
+fn open(args: &Args, backend: IoBackend) -> std::io::Result<Arc<dyn BlockStore>> {
+    let path = &args.device;
+    Ok(match (backend, args.io_mode) {
+        (IoBackend::Sync, IoMode::Buffered) => Arc::new(BlockDevice::open(path, BLOCK_SIZE)?),
+        (IoBackend::Sync, IoMode::Direct) => Arc::new(BlockDevice::open_direct(path, BLOCK_SIZE)?),
+        (IoBackend::Uring, IoMode::Buffered) => Arc::new(UringStore::open(path, BLOCK_SIZE)?),
+        (IoBackend::Uring, IoMode::Direct) => Arc::new(UringStore::open_direct(path, BLOCK_SIZE)?),
+    })
+}
+
+/// Runs `ops` random reads or writes on each of `threads` threads and
+/// returns the operations per second.
+fn run(store: &Arc<dyn BlockStore>, args: &Args, write: bool) -> f64 {
+    let blocks = store.block_count().unwrap();
+    let start = Instant::now();
+    let workers: Vec<_> = (0..args.threads)
+        .map(|thread| {
+            let store = store.clone();
+            let ops = args.ops;
+            std::thread::spawn(move || {
+                let mut buf = vec![thread as u8; BLOCK_SIZE];
+                let mut state = thread as u64 * 0x9e37_79b9_7f4a_7c15 + 1;
+                for _ in 0..ops {
+                    // xorshift64
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    let block = state % blocks;
+                    if write {
+                        store.write_block(block, &buf).unwrap();
+                    } else {
+                        store.read_block(block, &mut buf).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    (args.threads * args.ops) as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+    println!(
+        "{} threads, {} ops each, {:?} IO on {}",
+        args.threads, args.ops, args.io_mode, args.device
+    );
+    println!("{:<8} {:>12} {:>12}", "backend", "read IOPS", "write IOPS");
+    for backend in [IoBackend::Sync, IoBackend::Uring] {
+        let store = open(&args, backend)?;
+        let read = run(&store, &args, false);
+        let write = run(&store, &args, true);
+        store.flush()?;
+        let name = format!("{:?}", backend);
+        println!("{:<8} {:>12.0} {:>12.0}", name, read, write);
+    }
+    Ok(())
+}
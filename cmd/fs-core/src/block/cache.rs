@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use lru::LruCache;
+
+use super::BlockStore;
+use crate::metrics::metrics;
+
+/// A cached block, and whether it changed since it was last written to the
+/// wrapped store.
+struct Entry {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// When the block last changed, see [`State::version`].
+    version: u64,
+}
+
+struct State {
+    blocks: LruCache<u64, Entry>,
+    /// Dirty blocks evicted from `blocks` and not yet written back. Reads
+    /// still find them here.
+    evicted: HashMap<u64, Entry>,
+    /// Bumped whenever the wrapped store may hold something newer than a
+    /// read from it that started before, so that read is not cached.
+    epoch: u64,
+    /// Bumped on every change to a cached block, so a block written back
+    /// is only marked clean if it did not change while being written.
+    version: u64,
+}
+
+/// A dirty block as it was when taken for writing back.
+struct Snapshot {
+    block: u64,
+    data: Box<[u8]>,
+    version: u64,
+}
+
+/// Keeps the most recently used blocks of the wrapped store in memory.
+///
+/// Writes of whole blocks only change the cache; the blocks are written to
+/// the wrapped store when evicted and on [`BlockStore::write_back`] and
+/// [`BlockStore::flush`]. Blocks another node wrote must be dropped with
+/// [`BlockStore::invalidate`] before they are read again.
+pub struct BufferCache<S: BlockStore> {
+    inner: S,
+    state: Mutex<State>,
+    /// Held while cached blocks are written to `inner`, without holding
+    /// `state`, so two writes of one block land in the order they were
+    /// taken from the cache. Taken before `state`.
+    writing: Mutex<()>,
+    /// Size of `inner` in blocks, asked again only when a write goes past
+    /// it, as after the device was grown.
+    block_count: AtomicU64,
+}
+
+impl<S: BlockStore> BufferCache<S> {
+    /// Caches up to `capacity` blocks of `inner`.
+    pub fn new(inner: S, capacity: NonZeroUsize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                blocks: LruCache::new(capacity),
+                evicted: HashMap::new(),
+                epoch: 0,
+                version: 0,
+            }),
+            writing: Mutex::new(()),
+            block_count: AtomicU64::new(inner.block_count().unwrap_or(0)),
+            inner,
+        }
+    }
+
+    fn read_one(&self, block: u64, buf: &mut [u8]) -> io::Result<()> {
+        let epoch = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(entry) = state.blocks.get(&block) {
+                buf.copy_from_slice(&entry.data[..buf.len()]);
+                metrics().block_cache(true);
+                return Ok(());
+            }
+            if let Some(entry) = state.evicted.get(&block) {
+                buf.copy_from_slice(&entry.data[..buf.len()]);
+                metrics().block_cache(true);
+                return Ok(());
+            }
+            state.epoch
+        };
+
+        metrics().block_cache(false);
+        self.inner.read_block(block, buf)?;
+        // Only whole blocks are cached.
+        if buf.len() == self.block_size() {
+            let mut state = self.state.lock().unwrap();
+            if state.epoch == epoch
+                && !state.blocks.contains(&block)
+                && !state.evicted.contains_key(&block)
+            {
+                let entry = Entry {
+                    data: buf.into(),
+                    dirty: false,
+                    version: 0,
+                };
+                if Self::insert(&mut state, block, entry) {
+                    drop(state);
+                    self.write_evicted()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_one(&self, block: u64, buf: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.version += 1;
+        let version = state.version;
+        if let Some(entry) = state.blocks.get_mut(&block) {
+            entry.data[..buf.len()].copy_from_slice(buf);
+            entry.dirty = true;
+            entry.version = version;
+            return Ok(());
+        }
+        let entry = if let Some(mut entry) = state.evicted.remove(&block) {
+            // Back into the cache; the pending write of the old data is
+            // skipped.
+            entry.data[..buf.len()].copy_from_slice(buf);
+            entry.version = version;
+            entry
+        } else if buf.len() < self.block_size() {
+            // The rest of the block is not cached, so write through.
+            state.epoch += 1;
+            return self.inner.write_block(block, buf);
+        } else {
+            Entry {
+                data: buf.into(),
+                dirty: true,
+                version,
+            }
+        };
+        if Self::insert(&mut state, block, entry) {
+            drop(state);
+            self.write_evicted()?;
+        }
+        Ok(())
+    }
+
+    /// Caches `entry`, and returns whether the block it evicts is dirty and
+    /// has to be written with [`Self::write_evicted`].
+    fn insert(state: &mut State, block: u64, entry: Entry) -> bool {
+        match state.blocks.push(block, entry) {
+            Some((evicted, old)) if evicted != block && old.dirty => {
+                state.evicted.insert(evicted, old);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Writes the dirty blocks evicted from the cache to the wrapped store.
+    fn write_evicted(&self) -> io::Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        let evicted: Vec<Snapshot> = {
+            let state = self.state.lock().unwrap();
+            state
+                .evicted
+                .iter()
+                .map(|(&block, entry)| Snapshot {
+                    block,
+                    data: entry.data.clone(),
+                    version: entry.version,
+                })
+                .collect()
+        };
+        self.write_snapshots(evicted)
+    }
+
+    /// Writes `dirty` to the wrapped store, runs of consecutive blocks in
+    /// one write each, and marks each block clean unless it changed since
+    /// its snapshot was taken. Called with `writing` held.
+    fn write_snapshots(&self, mut dirty: Vec<Snapshot>) -> io::Result<()> {
+        dirty.sort_unstable_by_key(|snapshot| snapshot.block);
+        for run in dirty.chunk_by(|a, b| a.block + 1 == b.block) {
+            let mut buf = Vec::with_capacity(run.len() * self.block_size());
+            for snapshot in run {
+                buf.extend_from_slice(&snapshot.data);
+            }
+            self.inner.write_block(run[0].block, &buf)?;
+
+            let mut state = self.state.lock().unwrap();
+            state.epoch += 1;
+            for snapshot in run {
+                if let Some(entry) = state.blocks.peek_mut(&snapshot.block) {
+                    if entry.version == snapshot.version {
+                        entry.dirty = false;
+                    }
+                } else if state
+                    .evicted
+                    .get(&snapshot.block)
+                    .is_some_and(|entry| entry.version == snapshot.version)
+                {
+                    state.evicted.remove(&snapshot.block);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Dirty blocks among the `count` blocks from `block_num`.
+    fn dirty(state: &State, block_num: u64, count: u64) -> Vec<Snapshot> {
+        let range = block_num..block_num.saturating_add(count);
+        state
+            .blocks
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .chain(state.evicted.iter())
+            .filter(|(block, _)| range.contains(block))
+            .map(|(&block, entry)| Snapshot {
+                block,
+                data: entry.data.clone(),
+                version: entry.version,
+            })
+            .collect()
+    }
+
+    /// Writes back the dirty blocks among the `count` blocks from
+    /// `block_num`, then removes them all from the cache.
+    fn write_back_and_remove(&self, block_num: u64, count: u64) -> io::Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        loop {
+            let mut state = self.state.lock().unwrap();
+            let dirty = Self::dirty(&state, block_num, count);
+            if dirty.is_empty() {
+                Self::remove(&mut state, block_num, count);
+                return Ok(());
+            }
+            drop(state);
+            tracing::warn!(
+                "Writing back {} changed blocks from {} before dropping them",
+                dirty.len(),
+                block_num
+            );
+            self.write_snapshots(dirty)?;
+        }
+    }
+
+    /// Removes the `count` blocks from `block_num` from the cache and then
+    /// applies `op` to them in the wrapped store, without holding `state`
+    /// meanwhile. Blocks read in the meantime may have been cached as they
+    /// were before, so they are dropped again; blocks written are kept.
+    fn remove_for(
+        &self,
+        block_num: u64,
+        count: u64,
+        op: impl FnOnce(&S) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        Self::remove(&mut self.state.lock().unwrap(), block_num, count);
+        let result = op(&self.inner);
+
+        let range = block_num..block_num.saturating_add(count);
+        let mut state = self.state.lock().unwrap();
+        let stale: Vec<u64> = state
+            .blocks
+            .iter()
+            .filter(|(block, entry)| range.contains(block) && !entry.dirty)
+            .map(|(&block, _)| block)
+            .collect();
+        state.epoch += 1;
+        for block in stale {
+            state.blocks.pop(&block);
+        }
+        result
+    }
+
+    /// Removes the cached blocks in `count` blocks from `block_num`.
+    fn remove(state: &mut State, block_num: u64, count: u64) {
+        let range = block_num..block_num.saturating_add(count);
+        let blocks: Vec<u64> = state
+            .blocks
+            .iter()
+            .map(|(&block, _)| block)
+            .filter(|block| range.contains(block))
+            .collect();
+        state.epoch += 1;
+        for block in blocks {
+            state.blocks.pop(&block);
+        }
+        state.evicted.retain(|block, _| !range.contains(block));
+    }
+}
+
+impl<S: BlockStore> BlockStore for BufferCache<S> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> io::Result<()> {
+        let block_size = self.block_size();
+        for (i, chunk) in buf.chunks_mut(block_size).enumerate() {
+            let block = block_num
+                .checked_add(i as u64)
+                .ok_or_else(|| super::out_of_range(block_num, "store"))?;
+            self.read_one(block, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, block_num: u64, buf: &[u8]) -> io::Result<()> {
+        let block_size = self.block_size();
+        // Fail now rather than on write-back.
+        let end = block_num
+            .checked_add(buf.len().div_ceil(block_size) as u64)
+            .ok_or_else(|| super::out_of_range(block_num, "store"))?;
+        if end > self.block_count.load(Ordering::Relaxed) {
+            let blocks = self.inner.block_count()?;
+            self.block_count.store(blocks, Ordering::Relaxed);
+            if end > blocks {
+                return Err(super::out_of_range(block_num, "store"));
+            }
+        }
+        for (i, chunk) in buf.chunks(block_size).enumerate() {
+            self.write_one(block_num + i as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.write_back()?;
+        self.inner.flush()
+    }
+
+    fn discard(&self, block_num: u64, count: u64) -> io::Result<()> {
+        self.remove_for(block_num, count, |inner| inner.discard(block_num, count))
+    }
+
+    fn trim(&self, block_num: u64, count: u64) -> io::Result<()> {
+        self.remove_for(block_num, count, |inner| inner.trim(block_num, count))
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    /// Writes the dirty blocks, runs of consecutive blocks in one write
+    /// each.
+    fn write_back(&self) -> io::Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        let dirty = Self::dirty(&self.state.lock().unwrap(), 0, u64::MAX);
+        self.write_snapshots(dirty)?;
+        self.inner.write_back()
+    }
+
+    /// Writes back the dirty blocks among them first: they hold changes
+    /// made under a lock this node held, which must not be lost. Nothing
+    /// is dropped if that fails.
+    fn invalidate(&self, block_num: u64, count: u64) -> io::Result<()> {
+        self.write_back_and_remove(block_num, count)?;
+        self.inner.invalidate(block_num, count)
+    }
+
+    /// Writes back the dirty blocks first, like [`BlockStore::invalidate`].
+    fn invalidate_all(&self) -> io::Result<()> {
+        self.write_back_and_remove(0, u64::MAX)?;
+        self.inner.invalidate_all()
+    }
+
+    fn forget_changes(&self, block_num: u64, count: u64) {
+        let _writing = self.writing.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let dirty = Self::dirty(&state, block_num, count);
+        if !dirty.is_empty() {
+            tracing::warn!(
+                "Dropped {} changed blocks from {} made under a lost lock",
+                dirty.len(),
+                block_num
+            );
+            for snapshot in dirty {
+                state.blocks.pop(&snapshot.block);
+                state.evicted.remove(&snapshot.block);
+            }
+            state.epoch += 1;
+        }
+        drop(state);
+        self.inner.forget_changes(block_num, count)
+    }
+}
+
+impl<S: BlockStore> Drop for BufferCache<S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_back() {
+            tracing::error!("Failed to write back cached blocks: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Fault, Faulty, MemoryStore, Rule};
+
+    const BLOCK: usize = 16;
+
+    fn cache(store: &MemoryStore, capacity: usize) -> BufferCache<MemoryStore> {
+        BufferCache::new(store.clone(), NonZeroUsize::new(capacity).unwrap())
+    }
+
+    fn read(store: &dyn BlockStore, block: u64) -> u8 {
+        let mut buf = [0u8; BLOCK];
+        store.read_block(block, &mut buf).unwrap();
+        buf[0]
+    }
+
+    fn write(store: &dyn BlockStore, block: u64, byte: u8) {
+        store.write_block(block, &[byte; BLOCK]).unwrap();
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let store = MemoryStore::new(BLOCK, 8);
+        let cache = cache(&store, 2);
+        read(&cache, 0);
+        read(&cache, 1);
+        read(&cache, 0);
+        read(&cache, 2);
+
+        // Changed behind the cache's back: only evicted blocks see it.
+        write(&store, 0, 1);
+        write(&store, 1, 1);
+        write(&store, 2, 1);
+        assert_eq!(read(&cache, 0), 0);
+        assert_eq!(read(&cache, 2), 0);
+        assert_eq!(read(&cache, 1), 1);
+    }
+
+    #[test]
+    fn writes_back_dirty_block_on_eviction() {
+        let store = MemoryStore::new(BLOCK, 8);
+        let cache = cache(&store, 2);
+        write(&cache, 0, 7);
+        write(&cache, 1, 8);
+        assert_eq!(read(&store, 0), 0);
+
+        write(&cache, 2, 9);
+        assert_eq!(read(&store, 0), 7);
+        assert_eq!(read(&store, 1), 0);
+        assert_eq!(read(&cache, 0), 7);
+    }
+
+    #[test]
+    fn flush_writes_dirty_blocks() {
+        let store = MemoryStore::new(BLOCK, 8);
+        let cache = cache(&store, 4);
+        write(&cache, 0, 1);
+        write(&cache, 1, 2);
+        write(&cache, 3, 3);
+        assert_eq!(read(&store, 1), 0);
+
+        cache.flush().unwrap();
+        assert_eq!(read(&store, 0), 1);
+        assert_eq!(read(&store, 1), 2);
+        assert_eq!(read(&store, 3), 3);
+        // Still cached, and clean.
+        write(&store, 1, 5);
+        assert_eq!(read(&cache, 1), 2);
+        cache.flush().unwrap();
+        assert_eq!(read(&store, 1), 5);
+    }
+
+    #[test]
+    fn invalidate_writes_back_dirty_blocks() {
+        let store = MemoryStore::new(BLOCK, 8);
+        let cache = cache(&store, 4);
+        write(&cache, 0, 1);
+        write(&cache, 1, 2);
+
+        cache.invalidate(0, 1).unwrap();
+        assert_eq!(read(&store, 0), 1);
+        assert_eq!(read(&store, 1), 0);
+
+        write(&store, 0, 3);
+        assert_eq!(read(&cache, 0), 3);
+        cache.invalidate_all().unwrap();
+        assert_eq!(read(&store, 1), 2);
+    }
+
+    #[test]
+    fn invalidate_keeps_blocks_it_cannot_write() {
+        let store = MemoryStore::new(BLOCK, 8);
+        let faulty = Faulty::new(store.clone());
+        faulty.inject(Rule::write(0..1, Fault::Error).times(1));
+        let cache = BufferCache::new(faulty, NonZeroUsize::new(4).unwrap());
+        write(&cache, 0, 1);
+
+        assert!(cache.invalidate(0, 1).is_err());
+        assert_eq!(read(&cache, 0), 1);
+        cache.invalidate(0, 1).unwrap();
+        assert_eq!(read(&store, 0), 1);
+    }
+
+    #[test]
+    fn forget_changes_drops_without_writing() {
+        let store = MemoryStore::new(BLOCK, 8);
+        let cache = cache(&store, 4);
+        write(&cache, 0, 1);
+        write(&cache, 1, 2);
+
+        cache.forget_changes(0, 1);
+        assert_eq!(read(&cache, 0), 0);
+        cache.flush().unwrap();
+        assert_eq!(read(&store, 0), 0);
+        assert_eq!(read(&store, 1), 2);
+    }
+
+    #[test]
+    fn write_past_the_store_is_refused() {
+        let store = MemoryStore::new(BLOCK, 8);
+        let cache = cache(&store, 4);
+        write(&cache, 7, 1);
+        assert!(cache.write_block(8, &[1; BLOCK]).is_err());
+        assert!(cache.write_block(7, &[1; 2 * BLOCK]).is_err());
+    }
+
+    #[test]
+    fn discard_drops_cached_blocks() {
+        let store = MemoryStore::new(BLOCK, 8);
+        let cache = cache(&store, 4);
+        write(&cache, 0, 1);
+        write(&cache, 1, 2);
+        cache.flush().unwrap();
+
+        cache.discard(0, 1).unwrap();
+        assert_eq!(read(&cache, 0), 0);
+        assert_eq!(read(&cache, 1), 2);
+    }
+}
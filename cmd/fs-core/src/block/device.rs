@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::path::Path;
+
+use super::BlockStore;
+
+/// `_IOR(0x12, 114, size_t)` on 64-bit Linux; the libc crate lacks it.
+const BLKGETSIZE64: libc::Ioctl = 0x8008_1272;
+/// `_IO(0x12, 119)`; the libc crate lacks it.
+const BLKDISCARD: libc::Ioctl = 0x1277;
+
+/// Sizes the kernel reports for a block device. For an image file, its file
+/// system's block size stands in for both sector sizes.
+#[derive(Debug, Clone, Copy)]
+pub struct Geometry {
+    /// Smallest unit the device can address; `O_DIRECT` IO must be aligned
+    /// to it.
+    pub logical_sector_size: usize,
+    /// Unit the device writes internally; smaller writes cost a
+    /// read-modify-write.
+    pub physical_sector_size: usize,
+    /// Bytes on the device.
+    pub size: u64,
+}
+
+impl Geometry {
+    pub fn of(file: &File) -> std::io::Result<Self> {
+        let metadata = file.metadata()?;
+        if !metadata.file_type().is_block_device() {
+            return Ok(Self {
+                logical_sector_size: metadata.blksize() as usize,
+                physical_sector_size: metadata.blksize() as usize,
+                size: metadata.len(),
+            });
+        }
+
+        let mut logical: libc::c_int = 0;
+        let mut physical: libc::c_uint = 0;
+        let mut size: u64 = 0;
+        // SAFETY: each request writes one value of the type pointed to.
+        unsafe {
+            ioctl(file, libc::BLKSSZGET, &mut logical)?;
+            ioctl(file, libc::BLKPBSZGET, &mut physical)?;
+            ioctl(file, BLKGETSIZE64, &mut size)?;
+        }
+        Ok(Self {
+            logical_sector_size: logical as usize,
+            physical_sector_size: physical as usize,
+            size,
+        })
+    }
+}
+
+/// # Safety
+/// `request` must access at most one `T` through its argument.
+unsafe fn ioctl<T>(file: &File, request: libc::Ioctl, value: &mut T) -> std::io::Result<()> {
+    if libc::ioctl(file.as_raw_fd(), request, value as *mut T) < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Deallocates `len` bytes at `pos`, which read as zeros afterwards: punches
+/// a hole in an image file, or has a block device zero the range, unmapping
+/// it where it can. Returns `false` if the file system or device cannot.
+pub(super) fn punch_hole(file: &File, pos: u64, len: u64) -> std::io::Result<bool> {
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // SAFETY: fallocate only takes plain values.
+    if unsafe { libc::fallocate(file.as_raw_fd(), mode, pos as i64, len as i64) } < 0 {
+        let e = std::io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) => Ok(false),
+            _ => Err(e),
+        };
+    }
+    Ok(true)
+}
+
+/// Lets a block device deallocate `len` bytes at `pos` (`BLKDISCARD`),
+/// leaving them to read as anything. Image files get a hole punched
+/// instead. Does nothing where neither is supported.
+pub(super) fn trim(file: &File, pos: u64, len: u64) -> std::io::Result<()> {
+    if !file.metadata()?.file_type().is_block_device() {
+        return punch_hole(file, pos, len).map(|_| ());
+    }
+    let mut range = [pos, len];
+    // SAFETY: BLKDISCARD reads the start and length from a [u64; 2].
+    match unsafe { ioctl(file, BLKDISCARD, &mut range) } {
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+        result => result,
+    }
+}
+
+/// Opens `path` with `O_DIRECT` and returns it with the sector size IO on it
+/// must be aligned to, checking that blocks of `block_size` are.
+pub(super) fn open_direct<P: AsRef<Path>>(
+    path: P,
+    block_size: usize,
+) -> std::io::Result<(File, usize)> {
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(&path)
+        .map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Failed to open {:?} with O_DIRECT: {}", path.as_ref(), e),
+            )
+        })?;
+    let sector_size = Geometry::of(&file)?.logical_sector_size;
+    if !block_size.is_multiple_of(sector_size) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Block size {} is not a multiple of the sector size {}",
+                block_size, sector_size
+            ),
+        ));
+    }
+    Ok((file, sector_size))
+}
+
+/// A buffer at an address that is a multiple of `align`, as `O_DIRECT` IO
+/// requires.
+pub(super) struct AlignedBuf {
+    storage: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    pub(super) fn new(len: usize, align: usize) -> Self {
+        let storage = vec![0u8; len + align];
+        let start = storage.as_ptr().align_offset(align);
+        Self {
+            storage,
+            start,
+            len,
+        }
+    }
+
+    pub(super) fn as_slice(&self) -> &[u8] {
+        &self.storage[self.start..self.start + self.len]
+    }
+
+    pub(super) fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.storage[self.start..self.start + self.len]
+    }
+}
+
+/// An image file or block device, read and written in place with
+/// positional IO.
+pub struct BlockDevice {
+    file: File,
+    block_size: usize,
+    /// Sector size all IO is aligned to when it bypasses the page cache.
+    direct: Option<usize>,
+    /// Whether discarded blocks are deallocated rather than overwritten
+    /// with zeros.
+    deallocate: bool,
+}
+
+impl BlockDevice {
+    pub fn open<P: AsRef<Path>>(path: P, block_size: usize) -> std::io::Result<Self> {
+        let file = File::options().read(true).write(true).open(path)?;
+        Ok(Self {
+            file,
+            block_size,
+            direct: None,
+            deallocate: false,
+        })
+    }
+
+    /// Opens the device for IO that bypasses the page cache (`O_DIRECT`),
+    /// so blocks other nodes wrote to a shared device are never served
+    /// stale from it.
+    pub fn open_direct<P: AsRef<Path>>(path: P, block_size: usize) -> std::io::Result<Self> {
+        let (file, sector_size) = open_direct(path, block_size)?;
+        Ok(Self {
+            file,
+            block_size,
+            direct: Some(sector_size),
+            deallocate: false,
+        })
+    }
+
+    /// Deallocates discarded blocks where the device or file system can,
+    /// which thin-provisioned devices need to get the space back, instead
+    /// of writing zeros over them.
+    pub fn with_discard(mut self, enabled: bool) -> Self {
+        self.deallocate = enabled;
+        self
+    }
+
+    pub fn geometry(&self) -> std::io::Result<Geometry> {
+        Geometry::of(&self.file)
+    }
+
+    fn position(&self, block_num: u64) -> std::io::Result<u64> {
+        block_num
+            .checked_mul(self.block_size as u64)
+            .ok_or_else(|| super::out_of_range(block_num, "device"))
+    }
+
+    fn read_direct(&self, sector_size: usize, pos: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let mut aligned = AlignedBuf::new(buf.len().next_multiple_of(sector_size), sector_size);
+        self.file.read_exact_at(aligned.as_mut_slice(), pos)?;
+        buf.copy_from_slice(&aligned.as_slice()[..buf.len()]);
+        Ok(())
+    }
+
+    fn write_direct(&self, sector_size: usize, pos: u64, buf: &[u8]) -> std::io::Result<()> {
+        let mut aligned = AlignedBuf::new(buf.len().next_multiple_of(sector_size), sector_size);
+        if aligned.len != buf.len() {
+            // The rest of the last sector keeps what it holds.
+            self.file.read_exact_at(aligned.as_mut_slice(), pos)?;
+        }
+        aligned.as_mut_slice()[..buf.len()].copy_from_slice(buf);
+        self.file.write_all_at(aligned.as_slice(), pos)
+    }
+}
+
+impl BlockStore for BlockDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let pos = self.position(block_num)?;
+        match self.direct {
+            Some(sector_size) => self.read_direct(sector_size, pos, buf),
+            None => self.file.read_exact_at(buf, pos),
+        }
+    }
+
+    fn write_block(&self, block_num: u64, buf: &[u8]) -> std::io::Result<()> {
+        let pos = self.position(block_num)?;
+        match self.direct {
+            Some(sector_size) => self.write_direct(sector_size, pos, buf),
+            None => self.file.write_all_at(buf, pos),
+        }
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn discard(&self, block_num: u64, count: u64) -> std::io::Result<()> {
+        let len = count.saturating_mul(self.block_size as u64);
+        if self.deallocate && punch_hole(&self.file, self.position(block_num)?, len)? {
+            return Ok(());
+        }
+        let zeros = vec![0u8; self.block_size];
+        for block in block_num..block_num + count {
+            self.write_block(block, &zeros)?;
+        }
+        Ok(())
+    }
+
+    fn trim(&self, block_num: u64, count: u64) -> std::io::Result<()> {
+        let len = count.saturating_mul(self.block_size as u64);
+        trim(&self.file, self.position(block_num)?, len)
+    }
+
+    /// Size of the device or image file.
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.geometry()?.size)
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::BlockStore;
+
+/// What an IO a [`Rule`] matches does instead of succeeding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Fails with `EIO` without touching the store.
+    Error,
+    /// Writes only the first `n` bytes, then fails.
+    ShortWrite(usize),
+    /// Writes only the first `n` bytes, and reports success, as a write
+    /// cut short by a crash does.
+    TornWrite(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Io {
+    Read,
+    Write,
+    Flush,
+}
+
+/// Injects `fault` into the IO on a range of blocks.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    io: Io,
+    blocks: Range<u64>,
+    fault: Fault,
+    /// Matching IO left before the rule is used up; `None` for every one.
+    remaining: Option<u32>,
+}
+
+impl Rule {
+    /// Reads touching `blocks` fail. Faults other than [`Fault::Error`]
+    /// make no difference for reads.
+    pub fn read(blocks: Range<u64>) -> Self {
+        Self {
+            io: Io::Read,
+            blocks,
+            fault: Fault::Error,
+            remaining: None,
+        }
+    }
+
+    /// Writes touching `blocks` suffer `fault`, counting bytes from the
+    /// start of the write. Discards and trims touching them fail.
+    pub fn write(blocks: Range<u64>, fault: Fault) -> Self {
+        Self {
+            io: Io::Write,
+            blocks,
+            fault,
+            remaining: None,
+        }
+    }
+
+    /// Flushes fail, and the writes before them stay unflushed.
+    pub fn flush() -> Self {
+        Self {
+            io: Io::Flush,
+            blocks: 0..u64::MAX,
+            fault: Fault::Error,
+            remaining: None,
+        }
+    }
+
+    /// Applies to the next `n` matching IOs only.
+    pub fn times(mut self, n: u32) -> Self {
+        self.remaining = Some(n);
+        self
+    }
+}
+
+#[derive(Default)]
+struct Script {
+    rules: Vec<Rule>,
+    latency: Duration,
+    /// What the blocks written since the last flush held before, to put
+    /// back on a power loss.
+    unflushed: HashMap<u64, Vec<u8>>,
+}
+
+impl Script {
+    /// The fault for IO on `blocks`, using up one of the first matching
+    /// rule's uses.
+    fn take(&mut self, io: Io, blocks: Range<u64>) -> Option<Fault> {
+        let i = self.rules.iter().position(|rule| {
+            rule.io == io && rule.blocks.start < blocks.end && blocks.start < rule.blocks.end
+        })?;
+        let fault = self.rules[i].fault;
+        if let Some(remaining) = &mut self.rules[i].remaining {
+            *remaining -= 1;
+            if *remaining == 0 {
+                self.rules.remove(i);
+            }
+        }
+        Some(fault)
+    }
+}
+
+/// Fails, tears or delays IO on the wrapped store as scripted, for testing
+/// how the filesystem copes. Clones share the store and the script, so a
+/// test can hand one to a filesystem and script faults afterwards.
+pub struct Faulty<S> {
+    inner: Arc<S>,
+    script: Arc<Mutex<Script>>,
+}
+
+impl<S> Clone for Faulty<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            script: self.script.clone(),
+        }
+    }
+}
+
+impl<S: BlockStore> Faulty<S> {
+    /// Passes IO through to `inner` until faults are injected.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            script: Arc::default(),
+        }
+    }
+
+    /// Adds `rule`. Where rules overlap, the one added first applies.
+    pub fn inject(&self, rule: Rule) {
+        self.script.lock().unwrap().rules.push(rule);
+    }
+
+    /// Removes all rules and the latency.
+    pub fn clear(&self) {
+        let mut script = self.script.lock().unwrap();
+        script.rules.clear();
+        script.latency = Duration::ZERO;
+    }
+
+    /// Delays every read, write and flush by `latency`.
+    pub fn set_latency(&self, latency: Duration) {
+        self.script.lock().unwrap().latency = latency;
+    }
+
+    /// Undoes every write since the last successful flush, as losing power
+    /// does to writes still in the device's volatile cache.
+    pub fn power_loss(&self) -> io::Result<()> {
+        let unflushed = std::mem::take(&mut self.script.lock().unwrap().unflushed);
+        for (block, data) in unflushed {
+            self.inner.write_block(block, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Blocks of `len` bytes of IO at `block_num`.
+    fn blocks(&self, block_num: u64, len: usize) -> Range<u64> {
+        let count = len.div_ceil(self.inner.block_size()).max(1) as u64;
+        block_num..block_num.saturating_add(count)
+    }
+
+    /// Sleeps for the latency, and returns the fault to inject, if any.
+    fn before(&self, io: Io, blocks: Range<u64>) -> Option<Fault> {
+        let (latency, fault) = {
+            let mut script = self.script.lock().unwrap();
+            (script.latency, script.take(io, blocks))
+        };
+        if !latency.is_zero() {
+            std::thread::sleep(latency);
+        }
+        fault
+    }
+
+    /// Saves what `blocks` hold, unless they were written since the last
+    /// flush already.
+    fn remember(&self, blocks: Range<u64>) -> io::Result<()> {
+        let mut script = self.script.lock().unwrap();
+        let mut buf = vec![0u8; self.inner.block_size()];
+        for block in blocks {
+            if script.unflushed.contains_key(&block) {
+                continue;
+            }
+            self.inner.read_block(block, &mut buf)?;
+            script.unflushed.insert(block, buf.clone());
+        }
+        Ok(())
+    }
+}
+
+fn injected() -> io::Error {
+    io::Error::from_raw_os_error(libc::EIO)
+}
+
+impl<S: BlockStore> BlockStore for Faulty<S> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self.before(Io::Read, self.blocks(block_num, buf.len())) {
+            Some(_) => Err(injected()),
+            None => self.inner.read_block(block_num, buf),
+        }
+    }
+
+    fn write_block(&self, block_num: u64, buf: &[u8]) -> io::Result<()> {
+        let blocks = self.blocks(block_num, buf.len());
+        let fault = self.before(Io::Write, blocks.clone());
+        if fault == Some(Fault::Error) {
+            return Err(injected());
+        }
+        self.remember(blocks)?;
+        match fault {
+            Some(Fault::ShortWrite(n)) => {
+                self.inner
+                    .write_block(block_num, &buf[..n.min(buf.len())])?;
+                Err(io::Error::new(io::ErrorKind::WriteZero, "Short write"))
+            }
+            Some(Fault::TornWrite(n)) => {
+                self.inner.write_block(block_num, &buf[..n.min(buf.len())])
+            }
+            _ => self.inner.write_block(block_num, buf),
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if self.before(Io::Flush, 0..1).is_some() {
+            return Err(injected());
+        }
+        self.inner.flush()?;
+        self.script.lock().unwrap().unflushed.clear();
+        Ok(())
+    }
+
+    fn discard(&self, block_num: u64, count: u64) -> io::Result<()> {
+        let blocks = block_num..block_num.saturating_add(count);
+        if self.before(Io::Write, blocks.clone()).is_some() {
+            return Err(injected());
+        }
+        self.remember(blocks)?;
+        self.inner.discard(block_num, count)
+    }
+
+    fn trim(&self, block_num: u64, count: u64) -> io::Result<()> {
+        let blocks = block_num..block_num.saturating_add(count);
+        if self.before(Io::Write, blocks.clone()).is_some() {
+            return Err(injected());
+        }
+        self.remember(blocks)?;
+        self.inner.trim(block_num, count)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn write_back(&self) -> io::Result<()> {
+        self.inner.write_back()
+    }
+
+    fn invalidate(&self, block_num: u64, count: u64) -> io::Result<()> {
+        self.inner.invalidate(block_num, count)
+    }
+
+    fn invalidate_all(&self) -> io::Result<()> {
+        self.inner.invalidate_all()
+    }
+
+    fn forget_changes(&self, block_num: u64, count: u64) {
+        self.inner.forget_changes(block_num, count)
+    }
+}
@@ -0,0 +1,67 @@
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use super::BlockStore;
+
+/// Blocks held in memory, for tests. Clones share the same blocks, so a
+/// test can hand one to a filesystem and inspect or reuse it afterwards.
+#[derive(Clone)]
+pub struct MemoryStore {
+    data: Arc<RwLock<Vec<u8>>>,
+    block_size: usize,
+}
+
+impl MemoryStore {
+    /// A store of `block_count` zeroed blocks.
+    pub fn new(block_size: usize, block_count: u64) -> Self {
+        Self {
+            data: Arc::new(RwLock::new(vec![0u8; block_size * block_count as usize])),
+            block_size,
+        }
+    }
+
+    /// Byte range of `len` bytes at `block_num` in a store of `size` bytes.
+    fn range(&self, block_num: u64, len: usize, size: usize) -> io::Result<std::ops::Range<usize>> {
+        (block_num as usize)
+            .checked_mul(self.block_size)
+            .filter(|start| start.saturating_add(len) <= size)
+            .map(|start| start..start + len)
+            .ok_or_else(|| super::out_of_range(block_num, "store"))
+    }
+}
+
+impl BlockStore for MemoryStore {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> io::Result<()> {
+        let data = self.data.read().unwrap();
+        let range = self.range(block_num, buf.len(), data.len())?;
+        buf.copy_from_slice(&data[range]);
+        Ok(())
+    }
+
+    fn write_block(&self, block_num: u64, buf: &[u8]) -> io::Result<()> {
+        let mut data = self.data.write().unwrap();
+        let range = self.range(block_num, buf.len(), data.len())?;
+        data[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn discard(&self, block_num: u64, count: u64) -> io::Result<()> {
+        let mut data = self.data.write().unwrap();
+        let len = (count as usize).saturating_mul(self.block_size);
+        let range = self.range(block_num, len, data.len())?;
+        data[range].fill(0);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+}
@@ -0,0 +1,68 @@
+use std::io;
+
+use super::BlockStore;
+use crate::metrics::metrics;
+
+/// Counts the reads and writes passing through to the wrapped store in the
+/// block metrics and traces each of them.
+pub struct Metered<S> {
+    inner: S,
+}
+
+impl<S: BlockStore> Metered<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: BlockStore> BlockStore for Metered<S> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    #[tracing::instrument(name = "block.read", level = "debug", skip(self, buf), fields(bytes = buf.len()))]
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_block(block_num, buf)?;
+        metrics().block_read(buf.len());
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "block.write", level = "debug", skip(self, buf), fields(bytes = buf.len()))]
+    fn write_block(&self, block_num: u64, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_block(block_num, buf)?;
+        metrics().block_written(buf.len());
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn discard(&self, block_num: u64, count: u64) -> io::Result<()> {
+        self.inner.discard(block_num, count)
+    }
+
+    fn trim(&self, block_num: u64, count: u64) -> io::Result<()> {
+        self.inner.trim(block_num, count)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn write_back(&self) -> io::Result<()> {
+        self.inner.write_back()
+    }
+
+    fn invalidate(&self, block_num: u64, count: u64) -> io::Result<()> {
+        self.inner.invalidate(block_num, count)
+    }
+
+    fn invalidate_all(&self) -> io::Result<()> {
+        self.inner.invalidate_all()
+    }
+
+    fn forget_changes(&self, block_num: u64, count: u64) {
+        self.inner.forget_changes(block_num, count)
+    }
+}
@@ -0,0 +1,140 @@
+//! Block storage. All layout code reads and writes through [`BlockStore`],
+//! implemented by [`BlockDevice`] and [`UringStore`] for image files and
+//! block devices and by [`MemoryStore`] for tests, and extended by decorators
+//! wrapping another store: [`Slice`] for a volume's range, [`Metered`] for
+//! metrics and traces, [`BufferCache`] for caching blocks in memory and
+//! [`Faulty`] for injecting faults in tests.
+
+use std::io;
+
+pub mod cache;
+pub mod device;
+pub mod faulty;
+pub mod memory;
+pub mod metered;
+pub mod slice;
+pub mod uring;
+
+pub use cache::BufferCache;
+pub use device::BlockDevice;
+pub use faulty::{Fault, Faulty, Rule};
+pub use memory::MemoryStore;
+pub use metered::Metered;
+pub use slice::Slice;
+pub use uring::UringStore;
+
+/// Storage addressed in fixed-size blocks, numbered from 0.
+///
+/// A read or write at `block_num` covers `buf.len()` bytes from the start of
+/// that block, so one call may span several consecutive blocks. Calls do not
+/// share a file position and may be made from several threads at once.
+pub trait BlockStore: Send + Sync {
+    fn block_size(&self) -> usize;
+
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_block(&self, block_num: u64, buf: &[u8]) -> io::Result<()>;
+
+    /// Makes the writes so far durable.
+    fn flush(&self) -> io::Result<()>;
+
+    /// Tells the store the `count` blocks from `block_num` are no longer in
+    /// use. They read as zeros until written again.
+    fn discard(&self, block_num: u64, count: u64) -> io::Result<()>;
+
+    /// Tells the store nothing reads the `count` blocks from `block_num`
+    /// before writing them, so a thin-provisioned device can release them.
+    /// Unlike after [`BlockStore::discard`], what they read is undefined.
+    fn trim(&self, _block_num: u64, _count: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Bytes addressable through this store.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Number of whole blocks addressable through this store.
+    fn block_count(&self) -> io::Result<u64> {
+        Ok(self.size()? / self.block_size() as u64)
+    }
+
+    /// Writes changes held back by a cache to the device, without making
+    /// them durable as [`BlockStore::flush`] does.
+    fn write_back(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Drops cached copies of the `count` blocks from `block_num`, which
+    /// another node may have written, so they are read from the device
+    /// again. Cached changes to them are written first; fails if that
+    /// does.
+    fn invalidate(&self, _block_num: u64, _count: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Drops every cached block, writing cached changes first.
+    fn invalidate_all(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Drops cached changes to the `count` blocks from `block_num` without
+    /// writing them, as they were made under a lock this node lost.
+    fn forget_changes(&self, _block_num: u64, _count: u64) {}
+}
+
+impl<S: BlockStore + ?Sized> BlockStore for Box<S> {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> io::Result<()> {
+        (**self).read_block(block_num, buf)
+    }
+
+    fn write_block(&self, block_num: u64, buf: &[u8]) -> io::Result<()> {
+        (**self).write_block(block_num, buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn discard(&self, block_num: u64, count: u64) -> io::Result<()> {
+        (**self).discard(block_num, count)
+    }
+
+    fn trim(&self, block_num: u64, count: u64) -> io::Result<()> {
+        (**self).trim(block_num, count)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+
+    fn block_count(&self) -> io::Result<u64> {
+        (**self).block_count()
+    }
+
+    fn write_back(&self) -> io::Result<()> {
+        (**self).write_back()
+    }
+
+    fn invalidate(&self, block_num: u64, count: u64) -> io::Result<()> {
+        (**self).invalidate(block_num, count)
+    }
+
+    fn invalidate_all(&self) -> io::Result<()> {
+        (**self).invalidate_all()
+    }
+
+    fn forget_changes(&self, block_num: u64, count: u64) {
+        (**self).forget_changes(block_num, count)
+    }
+}
+
+/// Error for IO on blocks `store` does not have.
+fn out_of_range(block_num: u64, store: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Block {} is outside the {}", block_num, store),
+    )
+}
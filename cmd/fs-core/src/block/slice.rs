@@ -0,0 +1,176 @@
+use std::io;
+
+use super::BlockStore;
+use crate::volume::Volume;
+
+/// A byte range of the wrapped store, such as one volume of a shared
+/// device. Block 0 is the first block of the range, and IO beyond its end
+/// fails.
+pub struct Slice<S> {
+    inner: S,
+    /// Block of the wrapped store that is block 0 here.
+    first_block: u64,
+    len: u64,
+}
+
+impl<S: BlockStore> Slice<S> {
+    /// The `len` bytes at `offset` of `inner`, which must start on a block
+    /// boundary.
+    pub fn new(inner: S, offset: u64, len: u64) -> io::Result<Self> {
+        let block_size = inner.block_size() as u64;
+        if !offset.is_multiple_of(block_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Offset {} is not a multiple of the block size {}",
+                    offset, block_size
+                ),
+            ));
+        }
+        Ok(Self {
+            inner,
+            first_block: offset / block_size,
+            len,
+        })
+    }
+
+    /// The range of `volume` on `inner`.
+    pub fn volume(inner: S, volume: &Volume) -> io::Result<Self> {
+        Self::new(inner, volume.offset, volume.size)
+    }
+
+    /// Block of the wrapped store holding `len` bytes at `block_num`.
+    fn translate(&self, block_num: u64, len: u64) -> io::Result<u64> {
+        block_num
+            .checked_mul(self.inner.block_size() as u64)
+            .filter(|start| start.saturating_add(len) <= self.len)
+            .map(|_| self.first_block + block_num)
+            .ok_or_else(|| super::out_of_range(block_num, "volume"))
+    }
+}
+
+impl<S: BlockStore> BlockStore for Slice<S> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> io::Result<()> {
+        let block = self.translate(block_num, buf.len() as u64)?;
+        self.inner.read_block(block, buf)
+    }
+
+    fn write_block(&self, block_num: u64, buf: &[u8]) -> io::Result<()> {
+        let block = self.translate(block_num, buf.len() as u64)?;
+        self.inner.write_block(block, buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn discard(&self, block_num: u64, count: u64) -> io::Result<()> {
+        let len = count.saturating_mul(self.block_size() as u64);
+        let block = self.translate(block_num, len)?;
+        self.inner.discard(block, count)
+    }
+
+    fn trim(&self, block_num: u64, count: u64) -> io::Result<()> {
+        let len = count.saturating_mul(self.block_size() as u64);
+        let block = self.translate(block_num, len)?;
+        self.inner.trim(block, count)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn write_back(&self) -> io::Result<()> {
+        self.inner.write_back()
+    }
+
+    fn invalidate(&self, block_num: u64, count: u64) -> io::Result<()> {
+        let len = count.saturating_mul(self.block_size() as u64);
+        match self.translate(block_num, len) {
+            Ok(block) => self.inner.invalidate(block, count),
+            // Nothing of the volume is cached there.
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Drops every block cached below, including those of other volumes.
+    fn invalidate_all(&self) -> io::Result<()> {
+        self.inner.invalidate_all()
+    }
+
+    fn forget_changes(&self, block_num: u64, count: u64) {
+        let len = count.saturating_mul(self.block_size() as u64);
+        if let Ok(block) = self.translate(block_num, len) {
+            self.inner.forget_changes(block, count)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::MemoryStore;
+
+    const BLOCK: u64 = 512;
+
+    /// Blocks 4 to 11 of a 16-block store.
+    fn slice() -> (MemoryStore, Slice<MemoryStore>) {
+        let store = MemoryStore::new(BLOCK as usize, 16);
+        let slice = Slice::new(store.clone(), 4 * BLOCK, 8 * BLOCK).unwrap();
+        (store, slice)
+    }
+
+    #[test]
+    fn last_block_in_range() {
+        let (store, slice) = slice();
+        slice.write_block(7, &[7; BLOCK as usize]).unwrap();
+        let mut buf = [0; BLOCK as usize];
+        store.read_block(11, &mut buf).unwrap();
+        assert_eq!(buf, [7; BLOCK as usize]);
+
+        slice.write_block(0, &[1; BLOCK as usize]).unwrap();
+        store.read_block(4, &mut buf).unwrap();
+        assert_eq!(buf, [1; BLOCK as usize]);
+        slice.discard(0, 8).unwrap();
+    }
+
+    #[test]
+    fn first_block_out_of_range() {
+        let (store, slice) = slice();
+        let mut buf = [0; BLOCK as usize];
+        assert!(slice.read_block(8, &mut buf).is_err());
+        assert!(slice.write_block(8, &buf).is_err());
+        // Starts inside, ends outside.
+        assert!(slice.write_block(7, &[9; 2 * BLOCK as usize]).is_err());
+        assert!(slice.discard(7, 2).is_err());
+        assert!(slice.trim(8, 1).is_err());
+
+        store.read_block(12, &mut buf).unwrap();
+        assert_eq!(buf, [0; BLOCK as usize]);
+    }
+
+    #[test]
+    fn offset_and_block_overflow() {
+        let (_, slice) = slice();
+        let mut buf = [0; BLOCK as usize];
+        assert!(slice.read_block(u64::MAX, &mut buf).is_err());
+        assert!(slice.read_block(u64::MAX / BLOCK, &mut buf).is_err());
+        assert!(slice.discard(1, u64::MAX).is_err());
+
+        // Block numbers near the top of the wrapped store do not wrap
+        // around to its start.
+        let store = MemoryStore::new(BLOCK as usize, 16);
+        let far = Slice::new(store, u64::MAX - u64::MAX % BLOCK, 8 * BLOCK).unwrap();
+        assert!(far.read_block(0, &mut buf).is_err());
+    }
+
+    #[test]
+    fn offset_must_be_block_aligned() {
+        let store = MemoryStore::new(BLOCK as usize, 16);
+        assert!(Slice::new(store, BLOCK + 1, BLOCK).is_err());
+    }
+}
@@ -0,0 +1,439 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+use io_uring::{opcode, types, IoUring};
+
+use super::device::{self, AlignedBuf, Geometry};
+use super::BlockStore;
+
+/// Operations in flight at once, each with a registered buffer of one block.
+const QUEUE_DEPTH: usize = 64;
+
+/// An image file or block device, read and written through io_uring.
+///
+/// Calls hand their IO to a thread owning the ring, which splits it into
+/// block-sized operations, submits everything queued in one batch and
+/// completes each call once all its operations have. The kernel only ever
+/// reads and writes the ring's registered buffers, never the callers'.
+pub struct UringStore {
+    file: File,
+    block_size: usize,
+    /// Whether discarded blocks are deallocated rather than overwritten
+    /// with zeros.
+    deallocate: bool,
+    requests: Option<mpsc::Sender<Request>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl UringStore {
+    pub fn open<P: AsRef<Path>>(path: P, block_size: usize) -> io::Result<Self> {
+        let file = File::options().read(true).write(true).open(path)?;
+        Self::new(file, block_size, None)
+    }
+
+    /// Opens the device for IO that bypasses the page cache (`O_DIRECT`),
+    /// like [`super::BlockDevice::open_direct`].
+    pub fn open_direct<P: AsRef<Path>>(path: P, block_size: usize) -> io::Result<Self> {
+        let (file, sector_size) = device::open_direct(path, block_size)?;
+        Self::new(file, block_size, Some(sector_size))
+    }
+
+    fn new(file: File, block_size: usize, direct: Option<usize>) -> io::Result<Self> {
+        let ring = Ring::new(&file, block_size, direct)?;
+        let (requests, incoming) = mpsc::channel();
+        let worker = std::thread::Builder::new()
+            .name("block-uring".to_string())
+            .spawn(move || ring.run(incoming))?;
+        Ok(Self {
+            file,
+            block_size,
+            deallocate: false,
+            requests: Some(requests),
+            worker: Some(worker),
+        })
+    }
+
+    /// Deallocates discarded blocks, like
+    /// [`super::BlockDevice::with_discard`].
+    pub fn with_discard(mut self, enabled: bool) -> Self {
+        self.deallocate = enabled;
+        self
+    }
+
+    pub fn geometry(&self) -> io::Result<Geometry> {
+        Geometry::of(&self.file)
+    }
+
+    fn position(&self, block_num: u64) -> io::Result<u64> {
+        block_num
+            .checked_mul(self.block_size as u64)
+            .ok_or_else(|| super::out_of_range(block_num, "device"))
+    }
+
+    /// Runs `op` on `len` bytes at `buf` and waits for it to complete.
+    fn call(&self, op: Op, block_num: u64, buf: *mut u8, len: usize) -> io::Result<()> {
+        let pos = self.position(block_num)?;
+        let (done, result) = mpsc::sync_channel(1);
+        let request = Request {
+            op,
+            pos,
+            buf,
+            len,
+            done,
+        };
+        // The ring thread only stops once the sender is dropped, or on an
+        // error it has already failed all requests with.
+        self.requests
+            .as_ref()
+            .expect("sender is only taken on drop")
+            .send(request)
+            .map_err(|_| stopped())?;
+        result.recv().unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+impl Drop for UringStore {
+    fn drop(&mut self) {
+        drop(self.requests.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl BlockStore for UringStore {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn read_block(&self, block_num: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.call(Op::Read, block_num, buf.as_mut_ptr(), buf.len())
+    }
+
+    fn write_block(&self, block_num: u64, buf: &[u8]) -> io::Result<()> {
+        // The ring thread only reads from the buffer of a write.
+        self.call(Op::Write, block_num, buf.as_ptr() as *mut u8, buf.len())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.call(Op::Flush, 0, std::ptr::null_mut(), 0)
+    }
+
+    /// Deallocates on the calling thread rather than through the ring; no
+    /// IO the caller issued is in flight, since calls wait for theirs.
+    fn discard(&self, block_num: u64, count: u64) -> io::Result<()> {
+        let len = count.saturating_mul(self.block_size as u64);
+        if self.deallocate && device::punch_hole(&self.file, self.position(block_num)?, len)? {
+            return Ok(());
+        }
+        let zeros = vec![0u8; self.block_size];
+        for block in block_num..block_num + count {
+            self.write_block(block, &zeros)?;
+        }
+        Ok(())
+    }
+
+    fn trim(&self, block_num: u64, count: u64) -> io::Result<()> {
+        let len = count.saturating_mul(self.block_size as u64);
+        device::trim(&self.file, self.position(block_num)?, len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.geometry()?.size)
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("The io_uring thread has stopped")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Read,
+    Write,
+    Flush,
+}
+
+/// One call to the store. `buf` stays valid until `done` is sent or
+/// dropped, since the caller waits for that.
+struct Request {
+    op: Op,
+    pos: u64,
+    buf: *mut u8,
+    len: usize,
+    done: mpsc::SyncSender<io::Result<()>>,
+}
+
+// SAFETY: `buf` is only used by the ring thread while the caller, which
+// owns it, waits for the request to complete.
+unsafe impl Send for Request {}
+
+/// A request being worked on, and what is left of it.
+struct Pending {
+    request: Request,
+    remaining: usize,
+    result: io::Result<()>,
+}
+
+/// The part of a request one operation on the ring covers: at most one
+/// block, so it fits a registered buffer.
+struct Chunk {
+    id: u64,
+    op: Op,
+    /// Offset of the chunk in the request's buffer.
+    offset: usize,
+    len: usize,
+    pos: u64,
+    /// The chunk is the unaligned tail of a direct write, and its buffer is
+    /// first filled with what the device holds so the rest of the last
+    /// sector is kept.
+    fill: bool,
+}
+
+struct Ring {
+    ring: IoUring,
+    block_size: usize,
+    /// Sector size all IO is aligned to when it bypasses the page cache.
+    direct: Option<usize>,
+    /// `QUEUE_DEPTH` buffers of one block, registered with the ring.
+    buffers: AlignedBuf,
+    base: *mut u8,
+    /// Chunks on the ring, by the buffer they use.
+    slots: Vec<Option<Chunk>>,
+    free: Vec<usize>,
+    queued: VecDeque<Chunk>,
+    pending: HashMap<u64, Pending>,
+    next_id: u64,
+}
+
+// SAFETY: `base` points into `buffers`, which moves with it.
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new(file: &File, block_size: usize, direct: Option<usize>) -> io::Result<Self> {
+        let ring = IoUring::new(QUEUE_DEPTH as u32)?;
+        let mut buffers = AlignedBuf::new(QUEUE_DEPTH * block_size, direct.unwrap_or(4096));
+        let base = buffers.as_mut_slice().as_mut_ptr();
+        let iovecs: Vec<libc::iovec> = (0..QUEUE_DEPTH)
+            .map(|slot| libc::iovec {
+                // SAFETY: each slot is within `buffers`.
+                iov_base: unsafe { base.add(slot * block_size) }.cast(),
+                iov_len: block_size,
+            })
+            .collect();
+        // SAFETY: the buffers live as long as the ring, see `run`.
+        unsafe { ring.submitter().register_buffers(&iovecs)? };
+        ring.submitter().register_files(&[file.as_raw_fd()])?;
+        Ok(Self {
+            ring,
+            block_size,
+            direct,
+            buffers,
+            base,
+            slots: (0..QUEUE_DEPTH).map(|_| None).collect(),
+            free: (0..QUEUE_DEPTH).rev().collect(),
+            queued: VecDeque::new(),
+            pending: HashMap::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Works off requests until the store is dropped.
+    fn run(mut self, incoming: mpsc::Receiver<Request>) {
+        loop {
+            if self.free.len() == QUEUE_DEPTH && self.queued.is_empty() {
+                match incoming.recv() {
+                    Ok(request) => self.accept(request),
+                    Err(_) => return,
+                }
+            }
+            // Requests arriving while we wait below are picked up after
+            // the next completion.
+            while let Ok(request) = incoming.try_recv() {
+                self.accept(request);
+            }
+            self.start_queued();
+
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EINTR | libc::EBUSY)) => {}
+                Err(e) => {
+                    tracing::error!("io_uring submission failed: {}", e);
+                    // Operations may still be on the ring, and the kernel
+                    // may write to their buffers after we return.
+                    std::mem::forget(self.buffers);
+                    return;
+                }
+            }
+            self.reap();
+        }
+    }
+
+    fn accept(&mut self, request: Request) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut chunks = 0;
+        if request.op == Op::Flush {
+            self.queue(id, request.op, 0, 0, 0);
+            chunks = 1;
+        }
+        let mut offset = 0;
+        while offset < request.len {
+            let len = (request.len - offset).min(self.block_size);
+            self.queue(id, request.op, offset, len, request.pos + offset as u64);
+            offset += len;
+            chunks += 1;
+        }
+        let pending = Pending {
+            remaining: chunks,
+            request,
+            result: Ok(()),
+        };
+        if chunks == 0 {
+            let _ = pending.request.done.send(Ok(()));
+            return;
+        }
+        self.pending.insert(id, pending);
+    }
+
+    fn queue(&mut self, id: u64, op: Op, offset: usize, len: usize, pos: u64) {
+        let fill = op == Op::Write && self.io_len(len) != len;
+        self.queued.push_back(Chunk {
+            id,
+            op,
+            offset,
+            len,
+            pos,
+            fill,
+        });
+    }
+
+    /// Bytes the operation for a chunk of `len` bytes moves.
+    fn io_len(&self, len: usize) -> usize {
+        match self.direct {
+            Some(sector_size) => len.next_multiple_of(sector_size),
+            None => len,
+        }
+    }
+
+    fn slot(&self, slot: usize) -> *mut u8 {
+        // SAFETY: slots are within `buffers`.
+        unsafe { self.base.add(slot * self.block_size) }
+    }
+
+    fn start_queued(&mut self) {
+        while !self.free.is_empty() {
+            let Some(chunk) = self.queued.pop_front() else {
+                break;
+            };
+            let slot = self.free.pop().unwrap();
+            if chunk.op == Op::Write && !chunk.fill {
+                let src = self.pending[&chunk.id].request.buf;
+                // SAFETY: the caller waits on the request, keeping its
+                // buffer alive, and the chunk lies within it.
+                unsafe {
+                    std::ptr::copy_nonoverlapping(src.add(chunk.offset), self.slot(slot), chunk.len)
+                };
+            }
+            self.push(slot, chunk);
+        }
+    }
+
+    /// Puts the operation for `chunk` on the ring, using buffer `slot`.
+    fn push(&mut self, slot: usize, chunk: Chunk) {
+        let fd = types::Fixed(0);
+        let buf = self.slot(slot);
+        let len = self.io_len(chunk.len) as u32;
+        let entry = match chunk.op {
+            Op::Flush => opcode::Fsync::new(fd)
+                .flags(types::FsyncFlags::DATASYNC)
+                .build(),
+            Op::Read => opcode::ReadFixed::new(fd, buf, len, slot as u16)
+                .offset(chunk.pos)
+                .build(),
+            Op::Write if chunk.fill => opcode::ReadFixed::new(fd, buf, len, slot as u16)
+                .offset(chunk.pos)
+                .build(),
+            Op::Write => opcode::WriteFixed::new(fd, buf, len, slot as u16)
+                .offset(chunk.pos)
+                .build(),
+        };
+        self.slots[slot] = Some(chunk);
+        // SAFETY: the buffer is registered and stays untouched until the
+        // operation completes. The ring has room for every slot.
+        unsafe {
+            self.ring
+                .submission()
+                .push(&entry.user_data(slot as u64))
+                .expect("submission queue holds QUEUE_DEPTH entries")
+        };
+    }
+
+    fn reap(&mut self) {
+        let completions: Vec<(usize, i32)> = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+            .collect();
+        for (slot, res) in completions {
+            let mut chunk = self.slots[slot].take().unwrap();
+            let expected = if chunk.op == Op::Flush {
+                0
+            } else {
+                self.io_len(chunk.len)
+            };
+            let result = if res < 0 {
+                Err(io::Error::from_raw_os_error(-res))
+            } else if res as usize != expected {
+                Err(match chunk.op {
+                    Op::Write if !chunk.fill => {
+                        io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer")
+                    }
+                    _ => {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")
+                    }
+                })
+            } else {
+                Ok(())
+            };
+
+            if result.is_ok() && chunk.fill {
+                // Write the chunk over what was read, in the same buffer.
+                chunk.fill = false;
+                let src = self.pending[&chunk.id].request.buf;
+                // SAFETY: as in `start_queued`.
+                unsafe {
+                    std::ptr::copy_nonoverlapping(src.add(chunk.offset), self.slot(slot), chunk.len)
+                };
+                self.push(slot, chunk);
+                continue;
+            }
+            if result.is_ok() && chunk.op == Op::Read {
+                let dst = self.pending[&chunk.id].request.buf;
+                // SAFETY: as in `start_queued`.
+                unsafe {
+                    std::ptr::copy_nonoverlapping(self.slot(slot), dst.add(chunk.offset), chunk.len)
+                };
+            }
+            self.free.push(slot);
+            self.complete(chunk.id, result);
+        }
+    }
+
+    fn complete(&mut self, id: u64, result: io::Result<()>) {
+        let pending = self.pending.get_mut(&id).unwrap();
+        if pending.result.is_ok() {
+            pending.result = result;
+        }
+        pending.remaining -= 1;
+        if pending.remaining == 0 {
+            let pending = self.pending.remove(&id).unwrap();
+            let _ = pending.request.done.send(pending.result);
+        }
+    }
+}
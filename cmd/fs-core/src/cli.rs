@@ -1,10 +1,17 @@
 use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
+use crate::config::{CoordinatorMode, MountConfig, MountOptions};
+
 #[derive(Parser)]
 #[command(name = "awesomefs")]
 #[command(about = "CLI to interact with the awesomefs filesystem", long_about = None)]
 pub struct Cli {
+    /// OTLP/gRPC collector to export traces to, e.g. http://localhost:4317
+    #[arg(long, global = true, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -15,6 +22,13 @@ pub enum Commands {
     Format {
         #[arg(short, long)]
         device: PathBuf,
+        /// Format only this volume of the device's volume table, adding it
+        /// (with --size) if it does not exist yet
+        #[arg(long)]
+        volume: Option<String>,
+        /// Size in bytes of a new volume
+        #[arg(long, requires = "volume")]
+        size: Option<u64>,
     },
     /// Mount the filesystem
     Mount {
@@ -22,6 +36,25 @@ pub enum Commands {
         device: PathBuf,
         #[arg(short, long)]
         mountpoint: PathBuf,
+        /// Mount this volume of the device's volume table
+        #[arg(long)]
+        volume: Option<String>,
+        /// TOML file with defaults for the metadata-service settings
+        #[arg(long, env = "FS_CORE_CONFIG")]
+        config: Option<PathBuf>,
+        /// How to coordinate with other nodes mounting the device
+        #[arg(long, value_enum)]
+        coordinator: CoordinatorMode,
+        /// Mount a shared device read-write without the remote coordinator
+        #[arg(long)]
+        force: bool,
+        /// Address to serve Prometheus metrics on at /metrics
+        #[arg(long, env = "FS_METRICS_LISTEN")]
+        metrics_listen: Option<SocketAddr>,
+        #[command(flatten)]
+        options: MountOptions,
+        #[command(flatten)]
+        metadata: MountConfig,
     },
     /// Start the filesystem service (future: with FUSE)
     Serve {
@@ -30,10 +63,65 @@ pub enum Commands {
 
         #[arg(value_name = "MOUNTPOINT")]
         mountpoint: PathBuf,
+
+        #[arg(long)]
+        volume: Option<String>,
+        #[arg(long, env = "FS_CORE_CONFIG")]
+        config: Option<PathBuf>,
+        #[arg(long, value_enum)]
+        coordinator: CoordinatorMode,
+        /// Mount a shared device read-write without the remote coordinator
+        #[arg(long)]
+        force: bool,
+        /// Address to serve Prometheus metrics on at /metrics
+        #[arg(long, env = "FS_METRICS_LISTEN")]
+        metrics_listen: Option<SocketAddr>,
+        #[command(flatten)]
+        options: MountOptions,
+        #[command(flatten)]
+        metadata: MountConfig,
+    },
+    /// Grow or shrink a formatted filesystem, after growing or before
+    /// shrinking the device. Unless online, fails while any node has it
+    /// mounted, which for a shared device the metadata-service is asked about
+    Resize {
+        #[arg(short, long)]
+        device: PathBuf,
+        /// Resize this volume of the device's volume table
+        #[arg(long)]
+        volume: Option<String>,
+        /// New size in bytes
+        #[arg(long)]
+        size: u64,
+        /// Grow the filesystem while nodes have it mounted, taking the
+        /// superblock lock through the metadata-service
+        #[arg(long, conflicts_with = "volume")]
+        online: bool,
+        /// TOML file with defaults for the metadata-service settings
+        #[arg(long, env = "FS_CORE_CONFIG")]
+        config: Option<PathBuf>,
+        #[command(flatten)]
+        metadata: MountConfig,
+    },
+    /// Deallocate the device's blocks no file uses, like fstrim. Fails
+    /// while any node has the filesystem mounted, which for a shared device
+    /// the metadata-service is asked about
+    Trim {
+        #[arg(short, long)]
+        device: PathBuf,
+        #[arg(long)]
+        volume: Option<String>,
+        /// TOML file with defaults for the metadata-service settings
+        #[arg(long, env = "FS_CORE_CONFIG")]
+        config: Option<PathBuf>,
+        #[command(flatten)]
+        metadata: MountConfig,
     },
     /// Print debug info about a filesystem
     Debug {
         #[arg(short, long)]
         device: PathBuf,
+        #[arg(long)]
+        volume: Option<String>,
     },
-}
\ No newline at end of file
+}
@@ -0,0 +1,206 @@
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use tonic::transport::{Certificate, ClientTlsConfig, Identity};
+
+/// Used when neither the flags nor the config file name an endpoint.
+const DEFAULT_METADATA_ENDPOINT: &str = "http://127.0.0.1:50051";
+
+/// Who coordinates access to the filesystem with other nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CoordinatorMode {
+    /// Locks are only taken within this process. Safe only when no other
+    /// node mounts the device.
+    Local,
+    /// Locks and inode numbers come from the metadata-service.
+    Remote,
+    /// No coordination; the filesystem is mounted read-only.
+    NoneReadonly,
+}
+
+/// How the device is read and written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IoMode {
+    /// Through the kernel page cache.
+    Buffered,
+    /// Bypassing the page cache (`O_DIRECT`), which may hold blocks that
+    /// other nodes have overwritten since.
+    Direct,
+}
+
+/// What submits IO to the device.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IoBackend {
+    /// Blocking reads and writes, one at a time per thread.
+    #[default]
+    Sync,
+    /// Batches of reads and writes in flight at once through io_uring, with
+    /// registered buffers.
+    Uring,
+}
+
+/// How `mount` does IO on the device.
+#[derive(Debug, Default, Clone, Args)]
+pub struct MountOptions {
+    /// Whether to go through the page cache [default: direct for devices
+    /// other nodes may mount, buffered otherwise]
+    #[arg(long, value_enum, env = "FS_IO_MODE")]
+    pub io_mode: Option<IoMode>,
+
+    /// What submits IO to the device
+    #[arg(long, value_enum, env = "FS_IO_BACKEND", default_value_t)]
+    pub io_backend: IoBackend,
+
+    /// Megabytes of blocks to cache in memory; 0 disables the cache
+    #[arg(long, env = "FS_CACHE_SIZE", default_value_t = 64)]
+    pub cache_size: usize,
+
+    /// Most KiB of a file read ahead of sequential reads through one open
+    /// handle; 0 disables readahead. Needs the cache. Has no effect yet, as
+    /// file data lives in the inode's block, which every read loads anyway
+    #[arg(long = "readahead-kb", env = "FS_READAHEAD_KB", default_value_t = 128)]
+    pub readahead_kb: u64,
+
+    /// Deallocate the blocks of deleted files on the device, which
+    /// thin-provisioned devices need to get the space back, instead of
+    /// writing zeros over them
+    #[arg(long, env = "FS_DISCARD")]
+    pub discard: bool,
+}
+
+/// How `mount` reaches the metadata-service. Read from the `--config` file,
+/// with command-line flags taking precedence.
+#[derive(Debug, Default, Clone, Deserialize, Args)]
+#[serde(deny_unknown_fields)]
+pub struct MountConfig {
+    /// Replicas of the metadata-service, comma-separated; followers redirect
+    /// us to the leader. Use https:// URLs for TLS
+    /// [default: http://127.0.0.1:50051]
+    #[arg(
+        long = "metadata-endpoints",
+        env = "METADATA_ENDPOINTS",
+        value_delimiter = ','
+    )]
+    #[serde(default)]
+    pub metadata_endpoints: Vec<String>,
+
+    /// File holding the bearer token to authenticate with, e.g. a projected
+    /// service-account token. Read again for every request, so rotated
+    /// tokens are picked up
+    #[arg(long = "token-file", env = "METADATA_TOKEN_FILE")]
+    pub token_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    #[serde(default)]
+    pub tls: TlsConfig,
+}
+
+impl MountConfig {
+    /// Reads a TOML config file, e.g.
+    ///
+    /// ```toml
+    /// metadata_endpoints = ["https://metadata-service:50051"]
+    /// token_file = "/var/run/secrets/awsomefs/token"
+    ///
+    /// [tls]
+    /// ca = "/etc/awsomefs/ca.crt"
+    /// cert = "/etc/awsomefs/tls.crt"
+    /// key = "/etc/awsomefs/tls.key"
+    /// ```
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = std::fs::read_to_string(&path)?;
+        toml::from_str(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.as_ref().display(), e),
+            )
+        })
+    }
+
+    /// Fills the settings missing here from `other`.
+    pub fn or(self, other: MountConfig) -> MountConfig {
+        MountConfig {
+            metadata_endpoints: if self.metadata_endpoints.is_empty() {
+                other.metadata_endpoints
+            } else {
+                self.metadata_endpoints
+            },
+            token_file: self.token_file.or(other.token_file),
+            tls: self.tls.or(other.tls),
+        }
+    }
+
+    pub fn endpoints(&self) -> Vec<String> {
+        if self.metadata_endpoints.is_empty() {
+            vec![DEFAULT_METADATA_ENDPOINT.to_string()]
+        } else {
+            self.metadata_endpoints.clone()
+        }
+    }
+}
+
+/// PEM files to verify the metadata-service with and, for mutual TLS, to
+/// authenticate to it.
+#[derive(Debug, Default, Clone, Deserialize, Args)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// CA bundle the metadata-service certificate must chain to
+    #[arg(long = "tls-ca", env = "METADATA_TLS_CA")]
+    pub ca: Option<PathBuf>,
+
+    /// Client certificate, for a metadata-service requiring one
+    #[arg(long = "tls-cert", env = "METADATA_TLS_CERT", requires = "key")]
+    pub cert: Option<PathBuf>,
+
+    /// Private key of --tls-cert
+    #[arg(long = "tls-key", env = "METADATA_TLS_KEY", requires = "cert")]
+    pub key: Option<PathBuf>,
+
+    /// Name to verify the server certificate against, if not the host of
+    /// the endpoint
+    #[arg(long = "tls-domain", env = "METADATA_TLS_DOMAIN")]
+    pub domain: Option<String>,
+}
+
+impl TlsConfig {
+    /// Fills the settings missing here from `other`.
+    pub fn or(self, other: TlsConfig) -> TlsConfig {
+        TlsConfig {
+            ca: self.ca.or(other.ca),
+            cert: self.cert.or(other.cert),
+            key: self.key.or(other.key),
+            domain: self.domain.or(other.domain),
+        }
+    }
+
+    /// Settings for `https://` endpoints; `None` if nothing is configured.
+    pub fn client(&self) -> io::Result<Option<ClientTlsConfig>> {
+        if self.ca.is_none() && self.cert.is_none() && self.key.is_none() {
+            return Ok(None);
+        }
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = &self.ca {
+            config = config.ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                config = config.identity(Identity::from_pem(
+                    std::fs::read(cert)?,
+                    std::fs::read(key)?,
+                ));
+            }
+            (None, None) => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "A client certificate needs both --tls-cert and --tls-key",
+                ))
+            }
+        }
+        if let Some(domain) = &self.domain {
+            config = config.domain_name(domain.clone());
+        }
+        Ok(Some(config))
+    }
+}
@@ -1,82 +1,168 @@
 use anyhow::Context;
-use bincode::{deserialize, serialize};
 
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 use fuser::{FileAttr, FileType};
 use metadata::MetadataCoordinator;
 use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
+use std::ops::Range;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::block::BlockDevice;
+use crate::block::BlockStore;
+use crate::inodes::InodeStore;
 use crate::layout::*;
 use crate::metadata;
+use crate::metrics::metrics;
 use crate::Superblock;
 
 pub const ROOT_INO: u64 = 1;
 
+const LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often an operation is retried after the coordinator aborted it to
+/// break a deadlock, before the error is passed on to the caller.
+const DEADLOCK_RETRIES: u32 = 3;
+/// Inode numbers reserved from the coordinator at a time.
+const INODE_BATCH: u32 = 64;
+/// There is no inode 0, so its lock guards the superblock.
+const SUPERBLOCK_LOCK: metadata::LockKey = metadata::LockKey(0);
+/// Held shared by every node that has the filesystem mounted, so work that
+/// needs it unmounted everywhere can take it exclusively. No inode gets the
+/// last number.
+const MOUNT_LOCK: metadata::LockKey = metadata::LockKey(u64::MAX);
+
+/// Ids of the operations taking locks, unique within the process so that
+/// several users of one coordinator session can tell their locks apart.
+static NEXT_TXN: AtomicU64 = AtomicU64::new(1);
+
+/// Starts an operation that takes locks.
+pub(crate) fn begin_txn() -> u64 {
+    NEXT_TXN.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Takes the mount lock exclusively for `txn`, keeping every node from
+/// mounting the filesystem until [`release_unmounted`]. Fails if any node
+/// has it mounted.
+pub(crate) async fn hold_unmounted(
+    coordinator: &dyn MetadataCoordinator,
+    txn: u64,
+) -> anyhow::Result<()> {
+    coordinator
+        .lock(MOUNT_LOCK, metadata::LockType::Write, LOCK_TIMEOUT, txn)
+        .await
+        .context("The filesystem is mounted; unmount it on every node first")
+}
+
+pub(crate) async fn release_unmounted(
+    coordinator: &dyn MetadataCoordinator,
+    txn: u64,
+) -> anyhow::Result<()> {
+    coordinator
+        .unlock(MOUNT_LOCK, txn)
+        .await
+        .context("Failed to release the mount lock")
+}
+
+/// True if `err` was caused by the coordinator aborting a lock request to
+/// break a deadlock.
+pub fn is_deadlock(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<metadata::LockError>() == Some(&metadata::LockError::Deadlock)
+}
+
+/// Drops cached copies of the blocks the lock on `key` guards.
+fn invalidate_blocks(inodes: &InodeStore, key: &metadata::LockKey) -> std::io::Result<()> {
+    if *key == SUPERBLOCK_LOCK {
+        Superblock::invalidate(inodes.store())
+    } else if *key != MOUNT_LOCK {
+        inodes.invalidate(key.0)
+    } else {
+        Ok(())
+    }
+}
+
+/// Drops cached changes to the blocks the lock on `key` guards, made under
+/// it before it was lost.
+fn forget_changes(inodes: &InodeStore, key: &metadata::LockKey) {
+    if *key == SUPERBLOCK_LOCK {
+        Superblock::forget_changes(inodes.store());
+    } else if *key != MOUNT_LOCK {
+        inodes.forget_changes(key.0);
+    }
+}
+
+/// Exponential backoff with a little jitter, so the nodes involved in a
+/// deadlock do not retry in lockstep.
+fn deadlock_backoff(attempt: u32) -> Duration {
+    let jitter = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() % 10)
+        .unwrap_or(0);
+    Duration::from_millis((10u64 << attempt) + jitter as u64)
+}
+
 pub struct FsCoreInner {
     pub inode_counter: u64,
     pub inode_attrs: HashMap<u64, FileAttr>,
     pub inode_data: HashMap<u64, Vec<u8>>,
     pub path_to_ino: HashMap<String, u64>,
-    pub block_device: BlockDevice,
+    pub inodes: InodeStore,
     pub parent_to_children: HashMap<u64, HashMap<String, u64>>,
+    /// Bumped whenever cached inodes are dropped, so an inode read from
+    /// disk before that is not cached after it.
+    generation: u64,
 }
 
 impl FsCoreInner {
-    pub fn new(mut block_device: BlockDevice) -> Self {
+    pub fn new(inodes: InodeStore) -> Self {
         let inode_counter = {
-            let superblock = Superblock::load(&mut block_device.file, block_device.block_size)
-                .expect("Failed to load superblock");
+            let superblock = Superblock::load(inodes.store()).expect("Failed to load superblock");
             superblock.inode_count
         };
+        metrics().superblock(inode_counter);
+        if let Ok(blocks) = inodes.store().block_count() {
+            metrics().device_blocks(blocks);
+        }
 
         FsCoreInner {
             inode_counter,
             inode_attrs: HashMap::new(),
             inode_data: HashMap::new(),
             path_to_ino: HashMap::new(),
-            block_device,
+            inodes,
             parent_to_children: HashMap::new(),
+            generation: 0,
         }
     }
 
-    pub fn load_superblock(&mut self) -> std::io::Result<()> {
-        let mut file = &mut self.block_device.file;
-        let block_size = self.block_device.block_size;
-        let sb = Superblock::load(&mut file, block_size)?;
+    /// Reloads the inode counter from the superblock, and returns the
+    /// superblock.
+    pub fn load_superblock(&mut self) -> std::io::Result<Superblock> {
+        let sb = Superblock::load(self.inodes.store())?;
         self.inode_counter = sb.inode_count;
-        Ok(())
+        metrics().superblock(self.inode_counter);
+        Ok(sb)
     }
 
     pub fn save_superblock(&mut self) -> std::io::Result<()> {
-        let mut file = &mut self.block_device.file;
-        let block_size = self.block_device.block_size;
-
-        let mut superblock = Superblock::load(&mut file, block_size)?;
+        let mut superblock = Superblock::load(self.inodes.store())?;
         superblock.inode_count = self.inode_counter;
-        superblock.save(&mut file, block_size)
+        superblock.save(self.inodes.store())?;
+        metrics().superblock(self.inode_counter);
+        Ok(())
     }
 
+    /// Creates the file as inode `ino`, which must come from
+    /// [`FsCore::allocate_inode`].
     pub fn create_file_locked(
         &mut self,
         parent_ino: u64,
+        ino: u64,
         name: &str,
         data: &[u8],
     ) -> anyhow::Result<u64> {
-        self.load_superblock()?;
-
-        self.inode_counter += 1;
-        let ino = self.inode_counter;
-
-        let parent_path = self
-            .path_to_ino
-            .iter()
-            .find(|(_, &ino)| ino == parent_ino)
-            .map(|(p, _)| p.clone())
-            .unwrap();
+        // Another node may have changed the directory since we cached it.
+        let parent_path = self.reload_inode(parent_ino)?.path;
 
         let path = if parent_ino == ROOT_INO {
             format!("/{}", name)
@@ -132,61 +218,31 @@ impl FsCoreInner {
 
             let serialized = bincode::serialize(&entries).unwrap();
 
-            let parent_path = self
-                .path_to_ino
-                .iter()
-                .find(|(_, &ino)| ino == parent_ino)
-                .map(|(p, _)| p.clone())
-                .unwrap();
-
             let parent_inode = PersistedInode {
-                attr: self.inode_attrs.get(&parent_ino).unwrap().clone().into(),
+                attr: (*self.inode_attrs.get(&parent_ino).unwrap()).into(),
                 data: serialized,
                 path: parent_path.clone(),
             };
             self.save_inode(parent_ino, &parent_inode)?;
         }
-        self.save_superblock()?;
         Ok(ino)
     }
 
     pub fn load_from_device(&mut self) -> std::io::Result<()> {
-        let mut ino = 1;
-        loop {
+        self.load_superblock()?;
+
+        // The superblock counts every number reserved by any node. Deleted
+        // inodes and reserved numbers never used leave gaps below it.
+        for ino in 1..=self.inode_counter {
             match self.load_inode(ino) {
-                Ok(inode) => {
-                    let attr = inode.attr.clone();
-                    self.inode_attrs.insert(ino, attr.into());
-                    self.inode_data.insert(ino, inode.data.clone());
-                    self.path_to_ino.insert(inode.path, ino);
-                    self.inode_counter = self.inode_counter.max(ino + 1);
-
-                    if attr.kind == fuser::FileType::Directory {
-                        if let Ok(entries) =
-                            bincode::deserialize::<Vec<DirectoryEntry>>(&inode.data)
-                        {
-                            tracing::debug!("Loaded directory {} entries: {:?}", ino, entries);
-
-                            let mut map = HashMap::new();
-                            for entry in entries {
-                                map.insert(entry.name, entry.ino);
-                            }
-                            self.parent_to_children.insert(ino, map);
-                        }
-                    }
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
-                    // Likely uninitialized block or invalid data, stop scanning
-                    break;
-                }
+                Ok(inode) => self.cache_inode(ino, &inode),
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => continue,
                 Err(e) => {
                     // Any other I/O error, also break or handle differently if needed
                     tracing::warn!("Error loading inode {}: {}", ino, e);
                     break;
                 }
             }
-
-            ino += 1;
         }
 
         // Ensure root inode is present
@@ -197,136 +253,198 @@ impl FsCoreInner {
         Ok(())
     }
 
+    /// Returns the cached inode, reading it from disk on a miss. The cache
+    /// is kept coherent with other nodes through [`FsCoreInner::invalidate_inode`].
     pub fn get_or_load_inode(&mut self, ino: u64) -> std::io::Result<PersistedInode> {
-        // TODO some caching, needs fixing to work with multi-mount
-        // if let Some(attr) = self.inode_attrs.get(&ino) {
-        //     if let Some(data) = self.inode_data.get(&ino) {
-        //         if let Some(path) = self.path_to_ino.iter().find_map(|(path, i)| {
-        //             if *i == ino {
-        //                 Some(path.clone())
-        //             } else {
-        //                 None
-        //             }
-        //         }) {
-        //             return Ok(PersistedInode {
-        //                 attr: attr.clone().into(),
-        //                 data: data.clone(),
-        //                 path,
-        //             });
-        //         }
-        //     }
-        // }
-
-        // Fallback to disk
+        if let Some(inode) = self.cached_inode(ino) {
+            metrics().inode_cache(true);
+            return Ok(inode);
+        }
+
+        metrics().inode_cache(false);
+        self.reload_inode(ino)
+    }
+
+    /// The inode as cached, if it is.
+    pub fn cached_inode(&self, ino: u64) -> Option<PersistedInode> {
+        let attr = self.inode_attrs.get(&ino)?;
+        let data = self.inode_data.get(&ino)?;
+        let path = self
+            .path_to_ino
+            .iter()
+            .find_map(|(path, i)| (*i == ino).then(|| path.clone()))?;
+        Some(PersistedInode {
+            attr: (*attr).into(),
+            data: data.clone(),
+            path,
+        })
+    }
+
+    /// Reads the inode from disk, bypassing and refreshing the cache. Used
+    /// under lock, before a change, since an invalidation from the node that
+    /// held the lock before us may still be on its way.
+    pub fn reload_inode(&mut self, ino: u64) -> std::io::Result<PersistedInode> {
         let inode = self.load_inode(ino)?;
-        self.inode_attrs.insert(ino, inode.attr.clone().into());
+        self.cache_inode(ino, &inode);
+        Ok(inode)
+    }
+
+    fn cache_inode(&mut self, ino: u64, inode: &PersistedInode) {
+        self.inode_attrs.insert(ino, inode.attr.into());
         self.inode_data.insert(ino, inode.data.clone());
         self.path_to_ino.insert(inode.path.clone(), ino);
-        Ok(inode)
+
+        if inode.attr.kind == FileType::Directory {
+            let entries = if inode.data.is_empty() {
+                Vec::new()
+            } else {
+                bincode::deserialize::<Vec<DirectoryEntry>>(&inode.data).unwrap_or_default()
+            };
+            self.parent_to_children
+                .insert(ino, entries.into_iter().map(|e| (e.name, e.ino)).collect());
+        }
     }
 
-    pub fn load_inode(&mut self, ino: u64) -> std::io::Result<PersistedInode> {
-        let block = 1 + ino; // Block 0 is superblock
-        let mut buf = vec![0u8; self.block_device.block_size];
-        self.block_device.read_block(block, &mut buf)?;
+    fn forget_inode(&mut self, ino: u64) {
+        self.generation += 1;
+        self.inode_attrs.remove(&ino);
+        self.inode_data.remove(&ino);
+        self.path_to_ino.retain(|_, v| *v != ino);
+        self.parent_to_children.remove(&ino);
+    }
 
-        let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    /// Drops whatever we cached about `ino` after another node changed it.
+    pub fn invalidate_inode(&mut self, ino: u64) -> std::io::Result<()> {
+        self.forget_inode(ino);
+        invalidate_blocks(&self.inodes, &metadata::LockKey(ino))
+    }
 
-        if len == 0 || 4 + len > buf.len() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid inode length",
-            ));
+    /// Drops the whole cache after the locks on `inos` were lost, without
+    /// writing the changes made under them.
+    pub fn invalidate_lost(&mut self, inos: &[u64]) -> std::io::Result<()> {
+        for &ino in inos {
+            forget_changes(&self.inodes, &metadata::LockKey(ino));
         }
+        self.invalidate_all()
+    }
 
-        let serialized = &buf[4..4 + len];
+    /// Drops the whole cache, e.g. after changes may have been missed.
+    pub fn invalidate_all(&mut self) -> std::io::Result<()> {
+        self.generation += 1;
+        self.inode_attrs.clear();
+        self.inode_data.clear();
+        self.path_to_ino.clear();
+        self.parent_to_children.clear();
+        self.inodes.store().invalidate_all()
+    }
 
-        bincode::deserialize::<PersistedInode>(serialized)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    pub fn load_inode(&self, ino: u64) -> std::io::Result<PersistedInode> {
+        self.inodes.load(ino)
     }
 
     pub fn save_inode(&mut self, ino: u64, inode: &PersistedInode) -> std::io::Result<()> {
-        let block = 1 + ino; // Block 0 is superblock
+        self.inodes.save(ino, inode)?;
+        self.cache_inode(ino, inode);
+        Ok(())
+    }
 
-        let bytes = serialize(&inode).unwrap();
-        let len = bytes.len() as u32; // 4 bytes to store size
-        let mut padded = vec![0u8; self.block_device.block_size];
+    pub fn unlink_locked(&mut self, parent_ino: u64, name: &str) -> anyhow::Result<()> {
+        self.load_superblock()?;
 
-        if 4 + bytes.len() > self.block_device.block_size {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Serialized inode too large for block",
+        // Another node may have changed the directory since we cached it.
+        let parent_path = self.reload_inode(parent_ino)?.path;
+
+        let Some(children) = self.parent_to_children.get(&parent_ino) else {
+            return Err(anyhow::anyhow!("Parent directory not found"));
+        };
+        let Some(&ino) = children.get(name) else {
+            return Err(anyhow::anyhow!("File not found in directory"));
+        };
+
+        // Drop the entry before the inode, so failing in between leaves an
+        // unreachable inode rather than an entry pointing at nothing.
+        let entries: Vec<DirectoryEntry> = children
+            .iter()
+            .filter(|(child, _)| *child != name)
+            .map(|(name, ino)| DirectoryEntry {
+                name: name.clone(),
+                ino: *ino,
+            })
+            .collect();
+        let serialized = bincode::serialize(&entries).unwrap();
+        let parent_inode = PersistedInode {
+            attr: (*self.inode_attrs.get(&parent_ino).unwrap()).into(),
+            data: serialized,
+            path: parent_path.clone(),
+        };
+        self.save_inode(parent_ino, &parent_inode)?;
+
+        self.forget_inode(ino);
+        self.delete_inode_from_disk(ino)?;
+
+        self.save_superblock()?;
+        Ok(())
+    }
+
+    /// Makes the filesystem span the first `blocks` blocks of the store.
+    /// Shrinking it fails unless the blocks cut off hold no inode number
+    /// ever reserved.
+    pub fn resize_locked(&mut self, blocks: u64) -> anyhow::Result<()> {
+        let mut superblock = self.load_superblock()?;
+        let available = self.inodes.store().block_count()?;
+        if blocks > available {
+            return Err(anyhow::anyhow!(
+                "The device has room for only {} blocks, not {}",
+                available,
+                blocks
+            ));
+        }
+        let needed = InodeStore::block(superblock.inode_count) + 1;
+        if blocks < needed {
+            return Err(anyhow::anyhow!(
+                "Inode numbers up to {} are reserved, which takes {} blocks, not {}",
+                superblock.inode_count,
+                needed,
+                blocks
             ));
         }
 
-        padded[..4].copy_from_slice(&len.to_le_bytes()); // Save length first
-        padded[4..4 + bytes.len()].copy_from_slice(&bytes);
-
-        self.block_device.write_block(block, &padded)
+        superblock.block_count = blocks;
+        superblock.save(self.inodes.store())?;
+        self.inodes.store().flush()?;
+        Ok(())
     }
 
-    pub fn unlink_locked(&mut self, parent_ino: u64, name: &str) -> anyhow::Result<()> {
-        self.load_superblock()?;
+    /// See [`FsCore::trim`]; called with the mount lock held.
+    pub fn trim(&mut self) -> std::io::Result<(usize, u64)> {
+        let superblock = self.load_superblock()?;
+        let store = self.inodes.store();
 
-        let parent_path = self
-            .path_to_ino
-            .iter()
-            .find(|(_, &ino)| ino == parent_ino)
-            .map(|(p, _)| p.clone())
-            .unwrap();
-
-        // Remove the file from the parent directory
-        if let Some(parent) = self.parent_to_children.get_mut(&parent_ino) {
-            if let Some(ino) = parent.remove(name) {
-                // 1. Remove the inode data (file attributes and data)
-                self.inode_attrs.remove(&ino);
-                self.inode_data.remove(&ino);
-
-                // 2. Remove the file path reference
-                self.path_to_ino.retain(|_, v| *v != ino);
-
-                // 3. Remove the inode from disk
-                self.delete_inode_from_disk(ino)?;
-
-                // Regenerate and persist the parent directory's data
-                if let Some(children) = self.parent_to_children.get(&parent_ino) {
-                    let entries: Vec<DirectoryEntry> = children
-                        .iter()
-                        .map(|(name, ino)| DirectoryEntry {
-                            name: name.clone(),
-                            ino: *ino,
-                        })
-                        .collect();
-
-                    let serialized = bincode::serialize(&entries).unwrap();
-
-                    let parent_inode = PersistedInode {
-                        attr: self.inode_attrs.get(&parent_ino).unwrap().clone().into(),
-                        data: serialized,
-                        path: parent_path.clone(),
-                    };
-                    self.save_inode(parent_ino, &parent_inode)?;
+        let mut free = Vec::new();
+        for ino in 1..=superblock.inode_count {
+            match self.inodes.load(ino) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    free.push(InodeStore::block(ino))
                 }
-
-                // Success
-                self.save_superblock()?;
-                Ok(())
-            } else {
-                Err(anyhow::anyhow!("File not found in directory").into())
+                Err(e) => return Err(e),
             }
-        } else {
-            Err(anyhow::anyhow!("Parent directory not found").into())
         }
+        for run in free.chunk_by(|a, b| a + 1 == *b) {
+            store.discard(run[0], run.len() as u64)?;
+        }
+
+        let first = InodeStore::block(superblock.inode_count) + 1;
+        let unused = store.block_count()?.saturating_sub(first);
+        if unused > 0 {
+            store.trim(first, unused)?;
+        }
+        store.flush()?;
+        Ok((free.len(), unused))
     }
 
     fn delete_inode_from_disk(&mut self, ino: u64) -> std::io::Result<()> {
-        // Handle removing the inode from storage (from disk)
-        let block = 1 + ino; // Adjust based on your block structure, for example
-
-        let mut buf = vec![0u8; self.block_device.block_size];
-        buf.fill(0); // Empty the inode
-
-        self.block_device.write_block(block, &buf)
+        self.inodes.delete(ino)
     }
 
     pub fn insert_root_dir(&mut self) {
@@ -358,23 +476,18 @@ impl FsCoreInner {
         };
 
         self.save_inode(ROOT_INO, &root_inode).unwrap();
-        self.inode_attrs.insert(ROOT_INO, attr);
-        self.inode_data.insert(ROOT_INO, Vec::new()); // empty directory data
-        self.path_to_ino.insert("/".to_string(), ROOT_INO);
     }
 
-    pub fn mkdir(
+    /// Creates the directory as inode `ino`, which must come from
+    /// [`FsCore::allocate_inode`].
+    pub fn mkdir_locked(
         &mut self,
         parent_ino: u64,
+        ino: u64,
         name: &str,
         uid: u32,
         gid: u32,
     ) -> std::io::Result<FileAttr> {
-        self.load_superblock()?;
-
-        self.inode_counter += 1;
-        let ino = self.inode_counter;
-
         let attr = FileAttr {
             ino,
             size: 0,
@@ -393,12 +506,7 @@ impl FsCoreInner {
             blksize: 512,
         };
 
-        let parent_path = self
-            .path_to_ino
-            .iter()
-            .find(|(_, &ino)| ino == parent_ino)
-            .map(|(p, _)| p.clone())
-            .unwrap();
+        let parent_path = self.reload_inode(parent_ino)?.path;
 
         let path = if parent_ino == ROOT_INO {
             format!("/{}", name)
@@ -417,11 +525,6 @@ impl FsCoreInner {
         };
         self.save_inode(ino, &dir_inode).unwrap();
 
-        // Update in-memory structures
-        self.inode_attrs.insert(ino, attr);
-        self.inode_data.insert(ino, Vec::new()); // empty directory data
-        self.path_to_ino.insert(path, ino);
-
         self.parent_to_children
             .entry(parent_ino)
             .or_default()
@@ -440,76 +543,494 @@ impl FsCoreInner {
             let serialized = bincode::serialize(&entries).unwrap();
 
             let parent_inode = PersistedInode {
-                attr: self.inode_attrs.get(&parent_ino).unwrap().clone().into(),
+                attr: (*self.inode_attrs.get(&parent_ino).unwrap()).into(),
                 data: serialized,
                 path: parent_path.clone(),
             };
             self.save_inode(parent_ino, &parent_inode)?;
         }
-        self.save_superblock()?;
         Ok(attr)
     }
 }
 
+/// The filesystem. Directory changes and the caches go through the
+/// [`FsCoreInner`] behind one mutex; reads and writes of single inodes go
+/// straight to the [`InodeStore`] and only wait for IO on the same inode.
 pub struct FsCore {
     inner: Arc<Mutex<FsCoreInner>>,
+    inodes: InodeStore,
     pub coordinator: Box<dyn MetadataCoordinator>,
+    /// Inode numbers reserved for this node and not used yet.
+    free_inodes: Mutex<Range<u64>>,
+    /// Other nodes change the filesystem too, so blocks cached before
+    /// taking a lock cannot be trusted, and our changes must reach the
+    /// device before releasing it.
+    shared: bool,
+    /// Transaction holding the mount lock, 0 if it is not held.
+    mount_txn: AtomicU64,
 }
 
 impl FsCore {
-    pub fn new(block_device: BlockDevice) -> Arc<Self> {
-        Arc::new(FsCore {
-            inner: Arc::new(Mutex::new(FsCoreInner::new(block_device))),
-            coordinator: Box::new(metadata::local::LocalMetadataCoordinator::new()),
-        })
+    pub fn new(block_device: Box<dyn BlockStore>) -> Arc<Self> {
+        Self::with_coordinator(
+            block_device,
+            Box::new(metadata::local::LocalMetadataCoordinator::new()),
+        )
     }
 
     pub fn with_coordinator(
-        block_device: BlockDevice,
+        block_device: Box<dyn BlockStore>,
         coordinator: Box<dyn MetadataCoordinator>,
     ) -> Arc<Self> {
+        let inodes = InodeStore::new(Arc::from(block_device));
         Arc::new(FsCore {
-            inner: Arc::new(Mutex::new(FsCoreInner::new(block_device))),
+            inner: Arc::new(Mutex::new(FsCoreInner::new(inodes.clone()))),
+            inodes,
+            shared: coordinator.invalidations().is_some(),
             coordinator,
+            free_inodes: Mutex::new(0..0),
+            mount_txn: AtomicU64::new(0),
         })
     }
     pub async fn load_from_device(&self) -> std::io::Result<()> {
         self.with_inner(|inner| inner.load_from_device()).await
     }
 
+    /// Writes all changes to the device and makes them durable.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.inodes.store().flush()
+    }
+
+    /// Blocks to prefetch for bytes `range` of the inode's data; empty if
+    /// reading the inode fetches them already.
+    pub fn readahead_blocks(&self, ino: u64, range: Range<u64>) -> Range<u64> {
+        self.inodes.data_blocks(ino, range)
+    }
+
+    /// Prefetches `blocks` of the inode's data from the device.
+    pub fn readahead(&self, ino: u64, blocks: Range<u64>) -> std::io::Result<()> {
+        self.inodes.prefetch(ino, blocks)
+    }
+
+    /// Reads the inode from the block store, bypassing the inode cache.
+    pub fn read_inode(&self, ino: u64) -> std::io::Result<PersistedInode> {
+        self.inodes.load(ino)
+    }
+
+    /// Returns the cached inode, reading it from disk on a miss without
+    /// holding up requests for other inodes.
+    pub async fn get_inode(&self, ino: u64) -> std::io::Result<PersistedInode> {
+        let generation = {
+            let inner = self.inner.lock().await;
+            if let Some(inode) = inner.cached_inode(ino) {
+                metrics().inode_cache(true);
+                return Ok(inode);
+            }
+            inner.generation
+        };
+
+        metrics().inode_cache(false);
+        let inode = self.inodes.load(ino)?;
+        let mut inner = self.inner.lock().await;
+        if inner.generation == generation {
+            inner.cache_inode(ino, &inode);
+        }
+        Ok(inode)
+    }
+
+    /// Finds `name` in directory `parent`. Returns `None` if it has no such
+    /// entry.
+    pub async fn lookup(&self, parent: u64, name: &str) -> std::io::Result<Option<PersistedInode>> {
+        let parent = self.get_inode(parent).await?;
+        let Some(entry) = parent.entries().into_iter().find(|e| e.name == name) else {
+            return Ok(None);
+        };
+        match self.get_inode(entry.ino).await {
+            Ok(inode) => Ok(Some(inode)),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[tracing::instrument(name = "core.create_file", skip(self, data))]
     pub async fn create_file(
         &self,
         parent_ino: u64,
         name: &str,
         data: &[u8],
     ) -> anyhow::Result<u64> {
-        let lock_key = metadata::LockKey(parent_ino);
-        let timeout: Duration = Duration::from_secs(2);
+        let ino = self.allocate_inode().await?;
+        let keys = [metadata::LockKey(parent_ino)];
 
         tracing::trace!("Trying to acquire lock on inode {}", parent_ino);
-        self.coordinator
-            .lock(lock_key.clone(), metadata::LockType::Write, timeout)
+        let txn = begin_txn();
+        self.lock_for_update(txn, &keys)
             .await
             .context("Failed to acquire lock for file creation")?;
 
         let result = {
             let mut fs = self.inner.lock().await;
-            let result = fs.create_file_locked(parent_ino, name, data);
-
-            result
+            fs.create_file_locked(parent_ino, ino, name, data)
         };
 
-        self.coordinator
-            .unlock(lock_key)
+        self.unlock_all(txn, &keys)
             .await
             .context("Failed to release lock after file creation")?;
 
         result
     }
 
+    #[tracing::instrument(name = "core.mkdir", skip(self))]
+    pub async fn mkdir(
+        &self,
+        parent_ino: u64,
+        name: &str,
+        uid: u32,
+        gid: u32,
+    ) -> anyhow::Result<FileAttr> {
+        let ino = self.allocate_inode().await?;
+        let keys = [metadata::LockKey(parent_ino)];
+
+        let txn = begin_txn();
+        self.lock_for_update(txn, &keys)
+            .await
+            .context("Failed to acquire lock for mkdir")?;
+
+        let result = self
+            .with_inner(|inner| inner.mkdir_locked(parent_ino, ino, name, uid, gid))
+            .await;
+
+        self.unlock_all(txn, &keys)
+            .await
+            .context("Failed to release lock after mkdir")?;
+
+        Ok(result?)
+    }
+
+    #[tracing::instrument(name = "core.unlink", skip(self))]
     pub async fn unlink(&self, parent_ino: u64, name: &str) -> anyhow::Result<()> {
-        self.with_inner(|inner| inner.unlink_locked(parent_ino, name))
+        // Lock the directory before looking the name up in it, then the
+        // inode it names: resolved any earlier, the name could be pointed
+        // elsewhere before the directory is locked.
+        let mut keys = vec![metadata::LockKey(parent_ino)];
+        let txn = begin_txn();
+        self.lock_for_update(txn, &keys)
+            .await
+            .context("Failed to acquire lock on parent for unlink")?;
+
+        let child = self
+            .with_inner_result(|inner| {
+                inner.reload_inode(parent_ino)?;
+                Ok::<_, std::io::Error>(
+                    inner
+                        .parent_to_children
+                        .get(&parent_ino)
+                        .and_then(|children| children.get(name).copied()),
+                )
+            })
+            .await;
+        let locked = match child {
+            Ok(Some(ino)) => {
+                let child = metadata::LockKey(ino);
+                // Under the same transaction, so the coordinator sees the
+                // directory lock when the inode's is contended.
+                match self
+                    .lock_for_update(txn, std::slice::from_ref(&child))
+                    .await
+                {
+                    Ok(()) => {
+                        keys.push(child);
+                        Ok(Some(ino))
+                    }
+                    Err(e) => Err(e.context("Failed to acquire lock on inode for unlink")),
+                }
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(e.into()),
+        };
+
+        let result = match locked {
+            Ok(child) => {
+                self.with_inner(|inner| {
+                    // Nothing else can rename within a locked directory, but
+                    // the entry must be the inode that got locked.
+                    let current = inner
+                        .parent_to_children
+                        .get(&parent_ino)
+                        .and_then(|children| children.get(name).copied());
+                    if current != child {
+                        return Err(anyhow::anyhow!("{} changed while being locked", name));
+                    }
+                    inner.unlink_locked(parent_ino, name)
+                })
+                .await
+            }
+            Err(e) => Err(e),
+        };
+
+        self.unlock_all(txn, &keys)
+            .await
+            .context("Failed to release locks after unlink")?;
+
+        result
+    }
+
+    /// Takes the next inode number reserved for this node, reserving a new
+    /// range from the coordinator when they run out.
+    #[tracing::instrument(name = "core.allocate_inode", skip(self))]
+    pub async fn allocate_inode(&self) -> anyhow::Result<u64> {
+        let mut free = self.free_inodes.lock().await;
+        if let Some(ino) = free.next() {
+            return Ok(ino);
+        }
+
+        let keys = [SUPERBLOCK_LOCK];
+        let txn = begin_txn();
+        self.lock_for_update(txn, &keys)
+            .await
+            .context("Failed to acquire lock on superblock")?;
+
+        let result = self.reserve_inodes().await;
+
+        self.unlock_all(txn, &keys)
+            .await
+            .context("Failed to release lock on superblock")?;
+
+        *free = result?;
+        free.next()
+            .ok_or_else(|| anyhow::anyhow!("Coordinator reserved no inode numbers"))
+    }
+
+    /// Reserves a range of inode numbers and raises the superblock's counter
+    /// to its end, so the counter covers every number that may be in use.
+    /// The blocks of the numbers newly covered are zeroed first, as mounting
+    /// reads them and [`FsCore::trim`] leaves them undefined. Called with the
+    /// superblock lock held.
+    async fn reserve_inodes(&self) -> anyhow::Result<Range<u64>> {
+        let (floor, max_inode) = self
+            .with_inner_result(|inner| {
+                let superblock = inner.load_superblock()?;
+                let blocks = superblock.blocks(inner.inodes.store())?;
+                Ok::<_, std::io::Error>((superblock.inode_count, InodeStore::max_inode(blocks)))
+            })
+            .await?;
+        let range = self.coordinator.allocate_inodes(floor, INODE_BATCH).await?;
+        // Numbers past the end of the filesystem are reserved in vain.
+        let range = range.start..range.end.min(max_inode + 1);
+        if range.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "No inodes left; grow the filesystem with `awesomefs resize`",
+            )
+            .into());
+        }
+        self.with_inner_result(|inner| {
+            let last = range.end - 1;
+            if last > floor {
+                inner
+                    .inodes
+                    .store()
+                    .discard(InodeStore::block(floor + 1), last - floor)?;
+            }
+            inner.inode_counter = inner.inode_counter.max(last);
+            inner.save_superblock()
+        })
+        .await?;
+        Ok(range)
+    }
+
+    /// Marks the filesystem mounted on this node until the coordinator
+    /// session ends, keeping [`FsCore::trim`] from running anywhere.
+    pub async fn hold_mount(&self) -> anyhow::Result<()> {
+        let txn = begin_txn();
+        self.coordinator
+            .lock(MOUNT_LOCK, metadata::LockType::Read, LOCK_TIMEOUT, txn)
+            .await
+            .context("Failed to mark the filesystem mounted; is it being trimmed?")?;
+        self.mount_txn.store(txn, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Takes the mount lock again if it is among the `lost` inode locks, as
+    /// it goes with an expired session like any other. Trim holds it only
+    /// briefly, so this keeps trying until it succeeds.
+    pub async fn reclaim_mount(&self, lost: &[u64]) {
+        if !lost.contains(&MOUNT_LOCK.0) {
+            return;
+        }
+        let txn = self.mount_txn.swap(0, Ordering::Relaxed);
+        if txn == 0 {
+            return;
+        }
+        // Only drops the record of the lost lock.
+        let _ = self.coordinator.unlock(MOUNT_LOCK, txn).await;
+        loop {
+            match self.hold_mount().await {
+                Ok(()) => {
+                    tracing::info!("Marked the filesystem mounted again");
+                    return;
+                }
+                Err(e) => {
+                    tracing::error!("{:#}, retrying", e);
+                    tokio::time::sleep(LOCK_TIMEOUT).await;
+                }
+            }
+        }
+    }
+
+    /// Deallocates the blocks no inode uses, like `fstrim`, and returns how
+    /// many free inode blocks were discarded and how many blocks past the
+    /// inodes were trimmed. Free inode blocks must keep reading as no inode,
+    /// so they are discarded; blocks past the last inode number ever
+    /// reserved are zeroed when reserved, so they are trimmed. Fails if any
+    /// node has the filesystem mounted.
+    pub async fn trim(&self) -> anyhow::Result<(usize, u64)> {
+        let txn = begin_txn();
+        hold_unmounted(self.coordinator.as_ref(), txn).await?;
+
+        let result = self.with_inner_result(|inner| inner.trim()).await;
+
+        release_unmounted(self.coordinator.as_ref(), txn).await?;
+        Ok(result?)
+    }
+
+    /// Makes the filesystem span the first `blocks` blocks of the device,
+    /// under the superblock lock, so nodes that have it mounted reserve
+    /// inode numbers by the new size from then on.
+    #[tracing::instrument(name = "core.resize", skip(self))]
+    pub async fn resize(&self, blocks: u64) -> anyhow::Result<()> {
+        let keys = [SUPERBLOCK_LOCK];
+        let txn = begin_txn();
+        self.lock_for_update(txn, &keys)
+            .await
+            .context("Failed to acquire lock on superblock")?;
+
+        let result = self.with_inner(|inner| inner.resize_locked(blocks)).await;
+
+        self.unlock_all(txn, &keys)
+            .await
+            .context("Failed to release lock on superblock")?;
+
+        result
+    }
+
+    /// Applies `f` to the inode under a write lock, so that other nodes are
+    /// told about the change when the lock is released. Returns `None` if
+    /// the inode does not exist.
+    #[tracing::instrument(name = "core.update_inode", skip(self, f))]
+    pub async fn update_inode<F>(&self, ino: u64, f: F) -> anyhow::Result<Option<PersistedInode>>
+    where
+        F: FnOnce(&mut PersistedInode),
+    {
+        let keys = [metadata::LockKey(ino)];
+        let txn = begin_txn();
+        self.lock_for_update(txn, &keys)
+            .await
+            .context("Failed to acquire lock for inode update")?;
+
+        let result = self.inodes.update(ino, f);
+        if let Ok(Some(inode)) = &result {
+            self.with_inner(|inner| inner.cache_inode(ino, inode)).await;
+        }
+
+        self.unlock_all(txn, &keys)
             .await
+            .context("Failed to release lock after inode update")?;
+
+        Ok(result?)
+    }
+
+    /// Takes write locks on `keys` for operation `txn`, retrying with backoff
+    /// when the coordinator aborts the attempt to break a deadlock with
+    /// another node.
+    #[tracing::instrument(name = "core.lock_wait", skip(self))]
+    async fn lock_for_update(&self, txn: u64, keys: &[metadata::LockKey]) -> anyhow::Result<()> {
+        let mut attempt = 0;
+        let started = Instant::now();
+        loop {
+            let result = self
+                .coordinator
+                .lock_in_txn(txn, keys, metadata::LockType::Write, LOCK_TIMEOUT)
+                .await;
+            let failure = match &result {
+                Ok(()) => None,
+                Err(e) if is_deadlock(e) => Some("deadlock"),
+                Err(e) if e.downcast_ref() == Some(&metadata::LockError::Timeout) => {
+                    Some("timeout")
+                }
+                Err(_) => Some("error"),
+            };
+            metrics().lock_waited(started, failure);
+            if result.is_ok() && self.shared {
+                // The invalidation from the node that held the locks before
+                // us may still be on its way.
+                let invalidated = keys
+                    .iter()
+                    .try_for_each(|key| invalidate_blocks(&self.inodes, key));
+                if let Err(e) = invalidated {
+                    for key in keys.iter().rev() {
+                        self.coordinator.unlock(key.clone(), txn).await?;
+                    }
+                    return Err(anyhow::Error::new(e)
+                        .context("Failed to write back cached blocks before reading them again"));
+                }
+            }
+            match result {
+                Err(e) if is_deadlock(&e) && attempt < DEADLOCK_RETRIES => {
+                    attempt += 1;
+                    tracing::debug!(
+                        "Deadlock taking locks {:?}, retrying (attempt {})",
+                        keys,
+                        attempt
+                    );
+                    tokio::time::sleep(deadlock_backoff(attempt)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn unlock_all(&self, txn: u64, keys: &[metadata::LockKey]) -> anyhow::Result<()> {
+        // Changes made under a lock that went with an expired session may
+        // conflict with what other nodes wrote since, so they are dropped.
+        let mut lost = Vec::new();
+        for key in keys {
+            if !self.coordinator.is_locked(key, txn).await {
+                lost.push(key.clone());
+            }
+        }
+        // The next node to take the locks reads from the device.
+        let written = if !lost.is_empty() {
+            Err(
+                anyhow::Error::new(metadata::LockError::Lost).context(format!(
+                    "Lost locks {:?} before the changes were written",
+                    lost
+                )),
+            )
+        } else if self.shared {
+            self.inodes
+                .store()
+                .write_back()
+                .context("Failed to write back changes before unlocking")
+        } else {
+            Ok(())
+        };
+        if written.is_err() {
+            // Blocks left dirty would later overwrite what other nodes write
+            // under these locks, so the whole operation is dropped instead.
+            // The locks are still released, so the node is not wedged.
+            for key in keys {
+                forget_changes(&self.inodes, key);
+            }
+        }
+        for key in keys.iter().rev() {
+            // Lost locks fail to release; the others are still ours.
+            let unlocked = self.coordinator.unlock(key.clone(), txn).await;
+            if written.is_ok() {
+                unlocked?;
+            }
+        }
+        written
     }
 
     pub async fn with_inner<F, R>(&self, f: F) -> R
@@ -527,9 +1048,4 @@ impl FsCore {
         let mut inner = self.inner.lock().await;
         f(&mut inner)
     }
-
-    pub fn blocking_lock_inner(&self) -> tokio::sync::MutexGuard<'_, FsCoreInner> {
-        // blocking_lock() works for cases like readdir
-        self.inner.blocking_lock()
-    }
 }
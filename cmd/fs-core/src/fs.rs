@@ -4,105 +4,531 @@ use std::io::Result;
 
 // use std::os::unix::fs::FileExt;
 // use std::os::unix::fs::OpenOptionsExt;
-use fuser::{mount2, MountOption};
+use fuser::{MountOption, Session};
+use std::num::NonZeroUsize;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
 use tokio::time::{timeout, Duration};
 
+use crate::block::{BlockDevice, BlockStore, BufferCache, Metered, Slice, UringStore};
+use crate::config::{CoordinatorMode, IoBackend, IoMode, MountConfig, MountOptions};
+use crate::fuse::notify::forward_invalidations;
 use crate::local::LocalMetadataCoordinator;
 use crate::metadata::MetadataCoordinator;
 use crate::remote::RemoteMetadataCoordinator;
 use crate::AwsomeFs;
-use crate::BlockDevice;
 use crate::FsCore;
 use crate::Superblock;
+use crate::VolumeTable;
 
 const DEFAULT_BLOCK_SIZE: usize = 4096;
+/// How long `mount` waits for the metadata-service to open a session.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub fn format<P: AsRef<Path>>(device_path: P) -> Result<()> {
-    if is_formatted(&device_path).unwrap() {
+/// Opens the device holding the filesystem: all of `device_path`, or only
+/// the range of `volume` in the device's volume table. With `discard`,
+/// discarded blocks are deallocated rather than zeroed.
+fn open_device<P: AsRef<Path>>(
+    device_path: P,
+    volume: Option<&str>,
+    io_mode: IoMode,
+    io_backend: IoBackend,
+    discard: bool,
+) -> Result<Box<dyn BlockStore>> {
+    let path = device_path.as_ref();
+    let bd: Box<dyn BlockStore> = match (io_backend, io_mode) {
+        (IoBackend::Sync, IoMode::Buffered) => {
+            Box::new(BlockDevice::open(path, DEFAULT_BLOCK_SIZE)?.with_discard(discard))
+        }
+        (IoBackend::Sync, IoMode::Direct) => {
+            Box::new(BlockDevice::open_direct(path, DEFAULT_BLOCK_SIZE)?.with_discard(discard))
+        }
+        (IoBackend::Uring, IoMode::Buffered) => {
+            Box::new(UringStore::open(path, DEFAULT_BLOCK_SIZE)?.with_discard(discard))
+        }
+        (IoBackend::Uring, IoMode::Direct) => {
+            Box::new(UringStore::open_direct(path, DEFAULT_BLOCK_SIZE)?.with_discard(discard))
+        }
+    };
+    let Some(id) = volume else {
+        return Ok(Box::new(Metered::new(bd)));
+    };
+
+    let table = VolumeTable::load(&bd)?;
+    let volume = table.get(id).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No volume '{}' on {:?}", id, device_path.as_ref()),
+        )
+    })?;
+    Ok(Box::new(Metered::new(Slice::volume(bd, volume)?)))
+}
+
+/// Adds volume `id` of `size` bytes to the device's volume table, creating
+/// the table if the device has none. Does nothing if the volume exists.
+fn add_volume<P: AsRef<Path>>(device_path: P, id: &str, size: Option<u64>) -> Result<()> {
+    let bd = BlockDevice::open(&device_path, DEFAULT_BLOCK_SIZE)?;
+    let mut table = match VolumeTable::load(&bd) {
+        Ok(table) => table,
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            if Superblock::load(&bd).is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Device is formatted as a single filesystem, not as volumes",
+                ));
+            }
+            VolumeTable::new()
+        }
+        Err(e) => return Err(e),
+    };
+    if table.get(id).is_some() {
+        return Ok(());
+    }
+
+    let size = size.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Volume '{}' does not exist; --size is required to create it",
+                id
+            ),
+        )
+    })?;
+    let volume = table.add(id, size, bd.size()?)?;
+    table.save(&bd)?;
+    tracing::info!(
+        "Added volume {} at offset {} ({} bytes)",
+        volume.id,
+        volume.offset,
+        volume.size
+    );
+    Ok(())
+}
+
+pub fn format<P: AsRef<Path>>(
+    device_path: P,
+    volume: Option<&str>,
+    size: Option<u64>,
+) -> Result<()> {
+    match volume {
+        Some(id) => add_volume(&device_path, id, size)?,
+        None => {
+            let bd = BlockDevice::open(&device_path, DEFAULT_BLOCK_SIZE)?;
+            if VolumeTable::load(&bd).is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Device holds volumes; format one of them with --volume",
+                ));
+            }
+        }
+    }
+
+    if is_formatted(&device_path, volume).unwrap() {
         tracing::info!("Device alread formatted, skipping");
         return Ok(());
     }
 
     tracing::info!("Formatting device: {:?}", device_path.as_ref());
 
-    let mut bd = BlockDevice::open(&device_path, 4096)?;
+    let bd = open_device(
+        &device_path,
+        volume,
+        IoMode::Buffered,
+        IoBackend::Sync,
+        false,
+    )?;
     // // Write a magic header or initialize metadata block
 
-    let sb = Superblock::new(4096, 1);
-    sb.save(&mut bd.file, bd.block_size).unwrap();
+    let mut sb = Superblock::new(4096, 1);
+    sb.block_count = bd.block_count()?;
+    sb.save(&bd).unwrap();
+    bd.flush()?;
 
     // Here you would write superblock, reserve journal, etc.
     tracing::info!("Format complete.");
     Ok(())
 }
 
-pub async fn mount<P: AsRef<Path>>(device_path: P, mountpoint: P) -> Result<()> {
-    let mut bd = BlockDevice::open(&device_path, 4096)?;
+/// Whether other nodes may mount the device too: it is a block device (in
+/// the cluster, a disk attached to every node) or carved into volumes.
+fn is_shared<P: AsRef<Path>>(device_path: P) -> Result<bool> {
+    let file_type = std::fs::metadata(&device_path)?.file_type();
+    if file_type.is_block_device() {
+        return Ok(true);
+    }
+    let bd = BlockDevice::open(&device_path, DEFAULT_BLOCK_SIZE)?;
+    Ok(VolumeTable::load(&bd).is_ok())
+}
+
+/// Opens a session with the metadata-service coordinating the nodes that
+/// mount the filesystem on `volume`.
+async fn connect(volume: Option<&str>, config: &MountConfig) -> Result<RemoteMetadataCoordinator> {
+    let remote = timeout(
+        CONNECT_TIMEOUT,
+        RemoteMetadataCoordinator::connect(
+            config.endpoints(),
+            volume.unwrap_or_default(),
+            config.tls.client()?,
+            config.token_file.clone(),
+        ),
+    )
+    .await
+    .map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "Timeout while connecting to the metadata-service",
+        )
+    })?
+    .map_err(|e| {
+        std::io::Error::other(format!(
+            "Failed to connect to the metadata-service: {:#}",
+            e
+        ))
+    })?;
+    tracing::info!("Connected to remote metadata coordinator");
+    Ok(remote)
+}
+
+pub async fn mount<P: AsRef<Path>>(
+    device_path: P,
+    mountpoint: P,
+    volume: Option<&str>,
+    mode: CoordinatorMode,
+    force: bool,
+    options: &MountOptions,
+    config: &MountConfig,
+) -> Result<()> {
+    let shared = is_shared(&device_path)?;
+    let _mounted = if shared {
+        None
+    } else {
+        Some(lock_image(&device_path, false)?)
+    };
+    if mode == CoordinatorMode::Local && shared {
+        if !force {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{:?} may be mounted by other nodes; mount it with --coordinator remote, \
+                     or read-only with --coordinator none-readonly (--force to mount it \
+                     read-write without cluster locking)",
+                    device_path.as_ref()
+                ),
+            ));
+        }
+        tracing::warn!(
+            "Mounting shared device {:?} read-write without cluster locking",
+            device_path.as_ref()
+        );
+    }
+
+    let io_mode = options.io_mode.unwrap_or(if shared {
+        IoMode::Direct
+    } else {
+        IoMode::Buffered
+    });
+    tracing::info!(
+        "Doing {:?} IO on {:?} with the {:?} backend",
+        io_mode,
+        device_path.as_ref(),
+        options.io_backend
+    );
+    let mut bd = open_device(
+        &device_path,
+        volume,
+        io_mode,
+        options.io_backend,
+        options.discard,
+    )?;
+    let cache_blocks = options.cache_size * 1024 * 1024 / bd.block_size();
+    // Blocks read ahead have nowhere to go without the cache.
+    let mut readahead = 0;
+    if let Some(capacity) = NonZeroUsize::new(cache_blocks) {
+        tracing::info!("Caching up to {} blocks", capacity);
+        bd = Box::new(BufferCache::new(bd, capacity));
+        readahead = options.readahead_kb * 1024;
+    }
 
-    let _loaded = Superblock::load(&mut bd.file, bd.block_size).unwrap();
+    let loaded = Superblock::load(&bd).unwrap();
+    if loaded.block_count > bd.block_count()? {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "The filesystem spans {} blocks, but {:?} has room for only {}",
+                loaded.block_count,
+                device_path.as_ref(),
+                bd.block_count()?
+            ),
+        ));
+    }
 
-    let options = vec![
-        MountOption::RW,
+    let mut options = vec![
         MountOption::FSName("AwesomeFS".to_string()),
         MountOption::AutoUnmount,
         MountOption::AllowRoot,
     ];
 
-    // Try remote coordinator with timeout
-    let coordinator = match timeout(
-        Duration::from_secs(1),
-        RemoteMetadataCoordinator::connect("http://127.0.0.1:50051"),
-    )
-    .await
-    {
-        Ok(Ok(remote)) => {
-            tracing::info!("Connected to remote metadata coordinator");
-            Box::new(remote) as Box<dyn MetadataCoordinator>
+    let coordinator: Box<dyn MetadataCoordinator> = match mode {
+        CoordinatorMode::Remote => {
+            let remote = connect(volume, config).await?;
+            options.push(MountOption::RW);
+            Box::new(remote)
         }
-        Ok(Err(e)) => {
-            tracing::warn!(
-                "Failed to connect to remote coordinator: {}. Falling back to local.",
-                e
-            );
-            Box::new(LocalMetadataCoordinator::new()) as Box<dyn MetadataCoordinator>
+        CoordinatorMode::Local => {
+            options.push(MountOption::RW);
+            Box::new(LocalMetadataCoordinator::new())
         }
-        Err(_) => {
-            tracing::warn!(
-                "Timeout while connecting to remote coordinator. Falling back to local."
-            );
-            Box::new(LocalMetadataCoordinator::new()) as Box<dyn MetadataCoordinator>
+        CoordinatorMode::NoneReadonly => {
+            options.push(MountOption::RO);
+            Box::new(LocalMetadataCoordinator::new())
         }
     };
 
+    let invalidations = coordinator.invalidations();
     let fs_core = FsCore::with_coordinator(bd, coordinator);
+    fs_core
+        .hold_mount()
+        .await
+        .map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
 
-    let fs = AwsomeFs::new(fs_core).await?;
+    let fs = AwsomeFs::new(fs_core.clone(), readahead).await?;
 
-    mount2(fs, &mountpoint, &options)?;
+    let mut session = Session::new(fs, mountpoint.as_ref(), &options)?;
+    if let Some(invalidations) = invalidations {
+        tokio::spawn(forward_invalidations(fs_core, invalidations));
+    }
+    session.run()?;
 
     tracing::info!("Mount successful");
     Ok(())
 }
 
-pub fn debug<P: AsRef<Path>>(device_path: P) -> Result<()> {
+pub fn debug<P: AsRef<Path>>(device_path: P, volume: Option<&str>) -> Result<()> {
     tracing::info!("Device info: {:?}", device_path.as_ref());
 
-    let mut bd = BlockDevice::open(&device_path, 4096)?;
+    let bd = BlockDevice::open(&device_path, DEFAULT_BLOCK_SIZE)?;
+    tracing::info!("Geometry {:?}", bd.geometry()?);
+    if volume.is_none() {
+        if let Ok(table) = VolumeTable::load(&bd) {
+            for volume in &table.volumes {
+                tracing::info!("Volume {:?}", volume);
+            }
+            return Ok(());
+        }
+    }
+
+    let bd = open_device(
+        &device_path,
+        volume,
+        IoMode::Buffered,
+        IoBackend::Sync,
+        false,
+    )?;
 
-    let loaded = Superblock::load(&mut bd.file, bd.block_size).unwrap();
+    let loaded = Superblock::load(&bd).unwrap();
 
     tracing::info!("Superblock {:?}", loaded);
     Ok(())
 }
 
-pub fn is_formatted<P: AsRef<Path>>(device_path: P) -> std::io::Result<bool> {
+/// Deallocates the blocks no inode uses, like `fstrim`. Fails while any
+/// node has the filesystem mounted, which for a shared device the
+/// metadata-service at `config` is asked about.
+pub async fn trim<P: AsRef<Path>>(
+    device_path: P,
+    volume: Option<&str>,
+    config: &MountConfig,
+) -> Result<()> {
+    let shared = is_shared(&device_path)?;
+    let _unmounted = if shared {
+        None
+    } else {
+        Some(lock_image(&device_path, true)?)
+    };
+    let bd = open_device(
+        &device_path,
+        volume,
+        IoMode::Buffered,
+        IoBackend::Sync,
+        true,
+    )?;
+    let coordinator: Box<dyn MetadataCoordinator> = if shared {
+        Box::new(connect(volume, config).await?)
+    } else {
+        Box::new(LocalMetadataCoordinator::new())
+    };
+
+    let (free, unused) = FsCore::with_coordinator(bd, coordinator)
+        .trim()
+        .await
+        .map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+    tracing::info!(
+        "Discarded {} free inode blocks and trimmed {} unused blocks",
+        free,
+        unused
+    );
+    Ok(())
+}
+
+/// Locks an image file only this node uses, shared for as long as it is
+/// mounted and exclusively while it is trimmed, until the returned file is
+/// closed. Fails instead of waiting for the other kind of lock.
+fn lock_image<P: AsRef<Path>>(device_path: P, exclusive: bool) -> Result<std::fs::File> {
+    let file = std::fs::File::open(&device_path)?;
+    let operation = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+    // SAFETY: flock only takes plain values.
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(e);
+        }
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ResourceBusy,
+            format!(
+                "{:?} is {}",
+                device_path.as_ref(),
+                if exclusive {
+                    "mounted; unmount it first"
+                } else {
+                    "being trimmed"
+                }
+            ),
+        ));
+    }
+    Ok(file)
+}
+
+/// Grows or shrinks the filesystem to `size` bytes. The device must have
+/// been grown before, and may be shrunk after.
+///
+/// With `online`, nodes may have the filesystem mounted: the superblock is
+/// changed under its lock, taken through the metadata-service at `config`,
+/// and only a filesystem spanning a whole device can grow. Offline, volumes
+/// are resized in the volume table as well, and the filesystem can shrink
+/// as long as no inode number ever reserved is cut off. No node may mount it
+/// meanwhile, which for a shared device the metadata-service is asked to
+/// ensure.
+pub async fn resize<P: AsRef<Path>>(
+    device_path: P,
+    volume: Option<&str>,
+    size: u64,
+    online: bool,
+    config: &MountConfig,
+) -> Result<()> {
+    if online && volume.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Volumes can only be resized offline; nodes that have one mounted keep \
+             the size it had then",
+        ));
+    }
+
+    let shared = is_shared(&device_path)?;
+    let _unmounted = if shared || online {
+        None
+    } else {
+        Some(lock_image(&device_path, true)?)
+    };
+    let remote = if shared || online {
+        Some(connect(volume, config).await?)
+    } else {
+        None
+    };
+    let txn = crate::core::begin_txn();
+    if let (Some(remote), false) = (&remote, online) {
+        crate::core::hold_unmounted(remote, txn)
+            .await
+            .map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+    }
+
+    let result =
+        resize_filesystem(&device_path, volume, size, shared, online, remote.clone()).await;
+
+    if let (Some(remote), false) = (&remote, online) {
+        if let Err(e) = crate::core::release_unmounted(remote, txn).await {
+            tracing::warn!("{:#}", e);
+        }
+    }
+    result
+}
+
+/// Resizes the volume and the filesystem on it, with the locks [`resize`]
+/// needs held. Locks are taken through `remote` if given.
+async fn resize_filesystem<P: AsRef<Path>>(
+    device_path: P,
+    volume: Option<&str>,
+    size: u64,
+    shared: bool,
+    online: bool,
+    remote: Option<RemoteMetadataCoordinator>,
+) -> Result<()> {
+    // Grow a volume before the filesystem on it, and shrink it after.
+    let mut table = None;
+    if let Some(id) = volume {
+        let bd = BlockDevice::open(&device_path, DEFAULT_BLOCK_SIZE)?;
+        let mut volumes = VolumeTable::load(&bd)?;
+        let before = volumes.get(id).map_or(0, |v| v.size);
+        if volumes.resize(id, size, bd.size()?)?.size > before {
+            volumes.save(&bd)?;
+        } else {
+            table = Some((bd, volumes));
+        }
+    }
+
+    let io_mode = if shared {
+        IoMode::Direct
+    } else {
+        IoMode::Buffered
+    };
+    let bd = open_device(&device_path, volume, io_mode, IoBackend::Sync, false)?;
+    let blocks = size / bd.block_size() as u64;
+    let current = Superblock::load(&bd)?.blocks(&bd)?;
+    if online && blocks < current {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "The filesystem can only shrink while no node has it mounted; \
+             leave out --online",
+        ));
+    }
+    let coordinator: Box<dyn MetadataCoordinator> = match remote {
+        Some(remote) => Box::new(remote),
+        None => Box::new(LocalMetadataCoordinator::new()),
+    };
+
+    FsCore::with_coordinator(bd, coordinator)
+        .resize(blocks)
+        .await
+        .map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+    if let Some((bd, volumes)) = table {
+        volumes.save(&bd)?;
+    }
+
+    tracing::info!(
+        "Resized the filesystem from {} to {} blocks",
+        current,
+        blocks
+    );
+    Ok(())
+}
+
+pub fn is_formatted<P: AsRef<Path>>(device_path: P, volume: Option<&str>) -> std::io::Result<bool> {
     // let mut file = OpenOptions::new()
     //     .read(true)
     //     .open(device)?;
-    let mut bd = BlockDevice::open(&device_path, 4096)?;
+    let bd = open_device(
+        &device_path,
+        volume,
+        IoMode::Buffered,
+        IoBackend::Sync,
+        false,
+    )?;
 
-    match Superblock::load(&mut bd.file, DEFAULT_BLOCK_SIZE) {
+    match Superblock::load(&bd) {
         Ok(_) => Ok(true),
         Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Ok(false),
         Err(e) => Err(e),
@@ -4,77 +4,133 @@ use fuser::{
 };
 
 use libc::{EIO, ENOENT};
+use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::future::Future;
+use std::ops::Range;
 use std::sync::Arc;
+use tracing::Instrument;
 
+use super::readahead::Readahead;
 use crate::layout::*;
+use crate::metrics::Operation;
 
 use std::time::{Duration, SystemTime};
 
-const TTL: Duration = Duration::from_secs(1); // 1 second
+/// How long the kernel may cache attributes and directory entries. It is
+/// not told when other nodes change them, so this bounds how long it serves
+/// stale ones.
+const TTL: Duration = Duration::from_secs(1);
+
+/// Maps a failed core operation to an errno, reporting deadlocks the
+/// coordinator could not resolve by retrying as `EDEADLK`, changes dropped
+/// with a lost lock as `EIO` and running out of inodes as `ENOSPC`.
+fn errno_for(err: &anyhow::Error, fallback: i32) -> i32 {
+    if crate::is_deadlock(err) {
+        libc::EDEADLK
+    } else if err.downcast_ref::<crate::metadata::LockError>()
+        == Some(&crate::metadata::LockError::Lost)
+    {
+        libc::EIO
+    } else if err
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::StorageFull)
+    {
+        libc::ENOSPC
+    } else {
+        fallback
+    }
+}
+
+/// Runs the rest of a request on the runtime, inside the request's span.
+fn spawn_in<F>(span: tracing::Span, future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(future.instrument(span));
+}
 
 pub struct AwsomeFs {
     core: Arc<crate::FsCore>,
+    /// Most bytes read ahead of sequential reads.
+    readahead: u64,
+    /// Readahead state by file handle.
+    handles: HashMap<u64, Readahead>,
+    next_fh: u64,
 }
 
 impl AwsomeFs {
-    pub async fn new(core: Arc<crate::FsCore>) -> std::io::Result<Self> {
+    pub async fn new(core: Arc<crate::FsCore>, readahead: u64) -> std::io::Result<Self> {
         core.load_from_device().await?; // <-- load early!
         tracing::info!("Filesystem loaded");
-        Ok(Self { core })
+        Ok(Self {
+            core,
+            readahead,
+            handles: HashMap::new(),
+            next_fh: 1,
+        })
+    }
+
+    fn allocate_fh(&mut self) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        fh
+    }
+
+    /// Prefetches bytes `range` of the file in the background, if they are
+    /// not read along with the inode anyway.
+    fn readahead(&self, ino: u64, range: Range<u64>) {
+        let blocks = self.core.readahead_blocks(ino, range);
+        if blocks.is_empty() {
+            return;
+        }
+        let span = tracing::debug_span!(
+            "fuse.readahead",
+            ino,
+            start = blocks.start,
+            end = blocks.end
+        );
+        let core = self.core.clone();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            if let Err(e) = core.readahead(ino, blocks) {
+                tracing::debug!("Readahead of inode {} failed: {}", ino, e);
+            }
+        });
     }
 }
 
 impl Filesystem for AwsomeFs {
     fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let op = Operation::start("lookup");
+        let span = tracing::info_span!("fuse.lookup", parent, name = ?name);
         let name = name.to_owned();
         let core = self.core.clone();
 
-        tokio::spawn(async move {
-            core.with_inner(|inner| {
-                inner.load_superblock().unwrap();
-
-                let parent_path = inner
-                    .path_to_ino
-                    .iter()
-                    .find(|(_, &ino)| ino == parent)
-                    .map(|(p, _)| p.clone())
-                    .unwrap();
-
-                let path = if parent == crate::ROOT_INO {
-                    format!("/{}", name.to_str().unwrap())
-                } else {
-                    format!("{}/{}", parent_path, name.to_str().unwrap())
-                };
-
-                for ino in 1..inner.inode_counter + 1 {
-                    if let Ok(inode) = inner.get_or_load_inode(ino) {
-                        if inode.path == path {
-                            reply.entry(&TTL, &inode.attr.into(), 0);
-                            return;
-                        }
-                    }
+        spawn_in(span, async move {
+            match core.lookup(parent, &name.to_string_lossy()).await {
+                Ok(Some(inode)) => reply.entry(&TTL, &inode.attr.into(), 0),
+                Ok(None) | Err(_) => {
+                    op.failed();
+                    reply.error(ENOENT);
                 }
-                tracing::info!("this is where its wrong {} for", path);
-
-                reply.error(ENOENT);
-            })
-            .await;
+            }
         });
     }
 
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        let op = Operation::start("getattr");
+        let span = tracing::info_span!("fuse.getattr", ino);
         let core = self.core.clone();
 
-        tokio::task::block_in_place(|| {
-            let mut inner = core.blocking_lock_inner();
-
-            match inner.get_or_load_inode(ino) {
+        spawn_in(span, async move {
+            match core.get_inode(ino).await {
                 Ok(inode) => {
                     reply.attr(&TTL, &inode.attr.into());
                 }
                 Err(_) => {
                     tracing::info!("getattr: inode {} not found", ino);
+                    op.failed();
                     reply.error(ENOENT);
                 }
             }
@@ -90,19 +146,21 @@ impl Filesystem for AwsomeFs {
         _umask: u32,
         reply: ReplyEntry,
     ) {
+        let op = Operation::start("mkdir");
+        let span = tracing::info_span!("fuse.mkdir", parent, name = ?name);
         let name = name.to_string_lossy().to_string();
         let core = self.core.clone(); // Arc<FsCore>
 
-        tokio::task::block_in_place(|| {
-            let mut inner = core.blocking_lock_inner();
-
-            match inner.mkdir(parent, &name, 1000, 1000) {
+        spawn_in(span, async move {
+            match core.mkdir(parent, &name, 1000, 1000).await {
                 // TODO: real uid/gid
                 Ok(attr) => {
                     reply.entry(&TTL, &attr, 0);
                 }
-                Err(_) => {
-                    reply.error(libc::EIO);
+                Err(e) => {
+                    tracing::error!("mkdir failed, parent:{} name:{}: {:#}", parent, name, e);
+                    op.failed();
+                    reply.error(errno_for(&e, libc::EIO));
                 }
             }
         });
@@ -112,17 +170,27 @@ impl Filesystem for AwsomeFs {
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock: Option<u64>,
         reply: ReplyData,
     ) {
+        let op = Operation::start("read");
+        let span = tracing::info_span!("fuse.read", ino, offset, size);
         let core = self.core.clone();
-
-        tokio::spawn(async move {
-            core.with_inner(|inner| match inner.load_inode(ino) {
+        let prefetch =
+            self.handles
+                .entry(fh)
+                .or_default()
+                .on_read(offset as u64, size as u64, self.readahead);
+        if let Some(range) = prefetch {
+            self.readahead(ino, range);
+        }
+
+        spawn_in(span, async move {
+            match core.read_inode(ino) {
                 Ok(inode) => {
                     let start = offset as usize;
                     let end = (start + size as usize).min(inode.data.len());
@@ -133,9 +201,11 @@ impl Filesystem for AwsomeFs {
                         reply.data(&inode.data[start..end]);
                     }
                 }
-                Err(_) => reply.error(ENOENT),
-            })
-            .await;
+                Err(_) => {
+                    op.failed();
+                    reply.error(ENOENT)
+                }
+            }
         });
     }
 
@@ -147,17 +217,17 @@ impl Filesystem for AwsomeFs {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
+        let op = Operation::start("readdir");
+        let span = tracing::info_span!("fuse.readdir", ino, offset);
         let core = self.core.clone();
 
-        tokio::task::block_in_place(|| {
-            let mut inner = core.blocking_lock_inner();
-
+        spawn_in(span, async move {
             let mut entries = vec![
                 (ino, FileType::Directory, ".".into()),
                 (crate::ROOT_INO, FileType::Directory, "..".into()),
             ];
 
-            match inner.get_or_load_inode(ino) {
+            match core.get_inode(ino).await {
                 Ok(inode) => {
                     let dir_entries: Vec<DirectoryEntry> = if inode.data.is_empty()
                         || inode.attr.kind != FileType::Directory
@@ -174,7 +244,7 @@ impl Filesystem for AwsomeFs {
                     };
 
                     for entry in dir_entries {
-                        let file_type = match inner.get_or_load_inode(entry.ino) {
+                        let file_type = match core.get_inode(entry.ino).await {
                             Ok(child_inode) => child_inode.attr.kind.into(),
                             Err(_) => FileType::RegularFile, // fallback
                         };
@@ -183,6 +253,7 @@ impl Filesystem for AwsomeFs {
                     }
                 }
                 Err(_) => {
+                    op.failed();
                     reply.error(ENOENT);
                     return;
                 }
@@ -199,37 +270,86 @@ impl Filesystem for AwsomeFs {
     }
 
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        let op = Operation::start("unlink");
+        let span = tracing::info_span!("fuse.unlink", parent, name = ?name);
         let core = self.core.clone();
         let name = name.to_str().unwrap_or("").to_string();
 
-        tokio::spawn(async move {
+        spawn_in(span, async move {
             let result = core.unlink(parent, &name).await;
 
             match result {
                 Ok(_) => reply.ok(),
-                Err(_) => reply.error(libc::ENOENT),
+                Err(e) => {
+                    op.failed();
+                    reply.error(errno_for(&e, libc::ENOENT))
+                }
+            }
+        });
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let op = Operation::start("fsync");
+        let span = tracing::info_span!("fuse.fsync", ino);
+        let core = self.core.clone();
+        spawn_in(span, async move {
+            match core.flush() {
+                Ok(()) => reply.ok(),
+                Err(e) => {
+                    tracing::error!("Failed to flush inode {}: {}", ino, e);
+                    op.failed();
+                    reply.error(EIO);
+                }
             }
         });
     }
 
+    fn destroy(&mut self) {
+        if let Err(e) = self.core.flush() {
+            tracing::error!("Failed to flush on unmount: {}", e);
+        }
+    }
+
     fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let op = Operation::start("open");
+        let span = tracing::info_span!("fuse.open", ino);
         let core = self.core.clone(); // you'll need Arc<Mutex<FsCore>>
-        tokio::spawn(async move {
-            core.with_inner(|inner| {
-                match inner.load_inode(ino) {
-                    Ok(_) => {
-                        // Successfully found inode on disk
-                        reply.opened(0, 0);
-                    }
-                    Err(_) => {
-                        reply.error(libc::ENOENT);
-                    }
+        let fh = self.allocate_fh();
+        spawn_in(span, async move {
+            match core.read_inode(ino) {
+                Ok(_) => {
+                    // Successfully found inode on disk
+                    reply.opened(fh, 0);
+                }
+                Err(_) => {
+                    op.failed();
+                    reply.error(libc::ENOENT);
                 }
-            })
-            .await;
+            }
         });
     }
 
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.handles.remove(&fh);
+        reply.ok();
+    }
+
     fn write(
         &mut self,
         _req: &Request<'_>,
@@ -242,12 +362,14 @@ impl Filesystem for AwsomeFs {
         _lock_owner: Option<u64>,
         reply: fuser::ReplyWrite,
     ) {
+        let op = Operation::start("write");
+        let span = tracing::info_span!("fuse.write", ino, offset, bytes = data.len());
         let core = self.core.clone();
         let data = data.to_vec(); // <-- clone the slice into an owned Vec
 
-        tokio::spawn(async move {
-            core.with_inner(|inner| match inner.load_inode(ino) {
-                Ok(mut inode) => {
+        spawn_in(span, async move {
+            let result = core
+                .update_inode(ino, |inode| {
                     let start = offset as usize;
                     let end = start + data.len();
 
@@ -257,16 +379,21 @@ impl Filesystem for AwsomeFs {
 
                     inode.data[start..end].copy_from_slice(&data);
                     inode.attr.size = inode.data.len() as u64;
+                })
+                .await;
 
-                    if let Err(_) = inner.save_inode(ino, &inode) {
-                        reply.error(EIO);
-                    } else {
-                        reply.written(data.len() as u32);
-                    }
+            match result {
+                Ok(Some(_)) => reply.written(data.len() as u32),
+                Ok(None) => {
+                    op.failed();
+                    reply.error(ENOENT)
+                }
+                Err(e) => {
+                    tracing::error!("Failed to write inode {}: {:#}", ino, e);
+                    op.failed();
+                    reply.error(errno_for(&e, EIO));
                 }
-                Err(_) => reply.error(ENOENT),
-            })
-            .await;
+            }
         });
     }
 
@@ -280,30 +407,39 @@ impl Filesystem for AwsomeFs {
         _flags: i32,
         reply: fuser::ReplyCreate,
     ) {
+        let op = Operation::start("create");
+        let span = tracing::info_span!("fuse.create", parent, name = ?name);
         let core = self.core.clone();
         let name = name.to_str().unwrap_or("").to_string();
+        let fh = self.allocate_fh();
 
-        tokio::spawn(async move {
+        spawn_in(span, async move {
             let ino = match core.create_file(parent, &name, &[]).await {
                 Ok(ino) => ino,
-                Err(_) => {
-                    tracing::error!("create_file failed, parent:{} name:{}", parent, name);
-
-                    reply.error(EIO);
+                Err(e) => {
+                    tracing::error!(
+                        "create_file failed, parent:{} name:{}: {:#}",
+                        parent,
+                        name,
+                        e
+                    );
+
+                    op.failed();
+                    reply.error(errno_for(&e, EIO));
                     return;
                 }
             };
 
-            core.with_inner(|inner| match inner.load_inode(ino) {
+            match core.read_inode(ino) {
                 Ok(inode) => {
-                    reply.created(&TTL, &inode.attr.into(), 0, 0, 0);
+                    reply.created(&TTL, &inode.attr.into(), 0, fh, 0);
                 }
                 Err(_) => {
                     tracing::error!("Missing inode after creation, ino={}", ino);
+                    op.failed();
                     reply.error(ENOENT);
                 }
-            })
-            .await;
+            }
         });
     }
 
@@ -325,67 +461,72 @@ impl Filesystem for AwsomeFs {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
+        let op = Operation::start("setattr");
+        let span = tracing::info_span!("fuse.setattr", ino, size = ?size);
         let core = self.core.clone();
-        tokio::spawn(async move {
-            core.with_inner(|inner| {
-                match inner.load_inode(ino) {
-                    Ok(mut inode) => {
-                        if let Some(new_size) = size {
-                            inode.data.resize(new_size as usize, 0);
-                            inode.attr.size = new_size;
-                        }
-
-                        if let Some(new_uid) = uid {
-                            inode.attr.uid = new_uid;
-                        }
-                        if let Some(new_gid) = gid {
-                            inode.attr.gid = new_gid;
-                        }
-                        if let Some(new_mtime) = mtime {
-                            inode.attr.mtime = match new_mtime {
-                                TimeOrNow::SpecificTime(t) => t,
-                                TimeOrNow::Now => SystemTime::now(),
-                            };
-                        }
-                        if let Some(new_atime) = atime {
-                            inode.attr.atime = match new_atime {
-                                TimeOrNow::SpecificTime(t) => t,
-                                TimeOrNow::Now => SystemTime::now(),
-                            };
-                        }
-                        if let Some(new_ctime) = ctime {
-                            inode.attr.ctime = new_ctime;
-                        }
-
-                        // Save modified inode back to disk
-                        if let Err(e) = inner.save_inode(ino, &inode) {
-                            tracing::error!("Failed to save inode after setattr: {:?}", e);
-                            reply.error(libc::EIO);
-                            return;
-                        }
+        spawn_in(span, async move {
+            let result = core
+                .update_inode(ino, |inode| {
+                    if let Some(new_size) = size {
+                        inode.data.resize(new_size as usize, 0);
+                        inode.attr.size = new_size;
+                    }
 
-                        // Reply with updated attributes
-                        reply.attr(&TTL, &inode.attr.into());
+                    if let Some(new_uid) = uid {
+                        inode.attr.uid = new_uid;
                     }
-                    Err(_) => {
-                        reply.error(libc::ENOENT);
+                    if let Some(new_gid) = gid {
+                        inode.attr.gid = new_gid;
                     }
+                    if let Some(new_mtime) = mtime {
+                        inode.attr.mtime = match new_mtime {
+                            TimeOrNow::SpecificTime(t) => t,
+                            TimeOrNow::Now => SystemTime::now(),
+                        };
+                    }
+                    if let Some(new_atime) = atime {
+                        inode.attr.atime = match new_atime {
+                            TimeOrNow::SpecificTime(t) => t,
+                            TimeOrNow::Now => SystemTime::now(),
+                        };
+                    }
+                    if let Some(new_ctime) = ctime {
+                        inode.attr.ctime = new_ctime;
+                    }
+                })
+                .await;
+
+            match result {
+                // Reply with updated attributes
+                Ok(Some(inode)) => reply.attr(&TTL, &inode.attr.into()),
+                Ok(None) => {
+                    op.failed();
+                    reply.error(libc::ENOENT)
+                }
+                Err(e) => {
+                    tracing::error!("Failed to save inode after setattr: {:#}", e);
+                    op.failed();
+                    reply.error(errno_for(&e, libc::EIO));
                 }
-            })
-            .await;
+            }
         });
     }
 
     fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        let op = Operation::start("rmdir");
+        let span = tracing::info_span!("fuse.rmdir", parent, name = ?name);
         let core = self.core.clone();
         let name = name.to_str().unwrap_or("").to_string();
 
-        tokio::spawn(async move {
+        spawn_in(span, async move {
             let result = core.unlink(parent, &name).await;
 
             match result {
                 Ok(_) => reply.ok(),
-                Err(_) => reply.error(libc::ENOENT),
+                Err(e) => {
+                    op.failed();
+                    reply.error(errno_for(&e, libc::ENOENT))
+                }
             }
         });
     }
@@ -1 +1,3 @@
 pub mod filesystem;
+pub mod notify;
+pub mod readahead;
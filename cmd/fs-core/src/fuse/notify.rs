@@ -0,0 +1,33 @@
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::metadata::Invalidation;
+use crate::FsCore;
+
+/// Applies changes made by other nodes to our cache. What the kernel cached
+/// of them expires after the TTL our replies give it.
+pub async fn forward_invalidations(
+    core: Arc<FsCore>,
+    mut invalidations: broadcast::Receiver<Invalidation>,
+) {
+    loop {
+        let result = match invalidations.recv().await {
+            Ok(Invalidation::Inode(ino)) => {
+                core.with_inner(|inner| inner.invalidate_inode(ino)).await
+            }
+            Ok(Invalidation::Lost(inos)) => {
+                let result = core.with_inner(|inner| inner.invalidate_lost(&inos)).await;
+                let core = core.clone();
+                tokio::spawn(async move { core.reclaim_mount(&inos).await });
+                result
+            }
+            Ok(Invalidation::All) | Err(RecvError::Lagged(_)) => {
+                core.with_inner(|inner| inner.invalidate_all()).await
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if let Err(e) = result {
+            tracing::error!("Failed to invalidate cached blocks: {}", e);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::ops::Range;
+
+/// Readahead state of one open file handle.
+///
+/// Reads that start where the previous one ended are sequential. While
+/// they are, the window doubles, starting from twice the read size, up to
+/// the mount's maximum. Once less than half a window is left ahead of the
+/// reader, the next window is prefetched. Any other read starts over.
+#[derive(Debug, Default)]
+pub struct Readahead {
+    /// Where the next read starts if access is sequential.
+    next: u64,
+    window: u64,
+    /// End of what has been prefetched.
+    until: u64,
+}
+
+impl Readahead {
+    /// Records a read of `size` bytes at `offset`, and returns the bytes of
+    /// the file to prefetch, if any, with windows of at most `max` bytes.
+    pub fn on_read(&mut self, offset: u64, size: u64, max: u64) -> Option<Range<u64>> {
+        let end = offset.saturating_add(size);
+        let sequential = offset == self.next;
+        self.next = end;
+        if !sequential || max == 0 {
+            self.window = 0;
+            self.until = end;
+            return None;
+        }
+        if self.until.saturating_sub(end) > self.window / 2 {
+            return None;
+        }
+
+        self.window = if self.window == 0 {
+            size.saturating_mul(2)
+        } else {
+            self.window.saturating_mul(2)
+        }
+        .min(max);
+        let start = self.until.max(end);
+        self.until = end.saturating_add(self.window);
+        (start < self.until).then_some(start..self.until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u64 = 64;
+
+    #[test]
+    fn first_read_prefetches_nothing() {
+        let mut ra = Readahead::default();
+        assert_eq!(ra.on_read(100, 4, MAX), None);
+    }
+
+    #[test]
+    fn sequential_reads_grow_the_window_up_to_max() {
+        let mut ra = Readahead::default();
+        assert_eq!(ra.on_read(0, 4, MAX), Some(4..12));
+        assert_eq!(ra.on_read(4, 4, MAX), Some(12..24));
+        // More than half a window left ahead of the reader.
+        assert_eq!(ra.on_read(8, 4, MAX), None);
+        assert_eq!(ra.on_read(12, 4, MAX), Some(24..48));
+        for offset in [16, 20, 24] {
+            assert_eq!(ra.on_read(offset, 4, MAX), None);
+        }
+        // The window stops growing at the maximum.
+        assert_eq!(ra.on_read(28, 4, MAX), Some(48..96));
+        assert_eq!(ra.window, MAX);
+        // From then on, windows follow each other without gaps or overlap.
+        let mut prefetched = 96;
+        for offset in (32..256).step_by(4) {
+            if let Some(range) = ra.on_read(offset, 4, MAX) {
+                assert_eq!(range, prefetched..offset + 4 + MAX);
+                prefetched = range.end;
+            }
+        }
+        assert!(prefetched >= 256);
+    }
+
+    #[test]
+    fn seek_starts_over() {
+        let mut ra = Readahead::default();
+        assert_eq!(ra.on_read(0, 4, MAX), Some(4..12));
+        assert_eq!(ra.on_read(1000, 4, MAX), None);
+        assert_eq!(ra.window, 0);
+        // Sequential again from the new position, with the initial window.
+        assert_eq!(ra.on_read(1004, 4, MAX), Some(1008..1016));
+    }
+
+    #[test]
+    fn zero_max_disables_readahead() {
+        let mut ra = Readahead::default();
+        assert_eq!(ra.on_read(0, 4, 0), None);
+        assert_eq!(ra.on_read(4, 4, 0), None);
+    }
+}
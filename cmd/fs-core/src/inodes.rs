@@ -0,0 +1,151 @@
+//! Inodes on the block store. Inode `n` is kept in block `n + 1`, after the
+//! superblock, as the length of the serialized inode followed by the inode.
+
+use bincode::serialize;
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
+
+use crate::block::BlockStore;
+use crate::layout::PersistedInode;
+
+/// Inodes share this many locks, by inode number modulo the count.
+const LOCK_SHARDS: u64 = 64;
+
+/// Reads and writes inodes. IO on an inode holds a read or write lock for
+/// it, so a read never sees half of a write while IO on other inodes goes
+/// ahead in parallel.
+#[derive(Clone)]
+pub struct InodeStore {
+    store: Arc<dyn BlockStore>,
+    locks: Arc<[RwLock<()>]>,
+}
+
+impl InodeStore {
+    pub fn new(store: Arc<dyn BlockStore>) -> Self {
+        Self {
+            store,
+            locks: (0..LOCK_SHARDS).map(|_| RwLock::new(())).collect(),
+        }
+    }
+
+    /// The store the inodes are on, for IO on the other blocks.
+    pub fn store(&self) -> &dyn BlockStore {
+        self.store.as_ref()
+    }
+
+    fn lock(&self, ino: u64) -> &RwLock<()> {
+        &self.locks[(ino % LOCK_SHARDS) as usize]
+    }
+
+    /// Block holding inode `ino`.
+    pub fn block(ino: u64) -> u64 {
+        1 + ino // Block 0 is superblock
+    }
+
+    /// Highest inode number whose block is among the first `blocks`.
+    pub fn max_inode(blocks: u64) -> u64 {
+        blocks.saturating_sub(2)
+    }
+
+    /// Reads the inode. Fails with `InvalidData` if there is none.
+    pub fn load(&self, ino: u64) -> io::Result<PersistedInode> {
+        let _guard = self.lock(ino).read().unwrap();
+        self.read(ino)
+    }
+
+    pub fn save(&self, ino: u64, inode: &PersistedInode) -> io::Result<()> {
+        let _guard = self.lock(ino).write().unwrap();
+        self.write(ino, inode)
+    }
+
+    /// Discards the inode's block, which then reads as no inode.
+    pub fn delete(&self, ino: u64) -> io::Result<()> {
+        let _guard = self.lock(ino).write().unwrap();
+        self.store.discard(Self::block(ino), 1)
+    }
+
+    /// Reads `blocks` of the inode's data, as found by
+    /// [`Self::data_blocks`], so the block store can cache them ahead of the
+    /// reads asking for them.
+    pub fn prefetch(&self, ino: u64, blocks: Range<u64>) -> io::Result<()> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let _guard = self.lock(ino).read().unwrap();
+        let mut buf = vec![0u8; (blocks.end - blocks.start) as usize * self.store.block_size()];
+        self.store.read_block(blocks.start, &mut buf)
+    }
+
+    /// Blocks holding bytes `range` of the inode's data that reading the
+    /// inode does not already fetch. None so far: the data is kept in the
+    /// inode's block, after the inode's other fields, so there is nothing
+    /// to read ahead until file data gets blocks of its own.
+    pub fn data_blocks(&self, ino: u64, _range: Range<u64>) -> Range<u64> {
+        let block = Self::block(ino);
+        block..block
+    }
+
+    /// Drops cached copies of the inode's block, see
+    /// [`BlockStore::invalidate`].
+    pub fn invalidate(&self, ino: u64) -> io::Result<()> {
+        self.store.invalidate(Self::block(ino), 1)
+    }
+
+    /// Drops cached changes to the inode's block, see
+    /// [`BlockStore::forget_changes`].
+    pub fn forget_changes(&self, ino: u64) {
+        self.store.forget_changes(Self::block(ino), 1)
+    }
+
+    /// Applies `f` to the inode and writes it back, with no other IO on the
+    /// inode in between. Returns `None` if the inode does not exist.
+    pub fn update<F>(&self, ino: u64, f: F) -> io::Result<Option<PersistedInode>>
+    where
+        F: FnOnce(&mut PersistedInode),
+    {
+        let _guard = self.lock(ino).write().unwrap();
+        let mut inode = match self.read(ino) {
+            Ok(inode) => inode,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        f(&mut inode);
+        self.write(ino, &inode)?;
+        Ok(Some(inode))
+    }
+
+    fn read(&self, ino: u64) -> io::Result<PersistedInode> {
+        let mut buf = vec![0u8; self.store.block_size()];
+        self.store.read_block(Self::block(ino), &mut buf)?;
+
+        let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+
+        if len == 0 || 4 + len > buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid inode length",
+            ));
+        }
+
+        let serialized = &buf[4..4 + len];
+
+        bincode::deserialize::<PersistedInode>(serialized)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write(&self, ino: u64, inode: &PersistedInode) -> io::Result<()> {
+        let bytes = serialize(&inode).unwrap();
+        let len = bytes.len() as u32; // 4 bytes to store size
+        let mut padded = vec![0u8; self.store.block_size()];
+
+        if 4 + bytes.len() > padded.len() {
+            return Err(io::Error::other("Serialized inode too large for block"));
+        }
+
+        padded[..4].copy_from_slice(&len.to_le_bytes()); // Save length first
+        padded[4..4 + bytes.len()].copy_from_slice(&bytes);
+
+        self.store.write_block(Self::block(ino), &padded)
+    }
+}
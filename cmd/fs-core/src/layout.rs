@@ -126,6 +126,16 @@ pub struct PersistedInode {
     pub path: String,
 }
 
+impl PersistedInode {
+    /// The entries of a directory; none for other inodes.
+    pub fn entries(&self) -> Vec<DirectoryEntry> {
+        if self.attr.kind != FileType::Directory || self.data.is_empty() {
+            return Vec::new();
+        }
+        bincode::deserialize(&self.data).unwrap_or_default()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DirectoryEntry {
     pub name: String,
@@ -2,11 +2,15 @@ pub mod block;
 pub mod volume;
 pub mod metadata;
 pub mod cli;
+pub mod config;
 pub mod fs;
 pub mod superblock;
+pub mod telemetry;
 pub mod fuse;
 pub mod core;
+pub mod inodes;
 pub mod layout;
+pub mod metrics;
 
 pub use core::*;
 pub use layout::*;
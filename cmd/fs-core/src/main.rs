@@ -1,38 +1,147 @@
 use clap::Parser;
+use fs_core::config::MountConfig;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::process;
 
+/// Settings given as flags, completed from the config file at `path`.
+fn mount_config(path: Option<&Path>, flags: &MountConfig) -> std::io::Result<MountConfig> {
+    let file = match path {
+        Some(path) => MountConfig::load(path)?,
+        None => MountConfig::default(),
+    };
+    Ok(flags.clone().or(file))
+}
+
+/// Starts the metrics endpoint, if an address was given.
+fn serve_metrics(addr: Option<SocketAddr>) -> std::io::Result<()> {
+    match addr {
+        Some(addr) => fs_core::metrics::spawn_server(addr),
+        None => Ok(()),
+    }
+}
+
 #[tokio::main]
 async fn main() {
     tracing_log::LogTracer::init().expect("Failed to set up LogTracer");
 
-    let _ = tracing_subscriber::fmt()
-    .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .try_init();
     let cli = fs_core::Cli::parse();
+    let telemetry = match fs_core::telemetry::init(cli.otlp_endpoint.as_deref()) {
+        Ok(telemetry) => telemetry,
+        Err(e) => {
+            eprintln!("Error: Failed to set up trace export: {}", e);
+            process::exit(1);
+        }
+    };
 
     if let Err(e) = match &cli.command {
-        fs_core::Commands::Format { device } => {
+        fs_core::Commands::Format {
+            device,
+            volume,
+            size,
+        } => {
             tracing::info!("Running format on {}", device.display());
-            fs_core::fs::format(device)
+            fs_core::fs::format(device, volume.as_deref(), *size)
         }
-        fs_core::Commands::Mount { device, mountpoint } => {
+        fs_core::Commands::Mount {
+            device,
+            mountpoint,
+            volume,
+            config,
+            coordinator,
+            force,
+            metrics_listen,
+            options,
+            metadata,
+        } => {
             tracing::info!("Mounting {} to {}", device.display(), mountpoint.display());
-            fs_core::fs::mount(device, mountpoint).await
+            match serve_metrics(*metrics_listen)
+                .and_then(|_| mount_config(config.as_deref(), metadata))
+            {
+                Ok(config) => {
+                    fs_core::fs::mount(
+                        device,
+                        mountpoint,
+                        volume.as_deref(),
+                        *coordinator,
+                        *force,
+                        options,
+                        &config,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            }
+        }
+        fs_core::Commands::Resize {
+            device,
+            volume,
+            size,
+            online,
+            config,
+            metadata,
+        } => {
+            tracing::info!("Resizing {} to {} bytes", device.display(), size);
+            match mount_config(config.as_deref(), metadata) {
+                Ok(config) => {
+                    fs_core::fs::resize(device, volume.as_deref(), *size, *online, &config).await
+                }
+                Err(e) => Err(e),
+            }
+        }
+        fs_core::Commands::Trim {
+            device,
+            volume,
+            config,
+            metadata,
+        } => {
+            tracing::info!("Trimming {}", device.display());
+            match mount_config(config.as_deref(), metadata) {
+                Ok(config) => fs_core::fs::trim(device, volume.as_deref(), &config).await,
+                Err(e) => Err(e),
+            }
         }
-        fs_core::Commands::Debug { device } => {
+        fs_core::Commands::Debug { device, volume } => {
             tracing::info!("Debug info {}", device.display());
-            fs_core::fs::debug(device)
+            fs_core::fs::debug(device, volume.as_deref())
         }
-        fs_core::Commands::Serve { device, mountpoint } => {
+        fs_core::Commands::Serve {
+            device,
+            mountpoint,
+            volume,
+            config,
+            coordinator,
+            force,
+            metrics_listen,
+            options,
+            metadata,
+        } => {
             tracing::info!(
                 "Serving filesystem on {} mounted at {}",
                 device.display(),
                 mountpoint.display()
             );
-            fs_core::fs::mount(device, mountpoint).await
+            match serve_metrics(*metrics_listen)
+                .and_then(|_| mount_config(config.as_deref(), metadata))
+            {
+                Ok(config) => {
+                    fs_core::fs::mount(
+                        device,
+                        mountpoint,
+                        volume.as_deref(),
+                        *coordinator,
+                        *force,
+                        options,
+                        &config,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            }
         }
     } {
         eprintln!("Error: {}", e);
+        drop(telemetry);
         process::exit(1);
     }
 }
@@ -1,19 +1,48 @@
 use super::*;
 use anyhow;
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::ops::Range;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::{Duration, Instant};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// A held key and the requests waiting for it.
+#[derive(Default)]
+struct KeyState {
+    held: bool,
+    waiters: usize,
+    /// Wakes one waiter per release.
+    released: Arc<Notify>,
+}
+
+type Locks = Mutex<HashMap<LockKey, KeyState>>;
 
 #[derive(Default)]
 pub struct LocalMetadataCoordinator {
-    locks: Arc<Mutex<HashSet<LockKey>>>,
+    locks: Arc<Locks>,
 }
 
 impl LocalMetadataCoordinator {
     pub fn new() -> Self {
-        Self {
-            locks: Mutex::new(HashSet::new()).into(),
+        Self::default()
+    }
+}
+
+/// Counts a request among the key's waiters until it is granted or gives
+/// up, so the key's state outlives the release that wakes it.
+struct Waiting<'a> {
+    locks: &'a Locks,
+    key: &'a LockKey,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap();
+        if let Some(state) = locks.get_mut(self.key) {
+            state.waiters -= 1;
+            if !state.held && state.waiters == 0 {
+                locks.remove(self.key);
+            }
         }
     }
 }
@@ -23,35 +52,75 @@ impl MetadataCoordinator for LocalMetadataCoordinator {
     async fn lock(
         &self,
         key: LockKey,
-        lock_type: LockType,
+        _lock_type: LockType,
         timeout: Duration,
+        _txn: u64,
     ) -> anyhow::Result<()> {
-        let deadline = Instant::now() + timeout;
+        let deadline = tokio::time::Instant::now() + timeout;
+        let released = {
+            let mut locks = self.locks.lock().unwrap();
+            let state = locks.entry(key.clone()).or_default();
+            if !state.held {
+                state.held = true;
+                return Ok(());
+            }
+            state.waiters += 1;
+            state.released.clone()
+        };
+        let _waiting = Waiting {
+            locks: &self.locks,
+            key: &key,
+        };
+
         loop {
+            // Register for the wakeup before checking, so an unlock between the
+            // check and the await is not missed.
+            let notified = released.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
             {
                 let mut locks = self.locks.lock().unwrap();
-                if !locks.contains(&key) {
-                    locks.insert(key.clone());
+                let state = locks.get_mut(&key).unwrap();
+                if !state.held {
+                    state.held = true;
                     return Ok(());
                 }
             }
-            if Instant::now() > deadline {
+
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
                 anyhow::bail!("Timeout while acquiring lock on {:?}", key);
             }
-            thread::sleep(Duration::from_millis(10)); // simple backoff
         }
     }
 
-    async fn unlock(&self, key: LockKey) -> anyhow::Result<()> {
+    async fn unlock(&self, key: LockKey, _txn: u64) -> anyhow::Result<()> {
         let mut locks = self.locks.lock().unwrap();
-        if locks.remove(&key) {
-            Ok(())
-        } else {
-            anyhow::bail!("Tried to unlock a non-held lock {:?}", key);
+        match locks.get_mut(&key) {
+            Some(state) if state.held => {
+                state.held = false;
+                if state.waiters == 0 {
+                    locks.remove(&key);
+                } else {
+                    // A woken waiter that gives up passes the wakeup on.
+                    state.released.notify_one();
+                }
+                Ok(())
+            }
+            _ => anyhow::bail!("Tried to unlock a non-held lock {:?}", key),
         }
     }
-    
-    async fn is_locked(&self, key: &LockKey) -> bool {
-        self.locks.lock().unwrap().contains(key)
+
+    async fn is_locked(&self, key: &LockKey, _txn: u64) -> bool {
+        self.locks
+            .lock()
+            .unwrap()
+            .get(key)
+            .is_some_and(|state| state.held)
+    }
+
+    /// No other node allocates, so everything above `floor` is free.
+    async fn allocate_inodes(&self, floor: u64, count: u32) -> anyhow::Result<Range<u64>> {
+        Ok(floor + 1..floor + 1 + count as u64)
     }
 }
@@ -1,25 +1,103 @@
 // use std::collections::HashMap;
 // use std::path::PathBuf;
+use std::ops::Range;
 use std::time::Duration;
+use tokio::sync::broadcast;
 // use anyhow::Result;
 // use std::result::Result;
 
 pub mod local;
 pub mod remote;
 
-
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct LockKey(pub u64); // inode ID
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum LockType {
     Read,
     Write,
 }
 
+/// Lock failures callers may want to react to, carried inside `anyhow::Error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockError {
+    Timeout,
+    /// The coordinator aborted the request to break a deadlock; retrying the
+    /// whole operation may succeed.
+    Deadlock,
+    /// The lock went with an expired session, so what was done under it
+    /// must not reach the device.
+    Lost,
+}
+
+impl std::fmt::Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Timeout => write!(f, "timed out waiting for lock"),
+            LockError::Deadlock => write!(f, "lock request aborted to break a deadlock"),
+            LockError::Lost => write!(f, "lock was lost with an expired session"),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+/// Something another node may have changed since we cached it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalidation {
+    Inode(u64),
+    /// Changes may have been missed; nothing cached can be trusted.
+    All,
+    /// The locks on these inodes were lost, so changes made under them
+    /// must be dropped rather than written. Implies `All`.
+    Lost(Vec<u64>),
+}
+
+/// Locks are taken, checked and released for an operation, named by a
+/// transaction id that is never 0. A lock lost with an expired session thus
+/// stays lost to the operation that took it, even once another operation
+/// holds the key again.
 #[tonic::async_trait]
 pub trait MetadataCoordinator: Send + Sync {
-    async fn lock(&self, key: LockKey, lock_type: LockType, timeout: Duration) -> anyhow::Result<()>;
-    async fn unlock(&self, key: LockKey) -> anyhow::Result<()>;
-    async fn is_locked(&self, key: &LockKey) -> bool;
+    async fn lock(
+        &self,
+        key: LockKey,
+        lock_type: LockType,
+        timeout: Duration,
+        txn: u64,
+    ) -> anyhow::Result<()>;
+    async fn unlock(&self, key: LockKey, txn: u64) -> anyhow::Result<()>;
+    /// Whether `txn` holds the lock on `key`; false once it has been lost.
+    async fn is_locked(&self, key: &LockKey, txn: u64) -> bool;
+
+    /// Reserves `count` inode numbers above `floor` that are not handed to
+    /// any other node.
+    async fn allocate_inodes(&self, floor: u64, count: u32) -> anyhow::Result<Range<u64>>;
+
+    /// Changes made by other nodes, for coordinators that learn about them.
+    /// `None` means no other node modifies the filesystem.
+    fn invalidations(&self) -> Option<broadcast::Receiver<Invalidation>> {
+        None
+    }
+
+    /// Takes every lock in `keys` for operation `txn`, in the given order.
+    /// An operation may take its locks in several calls. On failure the locks acquired by this call are released
+    /// again.
+    async fn lock_in_txn(
+        &self,
+        txn: u64,
+        keys: &[LockKey],
+        lock_type: LockType,
+        timeout: Duration,
+    ) -> anyhow::Result<()> {
+        for (i, key) in keys.iter().enumerate() {
+            if let Err(e) = self.lock(key.clone(), lock_type, timeout, txn).await {
+                for held in keys[..i].iter().rev() {
+                    let _ = self.unlock(held.clone(), txn).await;
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
 }
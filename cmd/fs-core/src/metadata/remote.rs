@@ -3,22 +3,593 @@ use super::*;
 use anyhow::{Context, Result};
 use proto::metadata::{
     metadata_client::MetadataClient,
-    LockRequest, // UnlockRequest, IsLockedRequest,
+    AllocateInodesRequest,
+    HeartbeatRequest,
+    LockRequest,
+    LockStatus,
+    OpenSessionRequest,
+    RegisterRequest,
+    SessionRequest,
+    SessionResponse,
+    WatchRequest,
+    // IsLockedRequest,
 };
+use proto::LEADER_METADATA_KEY;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tonic::transport::Channel;
+use tokio::sync::broadcast;
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::Code;
+
+/// Extra time granted to the RPC on top of the server-side lock wait, so the
+/// server reports the timeout rather than the transport.
+const RPC_DEADLINE_SLACK: Duration = Duration::from_secs(1);
+/// Lease requested for our session; renewed three times per period.
+const SESSION_TTL: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Attempts per call across replicas before giving up; enough to ride out a
+/// leader election.
+const FAILOVER_ATTEMPTS: usize = 10;
+/// Pause before trying the next replica when none named a leader.
+const FAILOVER_BACKOFF: Duration = Duration::from_millis(200);
+/// First and longest pause before re-establishing a broken Watch stream or
+/// an expired session.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(200);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// Invalidations buffered for a slow consumer before it is told to drop
+/// everything instead.
+const INVALIDATION_BUFFER: usize = 1024;
+/// Used if registration fails; the server normally tells us the interval.
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Name this node reports when opening its session.
+fn client_name() -> String {
+    std::env::var("NODE_NAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "fs-core".to_string())
+}
+
+/// Where this node can be reached, as reported to the metadata-service.
+fn node_address() -> String {
+    std::env::var("POD_IP").unwrap_or_default()
+}
+
+fn lazy_channel(addr: &str, tls: Option<&ClientTlsConfig>) -> Result<Channel> {
+    let mut endpoint = Endpoint::from_shared(addr.to_string())
+        .with_context(|| format!("Invalid metadata-service endpoint '{}'", addr))?
+        .connect_timeout(CONNECT_TIMEOUT);
+    if let Some(tls) = tls {
+        endpoint = endpoint
+            .tls_config(tls.clone())
+            .with_context(|| format!("Invalid TLS settings for '{}'", addr))?;
+    }
+    Ok(endpoint.connect_lazy())
+}
+
+/// Adds the trace context and the bearer token read from a file to every
+/// request.
+#[derive(Clone, Default)]
+struct CallMetadata {
+    file: Option<Arc<PathBuf>>,
+}
+
+impl Interceptor for CallMetadata {
+    fn call(
+        &mut self,
+        mut request: tonic::Request<()>,
+    ) -> Result<tonic::Request<()>, tonic::Status> {
+        crate::telemetry::inject_context(request.metadata_mut());
+        let Some(file) = &self.file else {
+            return Ok(request);
+        };
+        let token = std::fs::read_to_string(file.as_ref()).map_err(|e| {
+            tonic::Status::unauthenticated(format!("Failed to read {:?}: {}", file, e))
+        })?;
+        let value = format!("Bearer {}", token.trim())
+            .parse()
+            .map_err(|_| tonic::Status::unauthenticated(format!("Invalid token in {:?}", file)))?;
+        request.metadata_mut().insert("authorization", value);
+        Ok(request)
+    }
+}
+
+type Client = MetadataClient<InterceptedService<Channel, CallMetadata>>;
+
+/// Whether a request that failed with `status` certainly had no effect: a
+/// replica answered UNAVAILABLE, which the service only does for requests
+/// it did not carry out, or the request never reached one. A connection
+/// lost after sending it leaves that open.
+fn not_processed(status: &tonic::Status) -> bool {
+    if status.code() != Code::Unavailable {
+        return false;
+    }
+    // Statuses a server sent have no source; transport failures do.
+    let mut source = std::error::Error::source(status);
+    if source.is_none() {
+        return true;
+    }
+    while let Some(err) = source {
+        if err
+            .downcast_ref::<hyper::Error>()
+            .is_some_and(hyper::Error::is_connect)
+        {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Pauses between reconnection attempts, doubling each time.
+struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self {
+            next: RECONNECT_BACKOFF_MIN,
+        }
+    }
+
+    async fn wait(&mut self) {
+        tokio::time::sleep(self.next).await;
+        self.next = (self.next * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+
+    fn reset(&mut self) {
+        self.next = RECONNECT_BACKOFF_MIN;
+    }
+}
+
+/// The metadata-service replicas we know of and the one we talk to.
+struct Endpoints {
+    channels: Vec<(String, Channel)>,
+    current: usize,
+    /// Used for `https://` replicas, including leaders we are redirected to.
+    tls: Option<ClientTlsConfig>,
+    metadata: CallMetadata,
+}
+
+impl Endpoints {
+    fn client(&self) -> (usize, Client) {
+        let (_, channel) = &self.channels[self.current];
+        let client = MetadataClient::with_interceptor(channel.clone(), self.metadata.clone());
+        (self.current, client)
+    }
+
+    /// Moves on from replica `failed` to `leader` if it was named, or to the
+    /// next replica in the list. Another call may have moved on already.
+    fn fail_over(&mut self, failed: usize, leader: Option<&str>) {
+        if self.current != failed {
+            return;
+        }
+        let known = leader.and_then(|addr| self.channels.iter().position(|(a, _)| a == addr));
+        self.current = match (leader, known) {
+            (_, Some(pos)) => pos,
+            (Some(addr), None) => match lazy_channel(addr, self.tls.as_ref()) {
+                Ok(channel) => {
+                    self.channels.push((addr.to_string(), channel));
+                    self.channels.len() - 1
+                }
+                Err(_) => (failed + 1) % self.channels.len(),
+            },
+            (None, None) => (failed + 1) % self.channels.len(),
+        };
+        tracing::info!(
+            "Switching to metadata-service at {}",
+            self.channels[self.current].0
+        );
+    }
+}
+
+/// A lock we hold and the transaction it was taken for.
+#[derive(Clone)]
+struct Held {
+    key: LockKey,
+    lock_type: LockType,
+    txn: u64,
+}
 
 #[derive(Clone)]
 pub struct RemoteMetadataCoordinator {
-    client: MetadataClient<Channel>,
+    endpoints: Arc<Mutex<Endpoints>>,
+    /// Session id; the metadata-service records it as owner of our locks.
+    /// Replaced when the session expires and a new one is opened.
+    owner: Arc<Mutex<String>>,
+    /// Locks we currently hold, to reclaim after a metadata-service restart.
+    held: Arc<Mutex<Vec<Held>>>,
+    /// Locks held in a session that expired, with the transactions that took
+    /// them. Releasing them fails with [`LockError::Lost`], so the work done
+    /// under them is not written.
+    lost: Arc<Mutex<Vec<(LockKey, u64)>>>,
+    invalidations: broadcast::Sender<Invalidation>,
+    /// Volume the mounted filesystem lives on; empty for a whole device.
+    volume: String,
 }
 
 impl RemoteMetadataCoordinator {
-    pub async fn connect<D: Into<String>>(dst: D) -> Result<Self> {
-        let client = MetadataClient::connect(dst.into())
+    /// Opens a session with the metadata-service reachable at any of
+    /// `endpoints` for the filesystem on `volume` (empty for a whole
+    /// device). With a replicated service, calls follow the leader.
+    /// `https://` endpoints are reached with `tls`. Requests carry the bearer
+    /// token in `token_file`, if given.
+    pub async fn connect<I, D>(
+        endpoints: I,
+        volume: &str,
+        tls: Option<ClientTlsConfig>,
+        token_file: Option<PathBuf>,
+    ) -> Result<Self>
+    where
+        I: IntoIterator<Item = D>,
+        D: Into<String>,
+    {
+        let channels = endpoints
+            .into_iter()
+            .map(|addr| {
+                let addr = addr.into();
+                let channel = lazy_channel(&addr, tls.as_ref())?;
+                Ok((addr, channel))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if channels.is_empty() {
+            anyhow::bail!("No metadata-service endpoints given");
+        }
+
+        let coordinator = Self {
+            endpoints: Arc::new(Mutex::new(Endpoints {
+                channels,
+                current: 0,
+                tls,
+                metadata: CallMetadata {
+                    file: token_file.map(Arc::new),
+                },
+            })),
+            owner: Arc::new(Mutex::new(String::new())),
+            held: Arc::new(Mutex::new(Vec::new())),
+            lost: Arc::new(Mutex::new(Vec::new())),
+            invalidations: broadcast::channel(INVALIDATION_BUFFER).0,
+            volume: volume.to_string(),
+        };
+
+        let heartbeat_interval = match coordinator.register().await {
+            Ok(interval) => interval,
+            Err(e) => {
+                tracing::warn!("Failed to register node: {:#}", e);
+                DEFAULT_HEARTBEAT_INTERVAL
+            }
+        };
+
+        let session = coordinator.open_session().await?;
+        tracing::info!("Opened metadata session {}", session.session_id);
+
+        *coordinator.owner.lock().unwrap() = session.session_id;
+        coordinator.spawn_keep_alive(Duration::from_millis(session.ttl_ms), session.server_epoch);
+        coordinator.spawn_watch();
+        coordinator.spawn_heartbeat(heartbeat_interval);
+        Ok(coordinator)
+    }
+
+    fn owner(&self) -> String {
+        self.owner.lock().unwrap().clone()
+    }
+
+    async fn open_session(&self) -> Result<SessionResponse> {
+        let session = self
+            .call(|mut client| async move {
+                client
+                    .open_session(OpenSessionRequest {
+                        client: client_name(),
+                        ttl_ms: SESSION_TTL.as_millis() as u64,
+                        node: client_name(),
+                    })
+                    .await
+            })
+            .await
+            .context("Failed to open metadata session")?;
+        if !session.success {
+            anyhow::bail!("Failed to open metadata session: {}", session.message);
+        }
+        Ok(session)
+    }
+
+    /// Opens a new session after ours expired, retrying with backoff until
+    /// the metadata-service is reachable again, and returns its epoch. The
+    /// locks of the old session are gone and other nodes may have changed
+    /// anything meanwhile, so the locks are marked lost and subscribers are
+    /// told to drop everything, including changes made under them.
+    async fn reopen_session(&self) -> String {
+        let mut backoff = Backoff::new();
+        loop {
+            match self.open_session().await {
+                Ok(session) => {
+                    tracing::info!("Opened new metadata session {}", session.session_id);
+                    *self.owner.lock().unwrap() = session.session_id;
+                    let lost: Vec<(LockKey, u64)> = std::mem::take(&mut *self.held.lock().unwrap())
+                        .into_iter()
+                        .map(|held| (held.key, held.txn))
+                        .collect();
+                    if !lost.is_empty() {
+                        tracing::error!("{} locks were lost with the expired session", lost.len());
+                    }
+                    let inos = lost.iter().map(|(key, _)| key.0).collect();
+                    self.lost.lock().unwrap().extend(lost);
+                    let _ = self.invalidations.send(Invalidation::Lost(inos));
+                    return session.server_epoch;
+                }
+                Err(e) => {
+                    tracing::warn!("{:#}", e);
+                    backoff.wait().await;
+                }
+            }
+        }
+    }
+
+    /// Registers this node and returns how often it has to send heartbeats.
+    async fn register(&self) -> Result<Duration> {
+        let resp = self
+            .call(|mut client| async move {
+                client
+                    .register(RegisterRequest {
+                        node: client_name(),
+                        address: node_address(),
+                    })
+                    .await
+            })
             .await
-            .context("Failed to connect to metadata-service")?;
-        Ok(Self { client })
+            .context("Register RPC failed")?;
+        if !resp.success {
+            anyhow::bail!("Failed to register node: {}", resp.message);
+        }
+        tracing::info!("Registered node {}", client_name());
+        Ok(Duration::from_millis(resp.heartbeat_interval_ms))
+    }
+
+    /// Keeps the node alive in the metadata-service's membership, registering
+    /// again if it was declared dead. Its sessions and volumes are gone by
+    /// then; the keep-alive task reports the lost session.
+    fn spawn_heartbeat(&self, interval: Duration) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let resp = this
+                    .call(|mut client| async move {
+                        client
+                            .heartbeat(HeartbeatRequest {
+                                node: client_name(),
+                            })
+                            .await
+                    })
+                    .await;
+                match resp {
+                    Ok(resp) if resp.success => {}
+                    Ok(resp) => {
+                        tracing::error!("{}, registering again", resp.message);
+                        if let Err(e) = this.register().await {
+                            tracing::warn!("Failed to register node: {:#}", e);
+                        }
+                    }
+                    Err(e) => tracing::warn!("Heartbeat failed: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Runs `f` against the current replica. When it is unreachable or not
+    /// the leader, moves on to the leader it names or the next replica and
+    /// tries again.
+    async fn call<T, F, Fut>(&self, f: F) -> Result<T, tonic::Status>
+    where
+        F: FnMut(Client) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+    {
+        self.fail_over(f, |status| status.code() == Code::Unavailable)
+            .await
+    }
+
+    /// Like [`Self::call`], for requests that must not take effect twice:
+    /// tries again only if the failed attempt certainly had no effect.
+    async fn call_at_most_once<T, F, Fut>(&self, f: F) -> Result<T, tonic::Status>
+    where
+        F: FnMut(Client) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+    {
+        self.fail_over(f, not_processed).await
+    }
+
+    async fn fail_over<T, F, Fut>(
+        &self,
+        mut f: F,
+        retry: fn(&tonic::Status) -> bool,
+    ) -> Result<T, tonic::Status>
+    where
+        F: FnMut(Client) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let (index, client) = self.endpoints.lock().unwrap().client();
+            let status = match f(client).await {
+                Ok(resp) => return Ok(resp.into_inner()),
+                Err(status) if retry(&status) && attempt < FAILOVER_ATTEMPTS => status,
+                Err(status) => return Err(status),
+            };
+            let leader = status
+                .metadata()
+                .get(LEADER_METADATA_KEY)
+                .and_then(|value| value.to_str().ok());
+            tracing::debug!("Metadata-service unavailable: {}", status.message());
+            self.endpoints.lock().unwrap().fail_over(index, leader);
+            if leader.is_none() {
+                tokio::time::sleep(FAILOVER_BACKOFF).await;
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Renews the session lease in the background. When the server reports
+    /// a new epoch it has restarted or a new leader took over, and the locks
+    /// we hold are reclaimed. An expired session is replaced.
+    fn spawn_keep_alive(&self, ttl: Duration, mut epoch: String) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ttl / 3);
+            loop {
+                interval.tick().await;
+                let resp = this
+                    .call(|mut client| {
+                        let req = SessionRequest {
+                            session_id: this.owner(),
+                        };
+                        async move { client.keep_alive(req).await }
+                    })
+                    .await;
+                let resp = match resp {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        tracing::warn!("Session keep-alive failed: {}", e);
+                        continue;
+                    }
+                };
+                if !resp.success {
+                    tracing::error!("Metadata session {} lost: {}", this.owner(), resp.message);
+                    epoch = this.reopen_session().await;
+                    continue;
+                }
+                if resp.server_epoch != epoch {
+                    tracing::info!("Metadata service restarted, reclaiming locks");
+                    epoch = resp.server_epoch;
+                    this.reclaim_held().await;
+                }
+            }
+        });
+    }
+
+    /// Follows the Watch stream and republishes what other nodes changed.
+    /// Whenever the stream is (re)established, changes may have gone unseen,
+    /// so subscribers are told to drop everything.
+    fn spawn_watch(&self) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = Backoff::new();
+            loop {
+                let stream = this
+                    .call(|mut client| {
+                        let req = WatchRequest {
+                            session_id: this.owner(),
+                        };
+                        async move { client.watch(req).await }
+                    })
+                    .await;
+                match stream {
+                    Ok(mut stream) => {
+                        backoff.reset();
+                        let _ = this.invalidations.send(Invalidation::All);
+                        loop {
+                            match stream.message().await {
+                                Ok(Some(event)) if event.reset => {
+                                    let _ = this.invalidations.send(Invalidation::All);
+                                }
+                                // Inode numbers of other volumes name other inodes.
+                                Ok(Some(event)) if event.volume != this.volume => {}
+                                Ok(Some(event)) => {
+                                    let _ = this.invalidations.send(Invalidation::Inode(event.key));
+                                }
+                                Ok(None) => break,
+                                Err(e) => {
+                                    tracing::warn!("Watch stream broke: {}", e);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => tracing::warn!("Failed to watch for changes: {}", e),
+                }
+                backoff.wait().await;
+            }
+        });
+    }
+
+    async fn reclaim_held(&self) {
+        let held = self.held.lock().unwrap().clone();
+        for Held { key, lock_type, .. } in held {
+            let req = LockRequest {
+                key: key.0,
+                shared: matches!(lock_type, LockType::Read),
+                owner: self.owner(),
+                reclaim: true,
+                volume: self.volume.clone(),
+                ..Default::default()
+            };
+            let resp = self
+                .call(|mut client| {
+                    let req = req.clone();
+                    async move { client.acquire_lock(req).await }
+                })
+                .await;
+            match resp {
+                Ok(resp) if resp.success => {
+                    tracing::debug!("Reclaimed lock on {:?}", key);
+                }
+                Ok(resp) => {
+                    tracing::error!("Failed to reclaim lock on {:?}: {}", key, resp.message);
+                }
+                Err(e) => tracing::error!("Failed to reclaim lock on {:?}: {}", key, e),
+            }
+        }
+    }
+
+    #[tracing::instrument(
+        name = "metadata.acquire_lock",
+        skip(self, key),
+        fields(key = key.0, otel.kind = "client")
+    )]
+    async fn acquire(
+        &self,
+        key: &LockKey,
+        lock_type: LockType,
+        timeout: Duration,
+        txn: u64,
+    ) -> anyhow::Result<()> {
+        let req = LockRequest {
+            key: key.0,
+            shared: matches!(lock_type, LockType::Read),
+            timeout_ms: timeout.as_millis() as u64,
+            owner: self.owner(),
+            txn,
+            volume: self.volume.clone(),
+            ..Default::default()
+        };
+
+        let resp = self
+            .call_at_most_once(|mut client| {
+                let mut req = tonic::Request::new(req.clone());
+                req.set_timeout(timeout + RPC_DEADLINE_SLACK);
+                async move { client.acquire_lock(req).await }
+            })
+            .await
+            .context("Lock RPC failed")?;
+        if resp.success {
+            self.held.lock().unwrap().push(Held {
+                key: key.clone(),
+                lock_type,
+                txn,
+            });
+            return Ok(());
+        }
+
+        let err = match resp.status() {
+            LockStatus::Deadlock => anyhow::Error::new(LockError::Deadlock),
+            LockStatus::Timeout | LockStatus::Grace => anyhow::Error::new(LockError::Timeout),
+            _ => anyhow::anyhow!(resp.message.clone()),
+        };
+        Err(err.context(format!("Failed to acquire lock on {:?}", key)))
     }
 }
 
@@ -29,32 +600,105 @@ impl MetadataCoordinator for RemoteMetadataCoordinator {
         key: LockKey,
         lock_type: LockType,
         timeout: Duration,
+        txn: u64,
     ) -> anyhow::Result<()> {
-        let mut client = self.client.clone();
-        let req = tonic::Request::new(LockRequest {
-            key: key.0,
-            // exclusive: matches!(lock_type, LockType::Write),
-            // timeout_ms: timeout.as_millis() as u64,
-        });
+        self.acquire(&key, lock_type, timeout, txn).await
+    }
 
-        client.acquire_lock(req).await.context("Lock RPC failed")?;
+    /// Sends all requests under `txn`, so the metadata-service can see which
+    /// held locks each queued request depends on.
+    async fn lock_in_txn(
+        &self,
+        txn: u64,
+        keys: &[LockKey],
+        lock_type: LockType,
+        timeout: Duration,
+    ) -> anyhow::Result<()> {
+        for (i, key) in keys.iter().enumerate() {
+            if let Err(e) = self.acquire(key, lock_type, timeout, txn).await {
+                for held in keys[..i].iter().rev() {
+                    let _ = self.unlock(held.clone(), txn).await;
+                }
+                return Err(e);
+            }
+        }
         Ok(())
     }
-    async fn unlock(&self, key: LockKey) -> anyhow::Result<()> {
+
+    #[tracing::instrument(
+        name = "metadata.release_lock",
+        skip(self, key),
+        fields(key = key.0, otel.kind = "client")
+    )]
+    async fn unlock(&self, key: LockKey, txn: u64) -> anyhow::Result<()> {
+        {
+            // Nothing to release: the session that held it is gone.
+            let mut lost = self.lost.lock().unwrap();
+            if let Some(pos) = lost.iter().position(|(k, t)| *k == key && *t == txn) {
+                lost.remove(pos);
+                return Err(anyhow::Error::new(LockError::Lost)
+                    .context(format!("Failed to release lock on {:?}", key)));
+            }
+        }
+        {
+            let mut held = self.held.lock().unwrap();
+            if let Some(pos) = held.iter().position(|h| h.key == key && h.txn == txn) {
+                held.remove(pos);
+            }
+        }
+
+        let req = LockRequest {
+            key: key.0,
+            owner: self.owner(),
+            volume: self.volume.clone(),
+            ..Default::default()
+        };
+
+        let resp = self
+            .call(|mut client| {
+                let req = req.clone();
+                async move { client.release_lock(req).await }
+            })
+            .await
+            .context("Unlock RPC failed")?;
+        if !resp.success {
+            anyhow::bail!("Failed to release lock on {:?}: {}", key, resp.message);
+        }
         Ok(())
     }
-    async fn is_locked(&self, key: &LockKey) -> bool {
-        true
+
+    async fn is_locked(&self, key: &LockKey, txn: u64) -> bool {
+        self.held
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|h| h.key == *key && h.txn == txn)
     }
-    // fn unlock(&self, key: LockKey) -> Result<()> {
-    //     let mut client = self.client.clone();
-    //     let req = tonic::Request::new(UnlockRequest { inode: key.0 });
 
-    //     tokio::runtime::Handle::current().block_on(async move {
-    //         client.unlock(req).await.context("Unlock RPC failed")?;
-    //         Ok(())
-    //     })
-    // }
+    #[tracing::instrument(name = "metadata.allocate_inodes", skip(self), fields(otel.kind = "client"))]
+    async fn allocate_inodes(&self, floor: u64, count: u32) -> anyhow::Result<Range<u64>> {
+        let req = AllocateInodesRequest {
+            session_id: self.owner(),
+            count,
+            floor,
+            volume: self.volume.clone(),
+        };
+        let resp = self
+            .call_at_most_once(|mut client| {
+                let req = req.clone();
+                async move { client.allocate_inodes(req).await }
+            })
+            .await
+            .context("AllocateInodes RPC failed")?;
+        if !resp.success {
+            anyhow::bail!("Failed to reserve inodes: {}", resp.message);
+        }
+        Ok(resp.first..resp.first + resp.count as u64)
+    }
+
+    fn invalidations(&self) -> Option<broadcast::Receiver<Invalidation>> {
+        Some(self.invalidations.subscribe())
+    }
 
     // fn is_locked(&self, key: &LockKey) -> bool {
     //     let mut client = self.client.clone();
@@ -0,0 +1,256 @@
+//! Prometheus metrics of the filesystem, served at `/metrics` by
+//! [`spawn_server`].
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+pub struct Metrics {
+    registry: Registry,
+    fuse_operations: IntCounterVec,
+    fuse_errors: IntCounterVec,
+    fuse_duration: HistogramVec,
+    block_operations: IntCounterVec,
+    block_bytes: IntCounterVec,
+    lock_wait: Histogram,
+    lock_failures: IntCounterVec,
+    /// The hit ratio is `sum(rate(awsomefs_inode_cache_lookups_total{result="hit"}[5m]))`
+    /// over `sum(rate(awsomefs_inode_cache_lookups_total[5m]))`.
+    inode_cache_lookups: IntCounterVec,
+    /// Hit ratio as for the inode cache, from `awsomefs_block_cache_lookups_total`.
+    block_cache_lookups: IntCounterVec,
+    inodes: IntGauge,
+    blocks: IntGauge,
+    blocks_used: IntGauge,
+}
+
+/// The metrics of this process.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("Invalid metric definition"))
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("awsomefs".to_string()), None)?;
+
+        let fuse_operations = IntCounterVec::new(
+            Opts::new("fuse_operations_total", "FUSE requests handled"),
+            &["op"],
+        )?;
+        let fuse_errors = IntCounterVec::new(
+            Opts::new("fuse_errors_total", "FUSE requests answered with an error"),
+            &["op"],
+        )?;
+        let fuse_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "fuse_operation_duration_seconds",
+                "Time from receiving a FUSE request to replying to it",
+            ),
+            &["op"],
+        )?;
+        let block_operations = IntCounterVec::new(
+            Opts::new("block_operations_total", "Block device reads and writes"),
+            &["direction"],
+        )?;
+        let block_bytes = IntCounterVec::new(
+            Opts::new(
+                "block_bytes_total",
+                "Bytes read from and written to the block device",
+            ),
+            &["direction"],
+        )?;
+        let lock_wait = Histogram::with_opts(HistogramOpts::new(
+            "lock_wait_seconds",
+            "Time spent acquiring the coordinator locks of one operation",
+        ))?;
+        let lock_failures = IntCounterVec::new(
+            Opts::new(
+                "lock_failures_total",
+                "Lock acquisitions that failed, by reason",
+            ),
+            &["reason"],
+        )?;
+        let inode_cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "inode_cache_lookups_total",
+                "Inode lookups answered from the cache (hit) or the device (miss)",
+            ),
+            &["result"],
+        )?;
+        let block_cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "block_cache_lookups_total",
+                "Block reads answered from the buffer cache (hit) or the device (miss)",
+            ),
+            &["result"],
+        )?;
+        let inodes = IntGauge::new(
+            "inodes",
+            "Inode numbers reserved according to the superblock",
+        )?;
+        let blocks = IntGauge::new("blocks", "Blocks on the device or volume")?;
+        let blocks_used = IntGauge::new(
+            "blocks_used",
+            "Blocks up to the last inode reserved according to the superblock",
+        )?;
+
+        registry.register(Box::new(fuse_operations.clone()))?;
+        registry.register(Box::new(fuse_errors.clone()))?;
+        registry.register(Box::new(fuse_duration.clone()))?;
+        registry.register(Box::new(block_operations.clone()))?;
+        registry.register(Box::new(block_bytes.clone()))?;
+        registry.register(Box::new(lock_wait.clone()))?;
+        registry.register(Box::new(lock_failures.clone()))?;
+        registry.register(Box::new(inode_cache_lookups.clone()))?;
+        registry.register(Box::new(block_cache_lookups.clone()))?;
+        registry.register(Box::new(inodes.clone()))?;
+        registry.register(Box::new(blocks.clone()))?;
+        registry.register(Box::new(blocks_used.clone()))?;
+
+        Ok(Self {
+            registry,
+            fuse_operations,
+            fuse_errors,
+            fuse_duration,
+            block_operations,
+            block_bytes,
+            lock_wait,
+            lock_failures,
+            inode_cache_lookups,
+            block_cache_lookups,
+            inodes,
+            blocks,
+            blocks_used,
+        })
+    }
+
+    pub fn block_read(&self, bytes: usize) {
+        self.block_operations.with_label_values(&["read"]).inc();
+        self.block_bytes
+            .with_label_values(&["read"])
+            .inc_by(bytes as u64);
+    }
+
+    pub fn block_written(&self, bytes: usize) {
+        self.block_operations.with_label_values(&["write"]).inc();
+        self.block_bytes
+            .with_label_values(&["write"])
+            .inc_by(bytes as u64);
+    }
+
+    /// Records how long taking locks took, and why it failed if it did.
+    pub fn lock_waited(&self, started: Instant, failure: Option<&str>) {
+        self.lock_wait.observe(started.elapsed().as_secs_f64());
+        if let Some(reason) = failure {
+            self.lock_failures.with_label_values(&[reason]).inc();
+        }
+    }
+
+    pub fn inode_cache(&self, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.inode_cache_lookups.with_label_values(&[result]).inc();
+    }
+
+    pub fn block_cache(&self, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.block_cache_lookups.with_label_values(&[result]).inc();
+    }
+
+    /// Updates the usage gauges from the superblock's inode counter.
+    pub fn superblock(&self, inode_count: u64) {
+        self.inodes.set(inode_count as i64);
+        // Block 0 holds the superblock, inode `n` block `n + 1`.
+        self.blocks_used.set(inode_count as i64 + 2);
+    }
+
+    pub fn device_blocks(&self, blocks: u64) {
+        self.blocks.set(blocks as i64);
+    }
+
+    fn encode(&self) -> prometheus::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Times one FUSE request from creation until dropped, i.e. until the
+/// handler has replied.
+pub struct Operation {
+    op: &'static str,
+    started: Instant,
+}
+
+impl Operation {
+    pub fn start(op: &'static str) -> Self {
+        Self {
+            op,
+            started: Instant::now(),
+        }
+    }
+
+    /// Counts the request as failed.
+    pub fn failed(&self) {
+        metrics().fuse_errors.with_label_values(&[self.op]).inc();
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        let metrics = metrics();
+        metrics.fuse_operations.with_label_values(&[self.op]).inc();
+        metrics
+            .fuse_duration
+            .with_label_values(&[self.op])
+            .observe(self.started.elapsed().as_secs_f64());
+    }
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut resp = Response::new(Body::from("Not found\n"));
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(resp);
+    }
+    match metrics().encode() {
+        Ok(body) => Ok(Response::builder()
+            .header(CONTENT_TYPE, TextEncoder::new().format_type())
+            .body(Body::from(body))
+            .unwrap()),
+        Err(e) => {
+            let mut resp = Response::new(Body::from(e.to_string()));
+            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            Ok(resp)
+        }
+    }
+}
+
+/// Serves `/metrics` on `addr` in the background. Fails if the address
+/// cannot be bound.
+pub fn spawn_server(addr: SocketAddr) -> std::io::Result<()> {
+    let builder = Server::try_bind(&addr).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("Failed to serve metrics on {}: {}", addr, e),
+        )
+    })?;
+    let server = builder.serve(make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(handle))
+    }));
+    tracing::info!("Serving metrics on http://{}/metrics", addr);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::error!("Metrics server failed: {}", e);
+        }
+    });
+    Ok(())
+}
@@ -1,8 +1,8 @@
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
 
-const SUPERBLOCK_OFFSET: u64 = 0;
+use crate::block::BlockStore;
+
+const SUPERBLOCK_BLOCK: u64 = 0;
 const SUPERBLOCK_MAGIC: u64 = 0xAABBCCDD11223344;
 const SUPERBLOCK_VERSION: u32 = 1;
 
@@ -13,28 +13,30 @@ pub struct Superblock {
     pub uuid: [u8; 16],   // basic uuid field
     pub block_size: u32,  // Block size in bytes
     pub inode_count: u64, // Total number of inodes
-    // pub block_count: u64,      // Total number of blocks
+    /// Blocks the filesystem spans from the start of the store; 0 on
+    /// filesystems formatted before it was recorded, which span all of it.
+    pub block_count: u64,
     // pub free_block_count: u64, // Free block count
     // pub free_inode_count: u64, // Free inode count
 }
 
 impl Superblock {
     pub fn new(block_size: u32, total_inodes: u64) -> Self {
-        let uuid = uuid::Uuid::new_v4().as_bytes().clone();
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
         Self {
             magic: SUPERBLOCK_MAGIC,
             version: SUPERBLOCK_VERSION,
             uuid,
             block_size,
             inode_count: total_inodes,
+            block_count: 0,
             // free_inode_count: total_inodes,
         }
     }
 
-    pub fn load(file: &mut File, block_size: usize) -> std::io::Result<Self> {
-        file.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
-        let mut buf = vec![0u8; block_size];
-        file.read_exact(&mut buf)?;
+    pub fn load(device: &dyn BlockStore) -> std::io::Result<Self> {
+        let mut buf = vec![0u8; device.block_size()];
+        device.read_block(SUPERBLOCK_BLOCK, &mut buf)?;
         let sb: Superblock = bincode::deserialize(&buf)
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
         if sb.magic != SUPERBLOCK_MAGIC {
@@ -46,13 +48,29 @@ impl Superblock {
         Ok(sb)
     }
 
-    pub fn save(&self, file: &mut File, block_size: usize) -> std::io::Result<()> {
-        file.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
-        let buf = bincode::serialize(self)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-        let mut padded = vec![0u8; block_size];
+    /// Blocks the filesystem spans on `device`.
+    pub fn blocks(&self, device: &dyn BlockStore) -> std::io::Result<u64> {
+        match self.block_count {
+            0 => device.block_count(),
+            blocks => Ok(blocks),
+        }
+    }
+
+    /// Drops cached copies of the superblock, see [`BlockStore::invalidate`].
+    pub fn invalidate(device: &dyn BlockStore) -> std::io::Result<()> {
+        device.invalidate(SUPERBLOCK_BLOCK, 1)
+    }
+
+    /// Drops cached changes to the superblock, see
+    /// [`BlockStore::forget_changes`].
+    pub fn forget_changes(device: &dyn BlockStore) {
+        device.forget_changes(SUPERBLOCK_BLOCK, 1)
+    }
+
+    pub fn save(&self, device: &dyn BlockStore) -> std::io::Result<()> {
+        let buf = bincode::serialize(self).map_err(std::io::Error::other)?;
+        let mut padded = vec![0u8; device.block_size()];
         padded[..buf.len()].copy_from_slice(&buf);
-        file.write_all(&padded)?;
-        Ok(())
+        device.write_block(SUPERBLOCK_BLOCK, &padded)
     }
 }
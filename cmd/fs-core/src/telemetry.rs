@@ -0,0 +1,95 @@
+//! Logging and tracing: events go to stderr, filtered by `RUST_LOG`, and
+//! spans optionally to an OpenTelemetry collector over OTLP. The trace
+//! context travels to the metadata-service in W3C `traceparent` metadata.
+
+use opentelemetry::propagation::Injector;
+use opentelemetry::trace::TraceError;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{trace, Resource};
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::{EnvFilter, Targets};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Flushes the spans not exported yet when dropped.
+pub struct Telemetry {
+    exporting: bool,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if self.exporting {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
+}
+
+/// Installs the global subscriber. With `otlp_endpoint` (e.g.
+/// `http://otel-collector:4317`) spans of this crate, down to the block
+/// reads and writes, are exported there as `service.name` `fs-core` unless
+/// `OTEL_SERVICE_NAME` says otherwise.
+pub fn init(otlp_endpoint: Option<&str>) -> Result<Telemetry, TraceError> {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let fmt = tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env());
+    let otel = match otlp_endpoint {
+        Some(endpoint) => {
+            let resource = Resource::default().merge(&Resource::new([KeyValue::new(
+                "service.name",
+                std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "fs-core".to_string()),
+            )]));
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(resource))
+                .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(tracer)
+                    .with_filter(Targets::new().with_target("fs_core", tracing::Level::DEBUG)),
+            )
+        }
+        None => None,
+    };
+
+    let _ = tracing_subscriber::registry()
+        .with(fmt)
+        .with(otel)
+        .try_init();
+    if let Some(endpoint) = otlp_endpoint {
+        tracing::info!("Exporting traces to {}", endpoint);
+    }
+    Ok(Telemetry {
+        exporting: otlp_endpoint.is_some(),
+    })
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+/// Adds the context of the current span to outgoing request metadata, so
+/// the server's spans join our trace.
+pub fn inject_context(metadata: &mut MetadataMap) {
+    let context = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut MetadataInjector(metadata))
+    });
+}
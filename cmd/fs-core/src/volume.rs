@@ -1,5 +1,149 @@
+use serde::{Deserialize, Serialize};
+
+use crate::block::BlockStore;
+
+const VOLUME_TABLE_MAGIC: u64 = 0x4157_5356_4f4c_5442; // "AWSVOLTB"
+const VOLUME_TABLE_VERSION: u32 = 1;
+/// Bytes reserved at the start of a shared device for the volume table.
+pub const VOLUME_TABLE_SIZE: u64 = 1 << 20;
+/// Volumes start and end on this boundary so their blocks stay aligned.
+const VOLUME_ALIGN: u64 = 4096;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Volume {
     pub id: String,
     pub size: u64,
     pub offset: u64,
 }
+
+/// Partition-like table at the start of a shared device, listing the
+/// independently formatted volumes carved out of it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VolumeTable {
+    magic: u64,
+    version: u32,
+    pub volumes: Vec<Volume>,
+}
+
+impl Default for VolumeTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VolumeTable {
+    pub fn new() -> Self {
+        Self {
+            magic: VOLUME_TABLE_MAGIC,
+            version: VOLUME_TABLE_VERSION,
+            volumes: Vec::new(),
+        }
+    }
+
+    /// Reads the table at the start of `device`. Fails with `InvalidData`
+    /// if the device has none.
+    pub fn load(device: &dyn BlockStore) -> std::io::Result<Self> {
+        let mut buf = vec![0u8; VOLUME_TABLE_SIZE as usize];
+        device.read_block(0, &mut buf)?;
+        let table: VolumeTable = bincode::deserialize(&buf)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if table.magic != VOLUME_TABLE_MAGIC {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "No volume table on device",
+            ));
+        }
+        Ok(table)
+    }
+
+    pub fn save(&self, device: &dyn BlockStore) -> std::io::Result<()> {
+        let buf = bincode::serialize(self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if buf.len() > VOLUME_TABLE_SIZE as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Volume table is full",
+            ));
+        }
+        let mut padded = vec![0u8; VOLUME_TABLE_SIZE as usize];
+        padded[..buf.len()].copy_from_slice(&buf);
+        device.write_block(0, &padded)?;
+        device.flush()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Volume> {
+        self.volumes.iter().find(|v| v.id == id)
+    }
+
+    /// Adds a volume of `size` bytes in the first gap after the table that
+    /// fits it on a device of `device_size` bytes.
+    pub fn add(&mut self, id: &str, size: u64, device_size: u64) -> std::io::Result<Volume> {
+        if self.get(id).is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Volume '{}' already exists", id),
+            ));
+        }
+        let size = size.div_ceil(VOLUME_ALIGN) * VOLUME_ALIGN;
+
+        let mut taken: Vec<(u64, u64)> = self
+            .volumes
+            .iter()
+            .map(|v| (v.offset, v.offset + v.size))
+            .collect();
+        taken.sort();
+
+        let mut offset = VOLUME_TABLE_SIZE;
+        for (start, end) in taken {
+            if offset + size <= start {
+                break;
+            }
+            offset = offset.max(end.div_ceil(VOLUME_ALIGN) * VOLUME_ALIGN);
+        }
+        if offset + size > device_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                format!("No room for {} bytes on the device", size),
+            ));
+        }
+
+        let volume = Volume {
+            id: id.to_string(),
+            size,
+            offset,
+        };
+        self.volumes.push(volume.clone());
+        Ok(volume)
+    }
+
+    /// Changes the size of volume `id` to `size` bytes in place, which
+    /// must not run into the next volume or past `device_size` bytes.
+    pub fn resize(&mut self, id: &str, size: u64, device_size: u64) -> std::io::Result<Volume> {
+        let volume = self.get(id).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, format!("No volume '{}'", id))
+        })?;
+        let offset = volume.offset;
+        let size = size.div_ceil(VOLUME_ALIGN) * VOLUME_ALIGN;
+
+        let limit = self
+            .volumes
+            .iter()
+            .map(|v| v.offset)
+            .filter(|&start| start > offset)
+            .fold(device_size, u64::min);
+        if offset + size > limit {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                format!(
+                    "Volume '{}' can grow to at most {} bytes",
+                    id,
+                    limit - offset
+                ),
+            ));
+        }
+
+        let volume = self.volumes.iter_mut().find(|v| v.id == id).unwrap();
+        volume.size = size;
+        Ok(volume.clone())
+    }
+}
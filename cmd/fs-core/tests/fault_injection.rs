@@ -0,0 +1,186 @@
+//! Runs the filesystem on a [`Faulty`] memory store and checks that failed,
+//! torn and lost writes leave it consistent and mountable.
+
+use std::sync::Arc;
+
+use fs_core::block::{Fault, Faulty, MemoryStore, Rule};
+use fs_core::inodes::InodeStore;
+use fs_core::{FsCore, Superblock, ROOT_INO};
+
+const BLOCK_SIZE: usize = 4096;
+
+/// A formatted store that can be mounted again after faults.
+struct Disk {
+    store: Faulty<MemoryStore>,
+}
+
+impl Disk {
+    fn format() -> Self {
+        let store = Faulty::new(MemoryStore::new(BLOCK_SIZE, 256));
+        Superblock::new(BLOCK_SIZE as u32, 1)
+            .save(&store)
+            .expect("Failed to format");
+        Self { store }
+    }
+
+    async fn mount(&self) -> Arc<FsCore> {
+        let core = FsCore::new(Box::new(self.store.clone()));
+        core.load_from_device().await.expect("Failed to mount");
+        core
+    }
+
+    /// Every block inode `ino` is stored on.
+    fn inode_blocks(ino: u64) -> std::ops::Range<u64> {
+        let block = InodeStore::block(ino);
+        block..block + 1
+    }
+}
+
+#[tokio::test]
+async fn failed_inode_save_keeps_old_inode() {
+    let disk = Disk::format();
+    let core = disk.mount().await;
+    let ino = core.create_file(ROOT_INO, "a", b"old").await.unwrap();
+    let old = core.get_inode(ino).await.unwrap();
+
+    let mut new = old.clone();
+    new.data = b"new".to_vec();
+    disk.store
+        .inject(Rule::write(Disk::inode_blocks(ino), Fault::Error));
+    let result = core.with_inner(|inner| inner.save_inode(ino, &new)).await;
+    assert!(result.is_err());
+
+    assert_eq!(core.get_inode(ino).await.unwrap().data, b"old");
+    assert_eq!(core.read_inode(ino).unwrap().data, b"old");
+}
+
+#[tokio::test]
+async fn short_inode_write_is_reported() {
+    let disk = Disk::format();
+    let core = disk.mount().await;
+    let ino = core.create_file(ROOT_INO, "a", b"old").await.unwrap();
+
+    let mut new = core.get_inode(ino).await.unwrap();
+    new.data = vec![7; 100];
+    disk.store
+        .inject(Rule::write(Disk::inode_blocks(ino), Fault::ShortWrite(10)));
+    let result = core.with_inner(|inner| inner.save_inode(ino, &new)).await;
+    assert!(result.is_err());
+    assert_eq!(core.get_inode(ino).await.unwrap().data, b"old");
+}
+
+#[tokio::test]
+async fn failed_directory_write_keeps_file() {
+    let disk = Disk::format();
+    let core = disk.mount().await;
+    let ino = core.create_file(ROOT_INO, "a", b"data").await.unwrap();
+
+    disk.store
+        .inject(Rule::write(Disk::inode_blocks(ROOT_INO), Fault::Error));
+    assert!(core.unlink(ROOT_INO, "a").await.is_err());
+
+    // Nothing changed, in the cache or on disk.
+    assert_eq!(
+        core.lookup(ROOT_INO, "a").await.unwrap().unwrap().data,
+        b"data"
+    );
+    assert_eq!(core.read_inode(ino).unwrap().data, b"data");
+    let remounted = disk.mount().await;
+    assert!(remounted.lookup(ROOT_INO, "a").await.unwrap().is_some());
+
+    disk.store.clear();
+    core.unlink(ROOT_INO, "a").await.unwrap();
+    assert!(core.lookup(ROOT_INO, "a").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn failed_inode_delete_leaves_no_entry() {
+    let disk = Disk::format();
+    let core = disk.mount().await;
+    let ino = core.create_file(ROOT_INO, "a", b"data").await.unwrap();
+
+    disk.store
+        .inject(Rule::write(Disk::inode_blocks(ino), Fault::Error));
+    assert!(core.unlink(ROOT_INO, "a").await.is_err());
+    assert!(core.lookup(ROOT_INO, "a").await.unwrap().is_none());
+
+    // The inode is left behind, but nothing refers to it.
+    disk.store.clear();
+    let remounted = disk.mount().await;
+    assert!(remounted.lookup(ROOT_INO, "a").await.unwrap().is_none());
+    remounted
+        .create_file(ROOT_INO, "a", b"again")
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn mount_after_power_loss() {
+    let disk = Disk::format();
+    let core = disk.mount().await;
+    core.create_file(ROOT_INO, "flushed", b"kept")
+        .await
+        .unwrap();
+    core.flush().unwrap();
+    core.create_file(ROOT_INO, "unflushed", b"lost")
+        .await
+        .unwrap();
+    drop(core);
+
+    disk.store.power_loss().unwrap();
+
+    let core = disk.mount().await;
+    let kept = core.lookup(ROOT_INO, "flushed").await.unwrap();
+    assert_eq!(kept.unwrap().data, b"kept");
+    assert!(core.lookup(ROOT_INO, "unflushed").await.unwrap().is_none());
+
+    let ino = core.create_file(ROOT_INO, "new", b"new").await.unwrap();
+    assert_eq!(core.get_inode(ino).await.unwrap().data, b"new");
+}
+
+#[tokio::test]
+async fn mount_after_torn_directory_write() {
+    let disk = Disk::format();
+    let core = disk.mount().await;
+    core.create_file(ROOT_INO, "a", b"a").await.unwrap();
+    core.flush().unwrap();
+
+    disk.store.inject(Rule::write(
+        Disk::inode_blocks(ROOT_INO),
+        Fault::TornWrite(8),
+    ));
+    core.create_file(ROOT_INO, "b", b"b").await.unwrap();
+    drop(core);
+    disk.store.clear();
+
+    // Only the unchanged start of the directory reached the disk, so the
+    // new entry is lost and the old ones are still there.
+    let core = disk.mount().await;
+    assert!(core.lookup(ROOT_INO, "a").await.unwrap().is_some());
+    assert!(core.lookup(ROOT_INO, "b").await.unwrap().is_none());
+    core.create_file(ROOT_INO, "c", b"c").await.unwrap();
+}
+
+#[tokio::test]
+async fn mount_survives_read_error() {
+    let disk = Disk::format();
+    let core = disk.mount().await;
+    let names = ["a", "b", "c"];
+    let mut inos = Vec::new();
+    for name in names {
+        inos.push(
+            core.create_file(ROOT_INO, name, name.as_bytes())
+                .await
+                .unwrap(),
+        );
+    }
+    drop(core);
+
+    disk.store
+        .inject(Rule::read(Disk::inode_blocks(inos[0])).times(1));
+    let core = disk.mount().await;
+    for name in names {
+        let inode = core.lookup(ROOT_INO, name).await.unwrap();
+        assert_eq!(inode.unwrap().data, name.as_bytes());
+    }
+}
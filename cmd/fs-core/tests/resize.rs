@@ -0,0 +1,103 @@
+//! Grows and shrinks filesystems on a [`MemoryStore`] and checks what the
+//! size recorded in the superblock allows.
+
+use std::sync::Arc;
+
+use fs_core::block::MemoryStore;
+use fs_core::inodes::InodeStore;
+use fs_core::{FsCore, Superblock, ROOT_INO};
+
+const BLOCK_SIZE: usize = 4096;
+const STORE_BLOCKS: u64 = 256;
+
+/// A store with room for `STORE_BLOCKS` blocks and a filesystem spanning
+/// `blocks` of them, or all of them for 0 as on images from before the size
+/// was recorded.
+fn format(blocks: u64) -> MemoryStore {
+    let store = MemoryStore::new(BLOCK_SIZE, STORE_BLOCKS);
+    let mut superblock = Superblock::new(BLOCK_SIZE as u32, 1);
+    superblock.block_count = blocks;
+    superblock.save(&store).expect("Failed to format");
+    store
+}
+
+async fn mount(store: &MemoryStore) -> Arc<FsCore> {
+    let core = FsCore::new(Box::new(store.clone()));
+    core.load_from_device().await.expect("Failed to mount");
+    core
+}
+
+fn superblock(store: &MemoryStore) -> Superblock {
+    Superblock::load(store).unwrap()
+}
+
+fn blocks(store: &MemoryStore) -> u64 {
+    superblock(store).blocks(store).unwrap()
+}
+
+#[tokio::test]
+async fn grow_up_to_the_store() {
+    let store = format(64);
+    let core = mount(&store).await;
+    assert_eq!(blocks(&store), 64);
+
+    core.resize(128).await.unwrap();
+    assert_eq!(blocks(&store), 128);
+    core.resize(STORE_BLOCKS).await.unwrap();
+    assert_eq!(blocks(&store), STORE_BLOCKS);
+
+    assert!(core.resize(STORE_BLOCKS + 1).await.is_err());
+    assert_eq!(blocks(&store), STORE_BLOCKS);
+    core.create_file(ROOT_INO, "a", b"a").await.unwrap();
+}
+
+#[tokio::test]
+async fn shrink_below_a_reserved_inode_is_refused() {
+    let store = format(0);
+    let core = mount(&store).await;
+    let ino = core.create_file(ROOT_INO, "a", b"data").await.unwrap();
+    let needed = InodeStore::block(superblock(&store).inode_count) + 1;
+    assert!(InodeStore::block(ino) < needed);
+
+    assert!(core.resize(InodeStore::block(ino)).await.is_err());
+    assert!(core.resize(needed - 1).await.is_err());
+    assert_eq!(superblock(&store).block_count, 0);
+
+    core.resize(needed).await.unwrap();
+    assert_eq!(blocks(&store), needed);
+    let remounted = mount(&store).await;
+    let inode = remounted.lookup(ROOT_INO, "a").await.unwrap();
+    assert_eq!(inode.unwrap().data, b"data");
+}
+
+#[tokio::test]
+async fn old_image_spans_the_whole_store() {
+    let store = format(0);
+    assert_eq!(superblock(&store).block_count, 0);
+    assert_eq!(blocks(&store), STORE_BLOCKS);
+
+    let core = mount(&store).await;
+    core.create_file(ROOT_INO, "a", b"a").await.unwrap();
+    core.resize(STORE_BLOCKS / 2).await.unwrap();
+    assert_eq!(superblock(&store).block_count, STORE_BLOCKS / 2);
+}
+
+#[tokio::test]
+async fn full_filesystem_takes_files_once_grown() {
+    // The superblock and the root directory leave room for 6 inodes.
+    let store = format(8);
+    let core = mount(&store).await;
+    for i in 0..5 {
+        core.create_file(ROOT_INO, &i.to_string(), b"")
+            .await
+            .unwrap();
+    }
+    let err = core.create_file(ROOT_INO, "full", b"").await.unwrap_err();
+    assert!(err
+        .chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(|e| e.kind() == std::io::ErrorKind::StorageFull));
+
+    core.resize(16).await.unwrap();
+    core.create_file(ROOT_INO, "full", b"").await.unwrap();
+}
@@ -1,6 +1,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::compile_protos("proto/metadata.proto")?;
+    tonic_build::compile_protos("proto/raft.proto")?;
     // println!("cargo:rerun-if-changed=src/proto/metadata.proto");
     // println!("cargo:include={}", "src/proto"); // ensures proper include paths
     Ok(())
-}
\ No newline at end of file
+}
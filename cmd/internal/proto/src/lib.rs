@@ -1,3 +1,11 @@
+/// Response metadata key under which a metadata-service replica that is not
+/// the leader names the address of the current leader.
+pub const LEADER_METADATA_KEY: &str = "x-raft-leader";
+
 pub mod metadata {
     include!(concat!(env!("OUT_DIR"), "/metadata.rs"));
 }
+
+pub mod raft {
+    include!(concat!(env!("OUT_DIR"), "/raft.rs"));
+}
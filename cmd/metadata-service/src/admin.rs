@@ -0,0 +1,309 @@
+use clap::{Args, Subcommand};
+use proto::metadata::{
+    metadata_client::MetadataClient, ForceReleaseRequest, ListLocksRequest, ListNodesRequest,
+    ListSessionsRequest, LockOwner, NodeState, SessionRequest,
+};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Request, Status};
+
+/// Inspect a running metadata-service and break locks and sessions.
+#[derive(Debug, Args)]
+pub struct AdminArgs {
+    /// Service to talk to; with a replicated group, the leader.
+    #[arg(
+        long,
+        env = "METADATA_ENDPOINT",
+        default_value = "http://127.0.0.1:50051"
+    )]
+    endpoint: String,
+
+    /// File holding the bearer token to authenticate with.
+    #[arg(long, env = "METADATA_TOKEN_FILE")]
+    token_file: Option<PathBuf>,
+
+    /// CA bundle the service certificate must chain to (https:// only).
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+
+    /// Client certificate, for a service requiring one.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key of --tls-cert.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Print JSON instead of tables.
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: AdminCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum AdminCommand {
+    /// List held and awaited locks with their holders and waiters
+    Locks,
+    /// List open sessions
+    Sessions,
+    /// List registered nodes
+    Nodes,
+    /// Release a lock as if its holder had
+    ReleaseLock {
+        key: u64,
+        /// Session whose grant to release; all holders if not given
+        #[arg(long)]
+        owner: Option<String>,
+        /// Volume the inode belongs to; a filesystem spanning a whole device
+        /// if not given
+        #[arg(long, default_value = "")]
+        volume: String,
+    },
+    /// End a session as if its lease ran out, releasing its locks
+    ExpireSession { id: String },
+}
+
+#[derive(Clone)]
+struct BearerToken(Option<String>);
+
+impl Interceptor for BearerToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(token) = &self.0 {
+            let value = format!("Bearer {}", token)
+                .parse()
+                .map_err(|_| Status::unauthenticated("Invalid token"))?;
+            request.metadata_mut().insert("authorization", value);
+        }
+        Ok(request)
+    }
+}
+
+type Client = MetadataClient<InterceptedService<Channel, BearerToken>>;
+
+async fn connect(args: &AdminArgs) -> Result<Client, Box<dyn Error>> {
+    let mut endpoint = Endpoint::from_shared(args.endpoint.clone())?;
+    if args.tls_ca.is_some() || args.tls_cert.is_some() {
+        let mut tls = ClientTlsConfig::new();
+        if let Some(ca) = &args.tls_ca {
+            tls = tls.ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+        }
+        if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+            tls = tls.identity(Identity::from_pem(
+                std::fs::read(cert)?,
+                std::fs::read(key)?,
+            ));
+        }
+        endpoint = endpoint.tls_config(tls)?;
+    }
+    let token = match &args.token_file {
+        Some(path) => Some(std::fs::read_to_string(path)?.trim().to_string()),
+        None => None,
+    };
+    let channel = endpoint.connect().await?;
+    Ok(MetadataClient::with_interceptor(
+        channel,
+        BearerToken(token),
+    ))
+}
+
+fn age(ms: u64) -> String {
+    format!("{:.1?}", Duration::from_millis(ms))
+}
+
+fn mode(shared: bool) -> &'static str {
+    if shared {
+        "shared"
+    } else {
+        "exclusive"
+    }
+}
+
+fn owner_json(owner: &LockOwner) -> Value {
+    json!({
+        "session": owner.owner,
+        "node": owner.node,
+        "mode": mode(owner.shared),
+        "age_ms": owner.age_ms,
+    })
+}
+
+fn print_json(value: Value) -> Result<(), Box<dyn Error>> {
+    println!("{}", serde_json::to_string_pretty(&value)?);
+    Ok(())
+}
+
+/// Runs one admin command against the service named in `args`.
+pub async fn run(args: AdminArgs) -> Result<(), Box<dyn Error>> {
+    let mut client = connect(&args).await?;
+    // Report what the service said rather than the whole gRPC status.
+    execute(&mut client, &args)
+        .await
+        .map_err(|e| match e.downcast::<Status>() {
+            Ok(status) => format!("{:?}: {}", status.code(), status.message()).into(),
+            Err(e) => e,
+        })
+}
+
+async fn execute(client: &mut Client, args: &AdminArgs) -> Result<(), Box<dyn Error>> {
+    match &args.command {
+        AdminCommand::Locks => {
+            let locks = client
+                .list_locks(ListLocksRequest {})
+                .await?
+                .into_inner()
+                .locks;
+            if args.json {
+                return print_json(
+                    locks
+                        .iter()
+                        .map(|l| {
+                            json!({
+                                "volume": l.volume,
+                                "key": l.key,
+                                "holders": l.holders.iter().map(owner_json).collect::<Vec<_>>(),
+                                "waiters": l.waiters.iter().map(owner_json).collect::<Vec<_>>(),
+                            })
+                        })
+                        .collect(),
+                );
+            }
+            println!(
+                "{:<16} {:<12} {:<8} {:<10} {:<38} {:<20} AGE",
+                "VOLUME", "KEY", "STATE", "MODE", "SESSION", "NODE"
+            );
+            for lock in &locks {
+                let entries = lock
+                    .holders
+                    .iter()
+                    .map(|o| ("held", o))
+                    .chain(lock.waiters.iter().map(|o| ("waiting", o)));
+                for (state, owner) in entries {
+                    println!(
+                        "{:<16} {:<12} {:<8} {:<10} {:<38} {:<20} {}",
+                        lock.volume,
+                        lock.key,
+                        state,
+                        mode(owner.shared),
+                        owner.owner,
+                        owner.node,
+                        age(owner.age_ms)
+                    );
+                }
+            }
+        }
+        AdminCommand::Sessions => {
+            let sessions = client
+                .list_sessions(ListSessionsRequest {})
+                .await?
+                .into_inner()
+                .sessions;
+            if args.json {
+                return print_json(
+                    sessions
+                        .iter()
+                        .map(|s| {
+                            json!({
+                                "id": s.id,
+                                "client": s.client,
+                                "node": s.node,
+                                "ttl_ms": s.ttl_ms,
+                                "expires_in_ms": s.expires_in_ms,
+                            })
+                        })
+                        .collect(),
+                );
+            }
+            println!(
+                "{:<38} {:<20} {:<20} {:<8} EXPIRES IN",
+                "SESSION", "CLIENT", "NODE", "TTL"
+            );
+            for s in &sessions {
+                println!(
+                    "{:<38} {:<20} {:<20} {:<8} {}",
+                    s.id,
+                    s.client,
+                    s.node,
+                    age(s.ttl_ms),
+                    age(s.expires_in_ms)
+                );
+            }
+        }
+        AdminCommand::Nodes => {
+            let nodes = client
+                .list_nodes(ListNodesRequest {})
+                .await?
+                .into_inner()
+                .nodes;
+            let state = |state: i32| {
+                NodeState::try_from(state)
+                    .map(|s| s.as_str_name().to_lowercase())
+                    .unwrap_or_else(|_| state.to_string())
+            };
+            if args.json {
+                return print_json(
+                    nodes
+                        .iter()
+                        .map(|n| {
+                            json!({
+                                "id": n.id,
+                                "address": n.address,
+                                "state": state(n.state),
+                                "since_heartbeat_ms": n.since_heartbeat_ms,
+                            })
+                        })
+                        .collect(),
+                );
+            }
+            println!(
+                "{:<20} {:<20} {:<8} LAST HEARTBEAT",
+                "NODE", "ADDRESS", "STATE"
+            );
+            for n in &nodes {
+                println!(
+                    "{:<20} {:<20} {:<8} {} ago",
+                    n.id,
+                    n.address,
+                    state(n.state),
+                    age(n.since_heartbeat_ms)
+                );
+            }
+        }
+        AdminCommand::ReleaseLock { key, owner, volume } => {
+            let released = client
+                .force_release_lock(ForceReleaseRequest {
+                    key: *key,
+                    owner: owner.clone().unwrap_or_default(),
+                    volume: volume.clone(),
+                })
+                .await?
+                .into_inner()
+                .released;
+            if args.json {
+                return print_json(json!({ "volume": volume, "key": key, "released": released }));
+            }
+            for owner in &released {
+                println!("Released lock on {} held by {}", key, owner);
+            }
+        }
+        AdminCommand::ExpireSession { id } => {
+            let resp = client
+                .expire_session(SessionRequest {
+                    session_id: id.clone(),
+                })
+                .await?
+                .into_inner();
+            if args.json {
+                return print_json(json!({ "session": id, "message": resp.message }));
+            }
+            println!("{}", resp.message);
+        }
+    }
+    Ok(())
+}
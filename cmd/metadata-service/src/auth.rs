@@ -0,0 +1,298 @@
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// Token file entry for callers not bound to a node.
+const ANY_NODE: &str = "*";
+
+/// Where bearer tokens are checked against. Without either file every
+/// caller may do anything.
+#[derive(Debug, Default, Clone, Deserialize, clap::Args)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// File of accepted static tokens, one `<token> <node>` pair per line.
+    /// A node of `*` allows the token to act for any node and to manage
+    /// volumes.
+    #[arg(long = "auth-token-file", env = "METADATA_AUTH_TOKEN_FILE")]
+    pub token_file: Option<PathBuf>,
+
+    /// JWKS file to verify JWTs (e.g. Kubernetes service-account tokens)
+    /// with. Tokens carrying a `kubernetes.io.node.name` claim act for that
+    /// node only; tokens without one are refused unless their subject is
+    /// listed in --auth-admin-subjects.
+    #[arg(long = "auth-jwks-file", env = "METADATA_AUTH_JWKS_FILE")]
+    pub jwks_file: Option<PathBuf>,
+
+    /// Subjects of JWTs that may act for any node and manage volumes, e.g.
+    /// `system:serviceaccount:awsomefs:csi-controller`.
+    #[arg(
+        long = "auth-admin-subjects",
+        env = "METADATA_AUTH_ADMIN_SUBJECTS",
+        value_delimiter = ','
+    )]
+    #[serde(default)]
+    pub admin_subjects: Vec<String>,
+
+    /// Audience JWTs must have been issued for; required with a JWKS file.
+    #[arg(long = "auth-audience", env = "METADATA_AUTH_AUDIENCE")]
+    pub audience: Option<String>,
+
+    /// Issuer JWTs must come from, if set.
+    #[arg(long = "auth-issuer", env = "METADATA_AUTH_ISSUER")]
+    pub issuer: Option<String>,
+}
+
+impl AuthConfig {
+    /// Fills the settings missing here from `other`.
+    pub fn or(self, other: AuthConfig) -> AuthConfig {
+        AuthConfig {
+            token_file: self.token_file.or(other.token_file),
+            jwks_file: self.jwks_file.or(other.jwks_file),
+            admin_subjects: if self.admin_subjects.is_empty() {
+                other.admin_subjects
+            } else {
+                self.admin_subjects
+            },
+            audience: self.audience.or(other.audience),
+            issuer: self.issuer.or(other.issuer),
+        }
+    }
+}
+
+/// An authenticated caller, attached to the requests it makes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub subject: String,
+    /// Node the caller acts for; `None` if it may act for any node, e.g. the
+    /// CSI controller.
+    pub node: Option<String>,
+}
+
+/// Reason a caller may not make a request.
+#[derive(Debug)]
+pub struct Denied(pub String);
+
+impl From<Denied> for Status {
+    fn from(denied: Denied) -> Self {
+        Status::permission_denied(denied.0)
+    }
+}
+
+impl Principal {
+    /// The caller of `request`, if the server authenticates callers.
+    pub fn of<T>(request: &Request<T>) -> Option<Principal> {
+        request.extensions().get::<Principal>().cloned()
+    }
+
+    /// Fails unless the caller may act for `node`.
+    pub fn check_node(&self, node: &str) -> Result<(), Denied> {
+        match &self.node {
+            Some(own) if own != node => Err(Denied(format!(
+                "'{}' may not act for node '{}'",
+                self.subject, node
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails if the caller is bound to a node; for requests that affect the
+    /// whole cluster.
+    pub fn check_admin(&self) -> Result<(), Denied> {
+        match &self.node {
+            Some(node) => Err(Denied(format!(
+                "'{}' acts for node '{}' and may not manage volumes",
+                self.subject, node
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(rename = "kubernetes.io", default)]
+    kubernetes: Option<KubernetesClaims>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KubernetesClaims {
+    node: Option<ObjectRef>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ObjectRef {
+    name: String,
+}
+
+#[derive(Debug)]
+struct Jwks {
+    keys: JwkSet,
+    audience: String,
+    issuer: Option<String>,
+    admins: HashSet<String>,
+}
+
+/// Checks the bearer token of every request and attaches the [`Principal`]
+/// it belongs to. Lets every request through if no tokens are configured.
+#[derive(Debug, Clone, Default)]
+pub struct Authenticator {
+    tokens: Arc<HashMap<String, Principal>>,
+    jwks: Option<Arc<Jwks>>,
+    enabled: bool,
+}
+
+fn invalid_data(path: &Path, message: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), message),
+    )
+}
+
+fn load_tokens(path: &Path) -> io::Result<HashMap<String, Principal>> {
+    let mut tokens = HashMap::new();
+    for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(token), Some(node), None) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(invalid_data(
+                path,
+                format!("line {}: expected '<token> <node>'", i + 1),
+            ));
+        };
+        let principal = Principal {
+            subject: format!("token:{}", i + 1),
+            node: (node != ANY_NODE).then(|| node.to_string()),
+        };
+        tokens.insert(token.to_string(), principal);
+    }
+    Ok(tokens)
+}
+
+fn load_jwks(path: &Path) -> io::Result<JwkSet> {
+    let text = std::fs::read_to_string(path)?;
+    serde_json::from_str(&text).map_err(|e| invalid_data(path, e))
+}
+
+impl Authenticator {
+    /// Reads the files named by `config`. If it names none, requests are
+    /// not authenticated.
+    pub fn load(config: &AuthConfig) -> io::Result<Self> {
+        if config.token_file.is_none() && config.jwks_file.is_none() {
+            return Ok(Self::default());
+        }
+        let tokens = match &config.token_file {
+            Some(path) => load_tokens(path)?,
+            None => HashMap::new(),
+        };
+        let jwks = match &config.jwks_file {
+            Some(path) => {
+                let keys = load_jwks(path)?;
+                let audience = config.audience.clone().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Verifying JWTs requires an audience",
+                    )
+                })?;
+                Some(Arc::new(Jwks {
+                    keys,
+                    audience,
+                    issuer: config.issuer.clone(),
+                    admins: config.admin_subjects.iter().cloned().collect(),
+                }))
+            }
+            None => None,
+        };
+        Ok(Self {
+            tokens: Arc::new(tokens),
+            jwks,
+            enabled: true,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn authenticate(&self, token: &str) -> Result<Principal, String> {
+        if let Some(principal) = self.tokens.get(token) {
+            return Ok(principal.clone());
+        }
+        let Some(jwks) = &self.jwks else {
+            return Err("Unknown token".to_string());
+        };
+
+        let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+        // Only asymmetric algorithms: a JWKS is public, so a shared secret
+        // taken from it would let anyone sign tokens.
+        if !matches!(
+            header.alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+                | Algorithm::ES256
+                | Algorithm::ES384
+                | Algorithm::EdDSA
+        ) {
+            return Err(format!("Algorithm {:?} is not accepted", header.alg));
+        }
+        let jwk = match &header.kid {
+            Some(kid) => jwks.keys.find(kid),
+            None => jwks.keys.keys.first(),
+        }
+        .ok_or("No key to verify the token with")?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| e.to_string())?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&jwks.audience]);
+        if let Some(issuer) = &jwks.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map_err(|e| e.to_string())?
+            .claims;
+        // A token bound to no node could act for every node, so only the
+        // subjects configured as admins get one without a node claim.
+        let node = claims.kubernetes.and_then(|k| k.node).map(|n| n.name);
+        if node.is_none() && !jwks.admins.contains(&claims.sub) {
+            return Err(format!("'{}' is bound to no node", claims.sub));
+        }
+        Ok(Principal {
+            subject: claims.sub,
+            node,
+        })
+    }
+}
+
+impl Interceptor for Authenticator {
+    /// Rejects requests without a valid bearer token.
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if !self.enabled {
+            return Ok(request);
+        }
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("Missing bearer token"))?;
+        let principal = self.authenticate(token.trim()).map_err(|e| {
+            tracing::debug!("Rejected token: {}", e);
+            Status::unauthenticated("Invalid bearer token")
+        })?;
+        request.extensions_mut().insert(principal);
+        Ok(request)
+    }
+}
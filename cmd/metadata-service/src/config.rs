@@ -0,0 +1,132 @@
+use serde::Deserialize;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+
+use crate::auth::AuthConfig;
+
+/// Settings read from the `--config` file. Command-line flags take
+/// precedence over them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub listen: Option<SocketAddr>,
+    pub http_listen: Option<SocketAddr>,
+    pub otlp_endpoint: Option<String>,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+}
+
+impl Config {
+    /// Reads a TOML config file, e.g.
+    ///
+    /// ```toml
+    /// listen = "0.0.0.0:50051"
+    /// http_listen = "0.0.0.0:9090"
+    /// otlp_endpoint = "http://otel-collector:4317"
+    ///
+    /// [tls]
+    /// cert = "/etc/metadata-service/tls.crt"
+    /// key = "/etc/metadata-service/tls.key"
+    /// ca = "/etc/metadata-service/ca.crt"
+    ///
+    /// [auth]
+    /// jwks_file = "/etc/metadata-service/jwks.json"
+    /// audience = "awsomefs"
+    /// admin_subjects = ["system:serviceaccount:awsomefs:csi-controller"]
+    /// ```
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = std::fs::read_to_string(&path)?;
+        toml::from_str(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.as_ref().display(), e),
+            )
+        })
+    }
+}
+
+/// PEM files to serve, and talk to the other members, over TLS.
+#[derive(Debug, Default, Clone, Deserialize, clap::Args)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Certificate chain presented to clients and, in a replicated group, to
+    /// the other members. Enables TLS.
+    #[arg(long = "tls-cert", env = "METADATA_TLS_CERT", requires = "key")]
+    pub cert: Option<PathBuf>,
+
+    /// Private key of --tls-cert.
+    #[arg(long = "tls-key", env = "METADATA_TLS_KEY", requires = "cert")]
+    pub key: Option<PathBuf>,
+
+    /// CA bundle client certificates must chain to, which makes clients
+    /// authenticate (mutual TLS). Also used to verify the other members.
+    #[arg(long = "tls-ca", env = "METADATA_TLS_CA", requires = "cert")]
+    pub ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Fills the settings missing here from `other`.
+    pub fn or(self, other: TlsConfig) -> TlsConfig {
+        TlsConfig {
+            cert: self.cert.or(other.cert),
+            key: self.key.or(other.key),
+            ca: self.ca.or(other.ca),
+        }
+    }
+
+    fn identity(&self) -> io::Result<Option<Identity>> {
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Ok(Some(Identity::from_pem(
+                std::fs::read(cert)?,
+                std::fs::read(key)?,
+            ))),
+            (None, None) => Ok(None),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS needs both a certificate and a key",
+            )),
+        }
+    }
+
+    fn ca(&self) -> io::Result<Option<Certificate>> {
+        self.ca
+            .as_ref()
+            .map(|ca| Ok(Certificate::from_pem(std::fs::read(ca)?)))
+            .transpose()
+    }
+
+    /// Server side of the configuration; `None` to serve plaintext.
+    pub fn server(&self) -> io::Result<Option<ServerTlsConfig>> {
+        let Some(identity) = self.identity()? else {
+            if self.ca.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Client certificates can only be required when serving TLS",
+                ));
+            }
+            return Ok(None);
+        };
+        let mut config = ServerTlsConfig::new().identity(identity);
+        if let Some(ca) = self.ca()? {
+            config = config.client_ca_root(ca);
+        }
+        Ok(Some(config))
+    }
+
+    /// Client side, used to reach the other members at their `https://`
+    /// addresses with our certificate as client identity.
+    pub fn client(&self) -> io::Result<Option<ClientTlsConfig>> {
+        let Some(identity) = self.identity()? else {
+            return Ok(None);
+        };
+        let mut config = ClientTlsConfig::new().identity(identity);
+        if let Some(ca) = self.ca()? {
+            config = config.ca_certificate(ca);
+        }
+        Ok(Some(config))
+    }
+}
@@ -0,0 +1,104 @@
+//! Probes and metrics: `/metrics`, `/healthz` and `/readyz` over plain
+//! HTTP, and the standard gRPC health service next to the Metadata service.
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use proto::metadata::metadata_server::MetadataServer;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+use crate::metrics::metrics;
+use crate::MetadataService;
+
+/// How often the gRPC health status is brought up to date.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+
+fn text(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut resp = Response::new(body.into());
+    *resp.status_mut() = status;
+    resp
+}
+
+fn handle(service: &MetadataService, req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET {
+        return text(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed\n");
+    }
+    match req.uri().path() {
+        // The process answers, which is all liveness asks for.
+        "/healthz" => text(StatusCode::OK, "ok\n"),
+        "/readyz" if service.is_ready() => text(StatusCode::OK, "ok\n"),
+        "/readyz" => text(StatusCode::SERVICE_UNAVAILABLE, "No leader\n"),
+        "/metrics" => {
+            service.update_metrics();
+            match metrics().encode() {
+                Ok(body) => Response::builder()
+                    .header(CONTENT_TYPE, prometheus::TEXT_FORMAT)
+                    .body(Body::from(body))
+                    .unwrap(),
+                Err(e) => text(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            }
+        }
+        _ => text(StatusCode::NOT_FOUND, "Not found\n"),
+    }
+}
+
+/// Serves the HTTP endpoints for `service` on `addr` in the background.
+/// Fails if the address cannot be bound.
+pub fn spawn_http_server(addr: SocketAddr, service: MetadataService) -> std::io::Result<()> {
+    let builder = Server::try_bind(&addr).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("Failed to serve HTTP on {}: {}", addr, e),
+        )
+    })?;
+    let server = builder.serve(make_service_fn(move |_| {
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let resp = handle(&service, req);
+                async move { Ok::<_, Infallible>(resp) }
+            }))
+        }
+    }));
+    tracing::info!("Serving metrics and probes on http://{}", addr);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::error!("HTTP server failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Keeps the gRPC health status of the whole server and of the Metadata
+/// service in line with [`MetadataService::is_ready`].
+pub async fn report_health(service: MetadataService, mut reporter: HealthReporter) {
+    let mut interval = tokio::time::interval(HEALTH_INTERVAL);
+    let mut reported = None;
+    loop {
+        interval.tick().await;
+        let ready = service.is_ready();
+        if reported == Some(ready) {
+            continue;
+        }
+        let status = if ready {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        reporter.set_service_status("", status).await;
+        if ready {
+            reporter
+                .set_serving::<MetadataServer<MetadataService>>()
+                .await;
+        } else {
+            reporter
+                .set_not_serving::<MetadataServer<MetadataService>>()
+                .await;
+        }
+        reported = Some(ready);
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::journal::Journal;
+use crate::store::{Record, State};
+
+/// Hands out inode numbers that are unique across all clients of a volume.
+/// Clients get whole ranges so they only ask every so often; numbers a
+/// client never uses are simply skipped.
+#[derive(Debug, Default, Clone)]
+pub struct InodeAllocator {
+    /// Highest number handed out so far, per volume.
+    last: Arc<Mutex<HashMap<String, u64>>>,
+    journal: Option<Arc<dyn Journal>>,
+}
+
+impl InodeAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Continues after the last number recorded in `state`.
+    pub fn with_journal(journal: Arc<dyn Journal>, state: &State) -> Self {
+        Self {
+            last: Arc::new(Mutex::new(state.last_inodes.clone())),
+            journal: Some(journal),
+        }
+    }
+
+    /// Reserves `count` numbers above both `floor` and anything handed out
+    /// before, and returns the first. A reservation that cannot be journaled
+    /// fails: forgetting it would hand the same numbers out again after a
+    /// restart.
+    pub fn allocate(&self, volume: &str, floor: u64, count: u64) -> io::Result<u64> {
+        let mut last = self.last.lock().unwrap();
+        let first = last.get(volume).copied().unwrap_or(0).max(floor) + 1;
+        let end = first + count - 1;
+        if let Some(journal) = &self.journal {
+            journal.append(Record::InodesAllocated {
+                volume: volume.to_string(),
+                last: end,
+            })?;
+        }
+        last.insert(volume.to_string(), end);
+        Ok(first)
+    }
+}
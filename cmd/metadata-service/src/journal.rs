@@ -0,0 +1,28 @@
+use std::fmt::Debug;
+use std::io;
+
+use crate::store::{Record, Store};
+
+/// Where the lock and session managers record their changes.
+///
+/// `append` orders a record after all earlier ones; `sync` returns once
+/// everything appended so far is durable. The local [`Store`] makes a record
+/// durable before `append` returns, a replicated journal once a majority of
+/// the group has stored it.
+#[tonic::async_trait]
+pub trait Journal: Send + Sync + Debug {
+    fn append(&self, record: Record) -> io::Result<()>;
+
+    async fn sync(&self) -> io::Result<()>;
+}
+
+#[tonic::async_trait]
+impl Journal for Store {
+    fn append(&self, record: Record) -> io::Result<()> {
+        Store::append(self, record)
+    }
+
+    async fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
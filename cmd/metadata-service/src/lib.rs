@@ -1,2 +1,17 @@
+pub mod admin;
+pub mod auth;
+pub mod config;
+pub mod health;
+pub mod inode;
+pub mod journal;
+pub mod lock;
+pub mod metrics;
+pub mod node;
+pub mod raft;
 pub mod server;
+pub mod session;
+pub mod store;
+pub mod telemetry;
+pub mod volume;
+pub mod watch;
 pub use server::*;
@@ -0,0 +1,803 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use crate::journal::Journal;
+use crate::store::{LockKey, Record, State};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    Timeout,
+    Deadlock,
+    NotHeld,
+    /// The grant could not be journaled and was not made.
+    Journal(io::Error),
+}
+
+impl std::fmt::Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Timeout => write!(f, "timed out waiting for lock"),
+            LockError::Deadlock => write!(f, "lock request would deadlock"),
+            LockError::NotHeld => write!(f, "lock is not held"),
+            LockError::Journal(e) => write!(f, "failed to persist lock state: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+/// A node in the wait-for graph: either all locks one owner takes as part of
+/// a transaction, or a single standalone request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Party {
+    Txn(String, u64),
+    Request(u64),
+}
+
+impl Party {
+    fn new(owner: &str, txn: u64, id: u64) -> Self {
+        if txn == 0 {
+            Party::Request(id)
+        } else {
+            Party::Txn(owner.to_string(), txn)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Holder {
+    id: u64,
+    owner: String,
+    party: Party,
+    mode: LockMode,
+    token: u64,
+    granted_at: Instant,
+    /// Restored from the store after a restart and not yet reclaimed.
+    reclaimable: bool,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    id: u64,
+    owner: String,
+    party: Party,
+    mode: LockMode,
+    tx: oneshot::Sender<Result<u64, LockError>>,
+    queued_at: Instant,
+}
+
+/// A grant or a queued request, as reported to administrators.
+#[derive(Debug, Clone)]
+pub struct LockEntry {
+    pub owner: String,
+    pub mode: LockMode,
+    /// How long the lock has been held, or the request queued.
+    pub age: Duration,
+}
+
+/// Holders and waiters of one key.
+#[derive(Debug, Clone)]
+pub struct KeyLocks {
+    pub key: LockKey,
+    pub holders: Vec<LockEntry>,
+    pub waiters: Vec<LockEntry>,
+}
+
+#[derive(Debug, Default)]
+struct KeyState {
+    holders: Vec<Holder>,
+    waiters: VecDeque<Waiter>,
+}
+
+impl KeyState {
+    fn can_grant(&self, mode: LockMode) -> bool {
+        match mode {
+            LockMode::Exclusive => self.holders.is_empty(),
+            LockMode::Shared => self.holders.iter().all(|h| h.mode == LockMode::Shared),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.holders.is_empty() && self.waiters.is_empty()
+    }
+
+    /// Parties the waiter at `pos` is blocked on: conflicting holders, plus
+    /// everyone queued ahead of it since grants are strictly FIFO.
+    fn blockers(&self, pos: usize) -> impl Iterator<Item = &Party> {
+        let waiter = &self.waiters[pos];
+        let holders = self
+            .holders
+            .iter()
+            .filter(move |h| waiter.mode == LockMode::Exclusive || h.mode == LockMode::Exclusive)
+            .map(|h| &h.party);
+        let ahead = self.waiters.iter().take(pos).map(|w| &w.party);
+        holders
+            .chain(ahead)
+            .filter(move |party| **party != waiter.party)
+    }
+}
+
+#[derive(Debug, Default)]
+struct LockTable {
+    keys: HashMap<LockKey, KeyState>,
+    /// Last fencing token handed out per key; outlives the key's lock state.
+    tokens: HashMap<LockKey, u64>,
+    next_id: u64,
+    journal: Option<Arc<dyn Journal>>,
+}
+
+impl LockTable {
+    /// Journals `record` before the change is applied; a grant or release
+    /// we could not persist is not made.
+    fn journal(&self, record: Record) -> io::Result<()> {
+        match &self.journal {
+            Some(journal) => journal.append(record),
+            None => Ok(()),
+        }
+    }
+
+    /// Hands out the next fencing token for `key` and records the grant.
+    fn issue(&mut self, key: &LockKey, owner: &str, mode: LockMode) -> io::Result<u64> {
+        let token = self.tokens.get(key).copied().unwrap_or(0) + 1;
+        self.journal(Record::LockGranted {
+            key: key.clone(),
+            owner: owner.to_string(),
+            shared: mode == LockMode::Shared,
+            token,
+        })?;
+        self.tokens.insert(key.clone(), token);
+        Ok(token)
+    }
+
+    /// Removes the holder at `pos`, leaving it in place if the release
+    /// cannot be journaled.
+    fn remove_holder(&mut self, key: &LockKey, pos: usize) -> io::Result<Holder> {
+        let owner = self.keys[key].holders[pos].owner.clone();
+        self.journal(Record::LockReleased {
+            key: key.clone(),
+            owner,
+        })?;
+        Ok(self.keys.get_mut(key).unwrap().holders.remove(pos))
+    }
+
+    /// Hands the lock to waiters at the head of the queue for as long as they
+    /// are compatible with the current holders. Stops at the first waiter that
+    /// has to keep waiting, so later requests never overtake earlier ones.
+    /// A waiter whose grant cannot be journaled is failed instead.
+    fn grant_waiters(&mut self, key: &LockKey) {
+        loop {
+            let Some(state) = self.keys.get_mut(key) else {
+                return;
+            };
+            match state.waiters.front() {
+                Some(front) if state.can_grant(front.mode) => {}
+                _ => break,
+            }
+            let waiter = state.waiters.pop_front().unwrap();
+            // A closed receiver means the request was cancelled; skip it.
+            if waiter.tx.is_closed() {
+                continue;
+            }
+
+            let token = match self.issue(key, &waiter.owner, waiter.mode) {
+                Ok(token) => token,
+                Err(e) => {
+                    let _ = waiter.tx.send(Err(LockError::Journal(e)));
+                    continue;
+                }
+            };
+            if waiter.tx.send(Ok(token)).is_err() {
+                let released = self.journal(Record::LockReleased {
+                    key: key.clone(),
+                    owner: waiter.owner.clone(),
+                });
+                match released {
+                    Ok(()) => continue,
+                    // Keep the grant the journal has; it goes when the
+                    // owner's session does.
+                    Err(e) => tracing::error!(
+                        "Failed to release lock on {} granted to '{}' after it gave up: {}",
+                        key,
+                        waiter.owner,
+                        e
+                    ),
+                }
+            }
+            self.keys
+                .entry(key.clone())
+                .or_default()
+                .holders
+                .push(Holder {
+                    id: waiter.id,
+                    owner: waiter.owner,
+                    party: waiter.party,
+                    mode: waiter.mode,
+                    token,
+                    granted_at: Instant::now(),
+                    reclaimable: false,
+                });
+        }
+        self.drop_if_idle(key);
+    }
+
+    fn drop_if_idle(&mut self, key: &LockKey) {
+        if self.keys.get(key).is_some_and(KeyState::is_idle) {
+            self.keys.remove(key);
+        }
+    }
+
+    /// Drops a queued or granted request by id, then lets the queue advance.
+    fn cancel(&mut self, key: &LockKey, id: u64) {
+        let Some(state) = self.keys.get_mut(key) else {
+            return;
+        };
+        state.waiters.retain(|w| w.id != id);
+        if let Some(pos) = state.holders.iter().position(|h| h.id == id) {
+            if let Err(e) = self.remove_holder(key, pos) {
+                tracing::error!("Failed to release cancelled lock on {}: {}", key, e);
+            }
+        }
+        self.grant_waiters(key);
+    }
+
+    fn waits_for(&self, party: &Party) -> Vec<Party> {
+        let mut out = Vec::new();
+        for state in self.keys.values() {
+            for (pos, waiter) in state.waiters.iter().enumerate() {
+                if waiter.party == *party {
+                    out.extend(state.blockers(pos).cloned());
+                }
+            }
+        }
+        out
+    }
+
+    /// Walks the wait-for graph from `start` and reports whether it leads back
+    /// to `start`, i.e. whether its newest request closed a cycle.
+    fn in_cycle(&self, start: &Party) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = self.waits_for(start);
+        while let Some(party) = stack.pop() {
+            if party == *start {
+                return true;
+            }
+            if seen.insert(party.clone()) {
+                stack.extend(self.waits_for(&party));
+            }
+        }
+        false
+    }
+}
+
+/// Per-key lock table with FIFO wait queues.
+///
+/// Requests that cannot be granted immediately are queued behind the current
+/// holders and woken in arrival order when the key is released. A request
+/// whose queueing would close a cycle in the wait-for graph is rejected with
+/// [`LockError::Deadlock`] instead of being queued.
+///
+/// With a [`Journal`] attached every grant and release is recorded, and each
+/// grant carries a per-key fencing token that keeps increasing across
+/// restarts and leader changes.
+#[derive(Debug, Default, Clone)]
+pub struct LockManager {
+    table: Arc<Mutex<LockTable>>,
+}
+
+/// Removes a queued request when its acquire future is dropped or times out,
+/// including the case where the grant raced with the cancellation.
+struct WaitGuard<'a> {
+    table: &'a Mutex<LockTable>,
+    key: LockKey,
+    id: u64,
+    armed: bool,
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.table.lock().unwrap().cancel(&self.key, self.id);
+        }
+    }
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a manager that records to `journal`, restoring the grants in
+    /// `state`. Restored grants stay in place until their owner reclaims them
+    /// or [`LockManager::drop_unreclaimed`] is called.
+    pub fn with_journal(journal: Arc<dyn Journal>, state: &State) -> Self {
+        let mut table = LockTable {
+            tokens: state.tokens.clone(),
+            journal: Some(journal),
+            ..Default::default()
+        };
+        for (key, grants) in &state.grants {
+            for grant in grants {
+                table.next_id += 1;
+                let id = table.next_id;
+                table
+                    .keys
+                    .entry(key.clone())
+                    .or_default()
+                    .holders
+                    .push(Holder {
+                        id,
+                        owner: grant.owner.clone(),
+                        party: Party::Request(id),
+                        mode: if grant.shared {
+                            LockMode::Shared
+                        } else {
+                            LockMode::Exclusive
+                        },
+                        token: grant.token,
+                        granted_at: Instant::now(),
+                        reclaimable: true,
+                    });
+            }
+        }
+        Self {
+            table: Arc::new(Mutex::new(table)),
+        }
+    }
+
+    /// Waits for the lock and returns the fencing token of the grant.
+    #[tracing::instrument(name = "lock.wait", skip(self, timeout), err(level = "debug"))]
+    pub async fn acquire(
+        &self,
+        key: &LockKey,
+        owner: &str,
+        txn: u64,
+        mode: LockMode,
+        timeout: Duration,
+    ) -> Result<u64, LockError> {
+        let (id, rx) = {
+            let mut table = self.table.lock().unwrap();
+            table.next_id += 1;
+            let id = table.next_id;
+            let party = Party::new(owner, txn, id);
+            let state = table.keys.entry(key.clone()).or_default();
+
+            if state.waiters.is_empty() && state.can_grant(mode) {
+                let token = match table.issue(key, owner, mode) {
+                    Ok(token) => token,
+                    Err(e) => {
+                        table.drop_if_idle(key);
+                        return Err(LockError::Journal(e));
+                    }
+                };
+                table.keys.get_mut(key).unwrap().holders.push(Holder {
+                    id,
+                    owner: owner.to_string(),
+                    party,
+                    mode,
+                    token,
+                    granted_at: Instant::now(),
+                    reclaimable: false,
+                });
+                return Ok(token);
+            }
+
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push_back(Waiter {
+                id,
+                owner: owner.to_string(),
+                party: party.clone(),
+                mode,
+                tx,
+                queued_at: Instant::now(),
+            });
+
+            if table.in_cycle(&party) {
+                tracing::info!(
+                    "Aborting lock request on {} by '{}' (txn {}): deadlock",
+                    key,
+                    owner,
+                    txn
+                );
+                table.cancel(key, id);
+                return Err(LockError::Deadlock);
+            }
+            (id, rx)
+        };
+
+        let mut guard = WaitGuard {
+            table: &self.table,
+            key: key.clone(),
+            id,
+            armed: true,
+        };
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(result)) => {
+                guard.armed = false;
+                result
+            }
+            _ => Err(LockError::Timeout),
+        }
+    }
+
+    /// Confirms a grant `owner` held before a restart and returns its fencing
+    /// token.
+    pub fn reclaim(&self, key: &LockKey, owner: &str) -> Result<u64, LockError> {
+        let mut table = self.table.lock().unwrap();
+        let holder = table
+            .keys
+            .get_mut(key)
+            .and_then(|state| {
+                // Prefer restored grants, but accept an owner that still holds
+                // the lock, e.g. after a reconnect without a server restart.
+                let pos = state
+                    .holders
+                    .iter()
+                    .position(|h| h.owner == owner && h.reclaimable)
+                    .or_else(|| state.holders.iter().position(|h| h.owner == owner))?;
+                state.holders.get_mut(pos)
+            })
+            .ok_or(LockError::NotHeld)?;
+        holder.reclaimable = false;
+        Ok(holder.token)
+    }
+
+    /// Releases restored grants nobody reclaimed, letting queued requests in.
+    /// Grants whose release cannot be journaled stay until their owner's
+    /// session ends.
+    pub fn drop_unreclaimed(&self) -> usize {
+        let mut table = self.table.lock().unwrap();
+        let mut dropped = 0;
+        let keys: Vec<LockKey> = table.keys.keys().cloned().collect();
+        for key in keys {
+            while let Some(pos) = table.keys[&key].holders.iter().position(|h| h.reclaimable) {
+                match table.remove_holder(&key, pos) {
+                    Ok(holder) => {
+                        tracing::info!(
+                            "Dropping unreclaimed lock on {} held by '{}'",
+                            key,
+                            holder.owner
+                        );
+                        dropped += 1;
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to drop unreclaimed lock on {}: {}", key, e);
+                        table.keys.get_mut(&key).unwrap().holders[pos].reclaimable = false;
+                    }
+                }
+            }
+            table.grant_waiters(&key);
+        }
+        dropped
+    }
+
+    /// Releases one grant held by `owner` on `key` and returns its mode, or
+    /// `None` if the owner held no lock on it. Fails, keeping the grant, if
+    /// the release cannot be journaled.
+    pub fn release(&self, key: &LockKey, owner: &str) -> io::Result<Option<LockMode>> {
+        let mut table = self.table.lock().unwrap();
+        let Some(pos) = table
+            .keys
+            .get(key)
+            .and_then(|state| state.holders.iter().position(|h| h.owner == owner))
+        else {
+            return Ok(None);
+        };
+        let holder = table.remove_holder(key, pos)?;
+        tracing::trace!(
+            "Released lock on {} held by '{}' for {:?}",
+            key,
+            owner,
+            holder.granted_at.elapsed()
+        );
+
+        table.grant_waiters(key);
+        Ok(Some(holder.mode))
+    }
+
+    /// Releases every grant held by `owner`, e.g. when its session expired.
+    /// Returns the released keys with the mode each was held in, and the
+    /// error that stopped it early, if any; the grants not released yet
+    /// stay in place.
+    pub fn release_owner(&self, owner: &str) -> (Vec<(LockKey, LockMode)>, io::Result<()>) {
+        let mut table = self.table.lock().unwrap();
+        let mut released = Vec::new();
+        let keys: Vec<LockKey> = table.keys.keys().cloned().collect();
+        for key in keys {
+            while let Some(pos) = table.keys[&key]
+                .holders
+                .iter()
+                .position(|h| h.owner == owner)
+            {
+                match table.remove_holder(&key, pos) {
+                    Ok(holder) => released.push((key.clone(), holder.mode)),
+                    Err(e) => {
+                        table.grant_waiters(&key);
+                        return (released, Err(e));
+                    }
+                }
+            }
+            table.grant_waiters(&key);
+        }
+        (released, Ok(()))
+    }
+
+    /// Releases every grant on `key`, whoever holds it. Returns the owners
+    /// released with the mode each held the key in, and the error that
+    /// stopped it early, if any.
+    pub fn release_key(&self, key: &LockKey) -> (Vec<(String, LockMode)>, io::Result<()>) {
+        let mut table = self.table.lock().unwrap();
+        let mut released = Vec::new();
+        let mut result = Ok(());
+        while table.keys.get(key).is_some_and(|s| !s.holders.is_empty()) {
+            match table.remove_holder(key, 0) {
+                Ok(holder) => released.push((holder.owner, holder.mode)),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        table.grant_waiters(key);
+        (released, result)
+    }
+
+    /// Every key held or waited for, ordered by key.
+    pub fn list(&self) -> Vec<KeyLocks> {
+        let table = self.table.lock().unwrap();
+        let mut locks: Vec<KeyLocks> = table
+            .keys
+            .iter()
+            .filter(|(_, state)| !state.is_idle())
+            .map(|(key, state)| KeyLocks {
+                key: key.clone(),
+                holders: state
+                    .holders
+                    .iter()
+                    .map(|h| LockEntry {
+                        owner: h.owner.clone(),
+                        mode: h.mode,
+                        age: h.granted_at.elapsed(),
+                    })
+                    .collect(),
+                waiters: state
+                    .waiters
+                    .iter()
+                    .map(|w| LockEntry {
+                        owner: w.owner.clone(),
+                        mode: w.mode,
+                        age: w.queued_at.elapsed(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        locks.sort_by(|a, b| a.key.cmp(&b.key));
+        locks
+    }
+
+    pub fn is_locked(&self, key: &LockKey) -> bool {
+        self.table
+            .lock()
+            .unwrap()
+            .keys
+            .get(key)
+            .is_some_and(|s| !s.holders.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(ino: u64) -> LockKey {
+        LockKey::new("", ino)
+    }
+
+    fn txn(owner: &str, txn: u64) -> Party {
+        Party::Txn(owner.to_string(), txn)
+    }
+
+    fn hold(table: &mut LockTable, ino: u64, party: &Party, mode: LockMode) {
+        table.next_id += 1;
+        table
+            .keys
+            .entry(key(ino))
+            .or_default()
+            .holders
+            .push(Holder {
+                id: table.next_id,
+                owner: String::new(),
+                party: party.clone(),
+                mode,
+                token: 0,
+                granted_at: Instant::now(),
+                reclaimable: false,
+            });
+    }
+
+    fn wait(table: &mut LockTable, ino: u64, party: &Party, mode: LockMode) {
+        table.next_id += 1;
+        let (tx, _) = oneshot::channel();
+        table
+            .keys
+            .entry(key(ino))
+            .or_default()
+            .waiters
+            .push_back(Waiter {
+                id: table.next_id,
+                owner: String::new(),
+                party: party.clone(),
+                mode,
+                tx,
+                queued_at: Instant::now(),
+            });
+    }
+
+    #[test]
+    fn two_party_cycle() {
+        let (a, b) = (txn("a", 1), txn("b", 1));
+        let mut table = LockTable::default();
+        hold(&mut table, 1, &a, LockMode::Exclusive);
+        hold(&mut table, 2, &b, LockMode::Exclusive);
+        wait(&mut table, 2, &a, LockMode::Exclusive);
+        assert!(!table.in_cycle(&a));
+
+        wait(&mut table, 1, &b, LockMode::Exclusive);
+        assert!(table.in_cycle(&b));
+        assert!(table.in_cycle(&a));
+    }
+
+    #[test]
+    fn shared_holders_do_not_block_shared_waiters() {
+        let (a, b) = (txn("a", 1), txn("b", 1));
+        let mut table = LockTable::default();
+        hold(&mut table, 1, &a, LockMode::Shared);
+        hold(&mut table, 2, &b, LockMode::Exclusive);
+        wait(&mut table, 2, &a, LockMode::Exclusive);
+        wait(&mut table, 1, &b, LockMode::Shared);
+        assert!(!table.in_cycle(&b));
+    }
+
+    #[test]
+    fn three_party_cycle() {
+        let (a, b, c) = (txn("a", 1), txn("b", 1), txn("c", 1));
+        let mut table = LockTable::default();
+        hold(&mut table, 1, &a, LockMode::Exclusive);
+        hold(&mut table, 2, &b, LockMode::Exclusive);
+        hold(&mut table, 3, &c, LockMode::Exclusive);
+        wait(&mut table, 2, &a, LockMode::Exclusive);
+        wait(&mut table, 3, &b, LockMode::Exclusive);
+        assert!(!table.in_cycle(&b));
+
+        wait(&mut table, 1, &c, LockMode::Exclusive);
+        assert!(table.in_cycle(&c));
+    }
+
+    #[test]
+    fn cycle_through_the_queue() {
+        // c holds nothing a or b wants, but is queued ahead of b on key 1.
+        let (a, b, c) = (txn("a", 1), txn("b", 1), txn("c", 1));
+        let mut table = LockTable::default();
+        hold(&mut table, 1, &a, LockMode::Shared);
+        hold(&mut table, 2, &b, LockMode::Exclusive);
+        wait(&mut table, 1, &c, LockMode::Exclusive);
+        wait(&mut table, 1, &b, LockMode::Shared);
+        assert!(!table.in_cycle(&b));
+
+        wait(&mut table, 2, &a, LockMode::Exclusive);
+        assert!(table.in_cycle(&a));
+    }
+
+    #[test]
+    fn standalone_requests_are_separate_parties() {
+        // One owner's requests outside a transaction cannot wait on each
+        // other, so they form no cycle with a transaction either.
+        let a = txn("a", 1);
+        let mut table = LockTable::default();
+        hold(&mut table, 1, &a, LockMode::Exclusive);
+        hold(&mut table, 2, &Party::Request(100), LockMode::Exclusive);
+        wait(&mut table, 2, &a, LockMode::Exclusive);
+        wait(&mut table, 1, &Party::Request(101), LockMode::Exclusive);
+        assert!(!table.in_cycle(&a));
+        assert!(!table.in_cycle(&Party::Request(101)));
+    }
+
+    #[test]
+    fn cycle_through_a_queued_request() {
+        // b waits behind the standalone request r, which waits for a.
+        let (a, b, r) = (txn("a", 1), txn("b", 1), Party::Request(100));
+        let mut table = LockTable::default();
+        hold(&mut table, 1, &a, LockMode::Exclusive);
+        hold(&mut table, 2, &b, LockMode::Exclusive);
+        wait(&mut table, 1, &r, LockMode::Shared);
+        wait(&mut table, 1, &b, LockMode::Shared);
+        assert!(!table.in_cycle(&b));
+        assert!(!table.in_cycle(&r));
+
+        wait(&mut table, 2, &a, LockMode::Exclusive);
+        assert!(table.in_cycle(&a));
+        assert!(table.in_cycle(&r));
+    }
+
+    /// Waits until `n` requests are queued on `ino`.
+    async fn queued(manager: &LockManager, ino: u64, n: usize) {
+        loop {
+            let waiting = manager
+                .list()
+                .iter()
+                .find(|locks| locks.key == key(ino))
+                .map_or(0, |locks| locks.waiters.len());
+            if waiting == n {
+                return;
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn spawn_acquire(
+        manager: &LockManager,
+        owner: &str,
+        mode: LockMode,
+    ) -> tokio::task::JoinHandle<Result<u64, LockError>> {
+        let manager = manager.clone();
+        let owner = owner.to_string();
+        tokio::spawn(async move {
+            manager
+                .acquire(&key(1), &owner, 0, mode, Duration::from_secs(10))
+                .await
+        })
+    }
+
+    #[tokio::test]
+    async fn waiters_are_granted_in_arrival_order() {
+        let manager = LockManager::new();
+        manager
+            .acquire(&key(1), "holder", 0, LockMode::Exclusive, Duration::ZERO)
+            .await
+            .unwrap();
+        let first = spawn_acquire(&manager, "first", LockMode::Exclusive);
+        queued(&manager, 1, 1).await;
+        let second = spawn_acquire(&manager, "second", LockMode::Exclusive);
+        queued(&manager, 1, 2).await;
+
+        manager.release(&key(1), "holder").unwrap();
+        first.await.unwrap().unwrap();
+        queued(&manager, 1, 1).await;
+        assert!(!second.is_finished());
+
+        manager.release(&key(1), "first").unwrap();
+        second.await.unwrap().unwrap();
+        assert!(manager.list()[0].waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn shared_request_does_not_overtake_queued_exclusive() {
+        let manager = LockManager::new();
+        manager
+            .acquire(&key(1), "reader", 0, LockMode::Shared, Duration::ZERO)
+            .await
+            .unwrap();
+        let writer = spawn_acquire(&manager, "writer", LockMode::Exclusive);
+        queued(&manager, 1, 1).await;
+        // Compatible with the current holder, but queued behind the writer.
+        let late = spawn_acquire(&manager, "late", LockMode::Shared);
+        queued(&manager, 1, 2).await;
+
+        manager.release(&key(1), "reader").unwrap();
+        writer.await.unwrap().unwrap();
+        queued(&manager, 1, 1).await;
+        assert!(!late.is_finished());
+
+        manager.release(&key(1), "writer").unwrap();
+        late.await.unwrap().unwrap();
+    }
+}
@@ -1,20 +1,165 @@
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 use tonic::transport::Server;
 
+const DEFAULT_LISTEN: &str = "127.0.0.1:50051";
+
+use metadata_service::admin::AdminArgs;
+use metadata_service::auth::{AuthConfig, Authenticator};
+use metadata_service::config::{Config, TlsConfig};
+use metadata_service::raft::{Peer, RaftNode};
+use metadata_service::MetadataService;
+
+#[derive(Parser)]
+#[command(name = "metadata-service")]
+#[command(about = "Lock and session coordination service for awsomefs", long_about = None)]
+struct Args {
+    /// TOML file with defaults for --listen, --http-listen, --otlp-endpoint
+    /// and the TLS and auth settings.
+    #[arg(long, env = "METADATA_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Directory to persist locks and sessions in. Without it all state is
+    /// lost when the service restarts.
+    #[arg(long, env = "METADATA_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Seconds after a restart during which clients can reclaim their locks
+    /// before new grants are issued. Replicated, locks not reclaimed this
+    /// long after a new leader took over are dropped.
+    #[arg(long, default_value_t = 30)]
+    grace_period_secs: u64,
+
+    /// Address to serve on [default: 127.0.0.1:50051]
+    #[arg(long, env = "METADATA_LISTEN")]
+    listen: Option<SocketAddr>,
+
+    /// Address to serve /metrics, /healthz and /readyz on over HTTP.
+    #[arg(long, env = "METADATA_HTTP_LISTEN")]
+    http_listen: Option<SocketAddr>,
+
+    /// OTLP/gRPC collector to export traces to, e.g. http://localhost:4317.
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Id of this instance among --peers. Enables replication, which
+    /// requires --tls-ca: the members only know each other by their client
+    /// certificates.
+    #[arg(long, env = "METADATA_NODE_ID", requires = "peers")]
+    node_id: Option<String>,
+
+    /// All members of the replicated group, this one included, as
+    /// comma-separated id=url pairs (e.g. a=http://10.0.0.1:50051,...).
+    #[arg(
+        long,
+        env = "METADATA_PEERS",
+        value_delimiter = ',',
+        requires = "node_id"
+    )]
+    peers: Vec<Peer>,
+
+    #[command(flatten)]
+    tls: TlsConfig,
+
+    #[command(flatten)]
+    auth: AuthConfig,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Inspect or repair the locks and sessions of a running service
+    Admin(AdminArgs),
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    if let Some(Command::Admin(admin)) = args.command {
+        return metadata_service::admin::run(admin).await;
+    }
+
     tracing_log::LogTracer::init().expect("Failed to set up LogTracer");
 
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .try_init();
-    
-    let addr = "127.0.0.1:50051".parse()?;
-    tracing::info!("Metadata service listening on {}", addr);
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let otlp_endpoint = args.otlp_endpoint.clone().or(config.otlp_endpoint);
+    let _telemetry = metadata_service::telemetry::init(otlp_endpoint.as_deref())?;
+    let tls = args.tls.clone().or(config.tls);
+    let auth = Authenticator::load(&args.auth.clone().or(config.auth))?;
+    if !auth.is_enabled() {
+        tracing::warn!("No auth tokens configured, any client may take any lock");
+    }
+
+    let mut raft = None;
+    let service = match (&args.node_id, &args.data_dir) {
+        (Some(_), Some(_)) if tls.ca.is_none() => {
+            // The replication RPCs take no bearer token, so without client
+            // certificates anyone reaching the port could rewrite the log.
+            return Err("--node-id requires --tls-ca".into());
+        }
+        (Some(id), Some(dir)) => {
+            let node = RaftNode::open(id, args.peers.clone(), dir, tls.client()?)?;
+            node.start();
+            raft = Some(node.service());
+            tracing::info!("Replicating as '{}' among {} members", id, args.peers.len());
+            MetadataService::replicated(node, Duration::from_secs(args.grace_period_secs))
+        }
+        (Some(_), None) => return Err("--node-id requires --data-dir".into()),
+        (None, Some(dir)) => {
+            MetadataService::open(dir, Duration::from_secs(args.grace_period_secs))?
+        }
+        (None, None) => {
+            tracing::warn!("No --data-dir given, lock state will not survive restarts");
+            MetadataService::new()
+        }
+    };
+    service.spawn_background_tasks();
+
+    if let Some(addr) = args.http_listen.or(config.http_listen) {
+        metadata_service::health::spawn_http_server(addr, service.clone())?;
+    }
+    let (reporter, health) = tonic_health::server::health_reporter();
+    tokio::spawn(metadata_service::health::report_health(
+        service.clone(),
+        reporter,
+    ));
+
+    let addr = match args.listen.or(config.listen) {
+        Some(addr) => addr,
+        None => DEFAULT_LISTEN.parse()?,
+    };
+    let mut server = Server::builder().trace_fn(metadata_service::telemetry::request_span);
+    match tls.server()? {
+        Some(tls_config) => {
+            server = server.tls_config(tls_config)?;
+            tracing::info!(
+                "Metadata service listening on {} (TLS{})",
+                addr,
+                if tls.ca.is_some() {
+                    ", client certificates required"
+                } else {
+                    ""
+                }
+            );
+        }
+        None => tracing::info!("Metadata service listening on {}", addr),
+    }
 
-    Server::builder()
-        .add_service(metadata_service::server::build_metadata_server())
+    server
+        .add_service(health)
+        .add_service(metadata_service::server::build_metadata_server(
+            service, auth,
+        ))
+        .add_optional_service(raft)
         .serve(addr)
         .await?;
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,165 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::lock::KeyLocks;
+use crate::raft::{RaftStatus, Role};
+
+/// Prometheus metrics of the service. Counters and histograms are updated
+/// as requests are served; the gauges are refreshed from the lock and
+/// session tables right before each scrape.
+pub struct Metrics {
+    registry: Registry,
+    locks_held: IntGauge,
+    lock_waiters: IntGauge,
+    queue_depth: IntGaugeVec,
+    sessions: IntGauge,
+    grant_latency: HistogramVec,
+    leases_expired: IntCounter,
+    serving: IntGauge,
+    raft_role: IntGaugeVec,
+    raft_term: IntGauge,
+    raft_commit_index: IntGauge,
+}
+
+/// The metrics of this process.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("Invalid metric definition"))
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("metadata".to_string()), None)?;
+
+        let locks_held = IntGauge::new("locks_held", "Lock grants currently held")?;
+        let lock_waiters = IntGauge::new("lock_waiters", "Lock requests waiting to be granted")?;
+        let queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "lock_queue_depth",
+                "Lock requests waiting for a key, for keys with any",
+            ),
+            &["key"],
+        )?;
+        let sessions = IntGauge::new("sessions", "Open client sessions")?;
+        let grant_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "lock_acquire_duration_seconds",
+                "Time from an AcquireLock request to its answer, by outcome",
+            )
+            .buckets(vec![
+                0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+            ]),
+            &["result"],
+        )?;
+        let leases_expired = IntCounter::new(
+            "leases_expired_total",
+            "Sessions ended because their client stopped renewing them",
+        )?;
+        let serving = IntGauge::new(
+            "serving",
+            "1 if this instance serves requests, 0 if it redirects them to the leader",
+        )?;
+        let raft_role = IntGaugeVec::new(
+            Opts::new("raft_role", "1 for the Raft role this member has"),
+            &["role"],
+        )?;
+        let raft_term = IntGauge::new("raft_term", "Current Raft term")?;
+        let raft_commit_index = IntGauge::new(
+            "raft_commit_index",
+            "Index of the last Raft log entry known to be committed",
+        )?;
+
+        registry.register(Box::new(locks_held.clone()))?;
+        registry.register(Box::new(lock_waiters.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(sessions.clone()))?;
+        registry.register(Box::new(grant_latency.clone()))?;
+        registry.register(Box::new(leases_expired.clone()))?;
+        registry.register(Box::new(serving.clone()))?;
+
+        Ok(Self {
+            registry,
+            locks_held,
+            lock_waiters,
+            queue_depth,
+            sessions,
+            grant_latency,
+            leases_expired,
+            serving,
+            raft_role,
+            raft_term,
+            raft_commit_index,
+        })
+    }
+
+    /// Registers the Raft metrics; only replicated services have them.
+    pub fn enable_raft(&self) -> prometheus::Result<()> {
+        self.registry.register(Box::new(self.raft_role.clone()))?;
+        self.registry.register(Box::new(self.raft_term.clone()))?;
+        self.registry
+            .register(Box::new(self.raft_commit_index.clone()))
+    }
+
+    /// Records how an AcquireLock request ended and how long it took.
+    pub fn lock_acquired(&self, result: &str, elapsed: Duration) {
+        self.grant_latency
+            .with_label_values(&[result])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn leases_expired(&self, count: usize) {
+        self.leases_expired.inc_by(count as u64);
+    }
+
+    /// Sets the gauges of a serving instance from its lock and session
+    /// tables.
+    pub fn observe_tables(&self, locks: &[KeyLocks], sessions: usize) {
+        self.serving.set(1);
+        self.locks_held
+            .set(locks.iter().map(|l| l.holders.len()).sum::<usize>() as i64);
+        self.lock_waiters
+            .set(locks.iter().map(|l| l.waiters.len()).sum::<usize>() as i64);
+        // Keys come and go; only report those with waiters right now.
+        self.queue_depth.reset();
+        for lock in locks.iter().filter(|l| !l.waiters.is_empty()) {
+            self.queue_depth
+                .with_label_values(&[&lock.key.to_string()])
+                .set(lock.waiters.len() as i64);
+        }
+        self.sessions.set(sessions as i64);
+    }
+
+    /// Clears the gauges of an instance that does not serve.
+    pub fn observe_idle(&self) {
+        self.serving.set(0);
+        self.locks_held.set(0);
+        self.lock_waiters.set(0);
+        self.queue_depth.reset();
+        self.sessions.set(0);
+    }
+
+    pub fn observe_raft(&self, status: &RaftStatus) {
+        for (role, name) in [
+            (Role::Follower, "follower"),
+            (Role::Candidate, "candidate"),
+            (Role::Leader, "leader"),
+        ] {
+            self.raft_role
+                .with_label_values(&[name])
+                .set((status.role == role) as i64);
+        }
+        self.raft_term.set(status.term as i64);
+        self.raft_commit_index.set(status.commit_index as i64);
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn encode(&self) -> prometheus::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+}
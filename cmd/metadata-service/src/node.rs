@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::journal::Journal;
+use crate::store::{Record, State};
+
+/// A node is suspect after missing this many heartbeats in a row...
+const SUSPECT_AFTER_MISSED: u32 = 2;
+/// ...and declared dead after missing this many.
+const DEAD_AFTER_MISSED: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    /// Registered, no heartbeat received yet.
+    Joining,
+    Active,
+    /// Missed heartbeats; may just be slow or partitioned.
+    Suspect,
+    /// Missed so many heartbeats that its leases were revoked and its
+    /// volumes released. It has to register again.
+    Dead,
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeStatus {
+    pub address: String,
+    pub state: NodeState,
+    pub since_heartbeat: Duration,
+}
+
+#[derive(Debug)]
+struct Node {
+    address: String,
+    state: NodeState,
+    last_heartbeat: Instant,
+}
+
+/// fs-core nodes and their health, judged by how many heartbeats they
+/// missed. Registrations are journaled; heartbeats are not, so after a
+/// restart every node gets a full grace of missed heartbeats again.
+#[derive(Debug, Clone)]
+pub struct NodeManager {
+    nodes: Arc<Mutex<HashMap<String, Node>>>,
+    heartbeat_interval: Duration,
+    journal: Option<Arc<dyn Journal>>,
+}
+
+impl NodeManager {
+    pub fn new(heartbeat_interval: Duration) -> Self {
+        Self {
+            nodes: Arc::default(),
+            heartbeat_interval,
+            journal: None,
+        }
+    }
+
+    /// Restores the nodes registered in `state`, as joining.
+    pub fn with_journal(
+        heartbeat_interval: Duration,
+        journal: Arc<dyn Journal>,
+        state: &State,
+    ) -> Self {
+        let now = Instant::now();
+        let nodes = state
+            .nodes
+            .iter()
+            .map(|(id, address)| {
+                (
+                    id.clone(),
+                    Node {
+                        address: address.clone(),
+                        state: NodeState::Joining,
+                        last_heartbeat: now,
+                    },
+                )
+            })
+            .collect();
+        Self {
+            nodes: Arc::new(Mutex::new(nodes)),
+            heartbeat_interval,
+            journal: Some(journal),
+        }
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.heartbeat_interval
+    }
+
+    fn journal(&self, record: Record) -> io::Result<()> {
+        match &self.journal {
+            Some(journal) => journal.append(record),
+            None => Ok(()),
+        }
+    }
+
+    /// Registers `id`, or re-registers it after it was declared dead or
+    /// restarted. Fails, changing nothing, if a new address cannot be
+    /// journaled.
+    pub fn register(&self, id: &str, address: &str) -> io::Result<NodeState> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let known = nodes.get(id).map(|node| node.address.as_str());
+        if known != Some(address) {
+            self.journal(Record::NodeRegistered {
+                id: id.to_string(),
+                address: address.to_string(),
+            })?;
+        }
+        nodes.insert(
+            id.to_string(),
+            Node {
+                address: address.to_string(),
+                state: NodeState::Joining,
+                last_heartbeat: Instant::now(),
+            },
+        );
+        tracing::info!("Node {} registered ({})", id, address);
+        Ok(NodeState::Joining)
+    }
+
+    /// Records a heartbeat of `id`. Returns `None` if the node is unknown or
+    /// was declared dead, and has to register again.
+    pub fn heartbeat(&self, id: &str) -> Option<NodeState> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(id)?;
+        if node.state == NodeState::Dead {
+            return None;
+        }
+        if node.state != NodeState::Active {
+            tracing::info!("Node {} is active", id);
+        }
+        node.state = NodeState::Active;
+        node.last_heartbeat = Instant::now();
+        Some(node.state)
+    }
+
+    /// All nodes, ordered by id.
+    pub fn list(&self) -> Vec<(String, NodeStatus)> {
+        let now = Instant::now();
+        let mut nodes: Vec<_> = self
+            .nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, node)| {
+                (
+                    id.clone(),
+                    NodeStatus {
+                        address: node.address.clone(),
+                        state: node.state,
+                        since_heartbeat: now - node.last_heartbeat,
+                    },
+                )
+            })
+            .collect();
+        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        nodes
+    }
+
+    /// Moves nodes to suspect or dead by the heartbeats they missed, and
+    /// returns the ids of those that just died.
+    pub fn take_dead(&self) -> Vec<String> {
+        let now = Instant::now();
+        let suspect_after = self.heartbeat_interval * SUSPECT_AFTER_MISSED;
+        let dead_after = self.heartbeat_interval * DEAD_AFTER_MISSED;
+
+        let mut dead = Vec::new();
+        for (id, node) in self.nodes.lock().unwrap().iter_mut() {
+            let silent = now - node.last_heartbeat;
+            match node.state {
+                NodeState::Dead => {}
+                _ if silent >= dead_after => {
+                    tracing::warn!(
+                        "Node {} declared dead after {:?} without heartbeat",
+                        id,
+                        silent
+                    );
+                    node.state = NodeState::Dead;
+                    dead.push(id.clone());
+                }
+                NodeState::Joining | NodeState::Active if silent >= suspect_after => {
+                    tracing::warn!(
+                        "Node {} is suspect after {:?} without heartbeat",
+                        id,
+                        silent
+                    );
+                    node.state = NodeState::Suspect;
+                }
+                _ => {}
+            }
+        }
+        dead
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Pretends `id` last sent a heartbeat `missed` intervals ago.
+    fn silence(manager: &NodeManager, id: &str, missed: u32) {
+        let mut nodes = manager.nodes.lock().unwrap();
+        nodes.get_mut(id).unwrap().last_heartbeat = Instant::now() - INTERVAL * missed;
+    }
+
+    fn state(manager: &NodeManager, id: &str) -> NodeState {
+        let nodes = manager.list();
+        nodes.iter().find(|(node, _)| node == id).unwrap().1.state
+    }
+
+    #[test]
+    fn join_activate_suspect_die() {
+        let manager = NodeManager::new(INTERVAL);
+        assert_eq!(manager.register("n1", "a:1").unwrap(), NodeState::Joining);
+        assert!(manager.take_dead().is_empty());
+        assert_eq!(state(&manager, "n1"), NodeState::Joining);
+
+        assert_eq!(manager.heartbeat("n1"), Some(NodeState::Active));
+        silence(&manager, "n1", SUSPECT_AFTER_MISSED);
+        assert!(manager.take_dead().is_empty());
+        assert_eq!(state(&manager, "n1"), NodeState::Suspect);
+
+        silence(&manager, "n1", DEAD_AFTER_MISSED);
+        assert_eq!(manager.take_dead(), ["n1"]);
+        assert_eq!(state(&manager, "n1"), NodeState::Dead);
+        // Reported once only.
+        assert!(manager.take_dead().is_empty());
+    }
+
+    #[test]
+    fn suspect_node_recovers_with_a_heartbeat() {
+        let manager = NodeManager::new(INTERVAL);
+        manager.register("n1", "a:1").unwrap();
+        silence(&manager, "n1", SUSPECT_AFTER_MISSED);
+        manager.take_dead();
+        assert_eq!(state(&manager, "n1"), NodeState::Suspect);
+
+        assert_eq!(manager.heartbeat("n1"), Some(NodeState::Active));
+        assert!(manager.take_dead().is_empty());
+        assert_eq!(state(&manager, "n1"), NodeState::Active);
+    }
+
+    #[test]
+    fn joining_node_dies_without_heartbeats() {
+        let manager = NodeManager::new(INTERVAL);
+        manager.register("n1", "a:1").unwrap();
+        silence(&manager, "n1", DEAD_AFTER_MISSED);
+        assert_eq!(manager.take_dead(), ["n1"]);
+    }
+
+    #[test]
+    fn dead_or_unknown_node_must_register() {
+        let manager = NodeManager::new(INTERVAL);
+        assert_eq!(manager.heartbeat("unknown"), None);
+
+        manager.register("n1", "a:1").unwrap();
+        silence(&manager, "n1", DEAD_AFTER_MISSED);
+        manager.take_dead();
+        assert_eq!(manager.heartbeat("n1"), None);
+        assert_eq!(state(&manager, "n1"), NodeState::Dead);
+
+        assert_eq!(manager.register("n1", "a:2").unwrap(), NodeState::Joining);
+        assert_eq!(manager.heartbeat("n1"), Some(NodeState::Active));
+        assert_eq!(manager.list()[0].1.address, "a:2");
+    }
+}
@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::store::{Record, State};
+
+const HARD_STATE_FILE: &str = "raft-state.bin";
+const LOG_FILE: &str = "raft-log.bin";
+const SNAPSHOT_FILE: &str = "raft-snapshot.bin";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub index: u64,
+    pub term: u64,
+    /// `None` for the no-op a new leader appends to commit its term.
+    pub record: Option<Record>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    last_index: u64,
+    last_term: u64,
+    state: State,
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Writes `bytes` to `name` in `dir` so that a crash leaves either the old or
+/// the new contents, and returns once the new contents are durable.
+fn write_atomic(dir: &Path, name: &str, bytes: &[u8]) -> io::Result<()> {
+    let tmp = dir.join(format!("{}.tmp", name));
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(name))?;
+    File::open(dir)?.sync_all()
+}
+
+fn frame(entry: &LogEntry, out: &mut Vec<u8>) -> io::Result<()> {
+    let bytes = bincode::serialize(entry).map_err(invalid_data)?;
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(&bytes);
+    Ok(())
+}
+
+/// Durable part of a Raft member: current term and vote, the log, and the
+/// snapshot that replaces the log's compacted prefix.
+///
+/// Entries are appended to the log file length-prefixed and synced before a
+/// call returns. Truncation and compaction rewrite the file, which is rare
+/// enough not to matter.
+#[derive(Debug)]
+pub struct RaftLog {
+    dir: PathBuf,
+    file: File,
+    term: u64,
+    voted_for: Option<String>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    /// Entries after `snapshot_index`, in order and without gaps.
+    entries: Vec<LogEntry>,
+}
+
+impl RaftLog {
+    /// Opens (or creates) the log in `dir`. Returns it with the state of its
+    /// snapshot; entries after the snapshot are not applied yet.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<(Self, State)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let hard: HardState = match fs::read(dir.join(HARD_STATE_FILE)) {
+            Ok(buf) => bincode::deserialize(&buf).map_err(invalid_data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e),
+        };
+        let snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(buf) => bincode::deserialize(&buf).map_err(invalid_data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot {
+                last_index: 0,
+                last_term: 0,
+                state: State::default(),
+            },
+            Err(e) => return Err(e),
+        };
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG_FILE))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut entries: Vec<LogEntry> = Vec::new();
+        let mut offset = 0;
+        while offset + 4 <= buf.len() {
+            let len = u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap()) as usize;
+            let Some(bytes) = buf.get(offset + 4..offset + 4 + len) else {
+                break;
+            };
+            let Ok(entry) = bincode::deserialize::<LogEntry>(bytes) else {
+                break;
+            };
+            // Left over if we crashed between writing a snapshot and
+            // rewriting the log.
+            if entry.index > snapshot.last_index {
+                entries.push(entry);
+            }
+            offset += 4 + len;
+        }
+        if offset < buf.len() {
+            tracing::warn!(
+                "Discarding {} trailing bytes of {}",
+                buf.len() - offset,
+                LOG_FILE
+            );
+            file.set_len(offset as u64)?;
+        }
+
+        tracing::info!(
+            "Opened raft log in {} (term {}, snapshot at {}, {} entries)",
+            dir.display(),
+            hard.term,
+            snapshot.last_index,
+            entries.len()
+        );
+
+        let log = Self {
+            dir,
+            file,
+            term: hard.term,
+            voted_for: hard.voted_for,
+            snapshot_index: snapshot.last_index,
+            snapshot_term: snapshot.last_term,
+            entries,
+        };
+        Ok((log, snapshot.state))
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn voted_for(&self) -> Option<&str> {
+        self.voted_for.as_deref()
+    }
+
+    pub fn set_hard_state(&mut self, term: u64, voted_for: Option<String>) -> io::Result<()> {
+        let hard = HardState { term, voted_for };
+        let bytes = bincode::serialize(&hard).map_err(invalid_data)?;
+        write_atomic(&self.dir, HARD_STATE_FILE, &bytes)?;
+        self.term = hard.term;
+        self.voted_for = hard.voted_for;
+        Ok(())
+    }
+
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    pub fn snapshot_term(&self) -> u64 {
+        self.snapshot_term
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    pub fn get(&self, index: u64) -> Option<&LogEntry> {
+        let pos = index.checked_sub(self.snapshot_index + 1)?;
+        self.entries.get(pos as usize)
+    }
+
+    /// Term of the entry at `index`, if the log still knows it.
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            Some(self.snapshot_term)
+        } else {
+            self.get(index).map(|entry| entry.term)
+        }
+    }
+
+    /// Up to `max` entries starting at `index`.
+    pub fn entries_from(&self, index: u64, max: usize) -> &[LogEntry] {
+        let start =
+            (index.saturating_sub(self.snapshot_index + 1) as usize).min(self.entries.len());
+        let end = (start + max).min(self.entries.len());
+        &self.entries[start..end]
+    }
+
+    pub fn append(&mut self, entries: Vec<LogEntry>) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for entry in &entries {
+            frame(entry, &mut buf)?;
+        }
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// Drops the entry at `index` and everything after it.
+    pub fn truncate_from(&mut self, index: u64) -> io::Result<()> {
+        let keep = index.saturating_sub(self.snapshot_index + 1) as usize;
+        self.entries.truncate(keep);
+        self.rewrite()
+    }
+
+    /// Replaces the log up to `index` with a snapshot of `state`, which must
+    /// be the state after applying exactly that prefix.
+    pub fn compact(&mut self, index: u64, state: &State) -> io::Result<()> {
+        let term = self
+            .term_at(index)
+            .ok_or_else(|| invalid_data(format!("no entry at {} to compact to", index)))?;
+        let snapshot = Snapshot {
+            last_index: index,
+            last_term: term,
+            state: state.clone(),
+        };
+        let bytes = bincode::serialize(&snapshot).map_err(invalid_data)?;
+        write_atomic(&self.dir, SNAPSHOT_FILE, &bytes)?;
+
+        let drop = (index - self.snapshot_index) as usize;
+        self.entries.drain(..drop);
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.rewrite()?;
+
+        tracing::debug!("Compacted raft log up to {}", index);
+        Ok(())
+    }
+
+    /// Serialized snapshot as sent to followers that lag behind it.
+    pub fn snapshot_bytes(&self) -> io::Result<Vec<u8>> {
+        match fs::read(self.dir.join(SNAPSHOT_FILE)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let snapshot = Snapshot {
+                    last_index: 0,
+                    last_term: 0,
+                    state: State::default(),
+                };
+                bincode::serialize(&snapshot).map_err(invalid_data)
+            }
+            other => other,
+        }
+    }
+
+    /// Replaces the log with a snapshot received from the leader and returns
+    /// the state it holds. Entries after the snapshot are kept if the log
+    /// agrees with it.
+    pub fn install(&mut self, data: &[u8]) -> io::Result<(u64, State)> {
+        let snapshot: Snapshot = bincode::deserialize(data).map_err(invalid_data)?;
+        write_atomic(&self.dir, SNAPSHOT_FILE, data)?;
+
+        if self.term_at(snapshot.last_index) == Some(snapshot.last_term) {
+            let drop = (snapshot.last_index - self.snapshot_index) as usize;
+            self.entries.drain(..drop);
+        } else {
+            self.entries.clear();
+        }
+        self.snapshot_index = snapshot.last_index;
+        self.snapshot_term = snapshot.last_term;
+        self.rewrite()?;
+
+        Ok((snapshot.last_index, snapshot.state))
+    }
+
+    fn rewrite(&mut self) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for entry in &self.entries {
+            frame(entry, &mut buf)?;
+        }
+        write_atomic(&self.dir, LOG_FILE, &buf)?;
+        self.file = OpenOptions::new()
+            .append(true)
+            .open(self.dir.join(LOG_FILE))?;
+        Ok(())
+    }
+}
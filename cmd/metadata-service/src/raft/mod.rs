@@ -0,0 +1,835 @@
+pub mod log;
+
+use rand::Rng;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::{Request, Response, Status};
+
+use crate::journal::Journal;
+use crate::store::{Record, State};
+use log::{LogEntry, RaftLog};
+use proto::raft::{
+    raft_client::RaftClient,
+    raft_server::{Raft, RaftServer},
+    AppendRequest, AppendResponse, Entry, SnapshotRequest, SnapshotResponse, VoteRequest,
+    VoteResponse,
+};
+
+/// How often a leader contacts idle followers.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+/// Followers that hear nothing from a leader for a random time in this range
+/// (in milliseconds) start an election.
+const ELECTION_TIMEOUT_MS: std::ops::RangeInclusive<u64> = 300..=600;
+/// A leader that heard from no majority for this long steps down.
+const LEADER_LEASE: Duration = Duration::from_millis(600);
+const TICK: Duration = Duration::from_millis(20);
+const RPC_TIMEOUT: Duration = Duration::from_millis(250);
+const MAX_ENTRIES_PER_APPEND: usize = 256;
+/// Number of applied entries after which the log is folded into a snapshot.
+const COMPACT_EVERY: u64 = 1024;
+
+fn election_deadline() -> Instant {
+    Instant::now() + Duration::from_millis(rand::thread_rng().gen_range(ELECTION_TIMEOUT_MS))
+}
+
+fn not_leader() -> io::Error {
+    io::Error::other("not the raft leader (anymore)")
+}
+
+fn encode_entry(entry: &LogEntry) -> io::Result<Entry> {
+    Ok(Entry {
+        term: entry.term,
+        index: entry.index,
+        command: bincode::serialize(&entry.record)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+    })
+}
+
+fn decode_entry(entry: Entry) -> io::Result<LogEntry> {
+    let record = bincode::deserialize::<Option<Record>>(&entry.command).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad entry {}: {}", entry.index, e),
+        )
+    })?;
+    Ok(LogEntry {
+        index: entry.index,
+        term: entry.term,
+        record,
+    })
+}
+
+fn internal(e: io::Error) -> Status {
+    Status::internal(e.to_string())
+}
+
+/// A member of the group, given as `id=address` on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub id: String,
+    /// URL both the Raft and the Metadata service of the member are served on.
+    pub addr: String,
+}
+
+impl FromStr for Peer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, addr) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected id=address, got '{}'", s))?;
+        Ok(Self {
+            id: id.to_string(),
+            addr: addr.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftStatus {
+    pub term: u64,
+    pub role: Role,
+    /// Address of the member this one believes to be leader.
+    pub leader: Option<String>,
+    pub commit_index: u64,
+    /// Set on a leader once the first entry of its term is committed. Only
+    /// then its applied state includes everything earlier leaders committed.
+    pub ready: bool,
+}
+
+#[derive(Debug)]
+struct Replica {
+    peer: Peer,
+    client: RaftClient<Channel>,
+    /// Wakes the replication task when there are new entries to send.
+    wake: Notify,
+}
+
+#[derive(Debug)]
+struct Inner {
+    log: RaftLog,
+    role: Role,
+    /// Id of the current leader, if known.
+    leader: Option<String>,
+    commit_index: u64,
+    last_applied: u64,
+    /// Result of applying the log up to `last_applied`.
+    state: State,
+    election_deadline: Instant,
+    /// Index of the no-op this node appended when it became leader.
+    term_start: u64,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    last_ack: HashMap<String, Instant>,
+}
+
+enum Message {
+    Append(AppendRequest),
+    Snapshot(SnapshotRequest),
+}
+
+/// One member of a Raft group replicating [`Record`]s.
+///
+/// The leader hands out a [`Journal`] per term; records appended through it
+/// go into the replicated log and count as durable once a majority stored
+/// them. Every member applies committed records to its copy of [`State`], so
+/// a newly elected leader starts from exactly what its predecessors
+/// acknowledged to clients.
+#[derive(Debug)]
+pub struct RaftNode {
+    id: String,
+    members: Vec<Peer>,
+    replicas: Vec<Replica>,
+    inner: Mutex<Inner>,
+    status: watch::Sender<RaftStatus>,
+}
+
+impl RaftNode {
+    /// Opens the Raft state of member `id` of `members` in `dir`. Members
+    /// with `https://` addresses are reached with `tls`. Must be called from
+    /// within a tokio runtime.
+    pub fn open<P: AsRef<Path>>(
+        id: &str,
+        members: Vec<Peer>,
+        dir: P,
+        tls: Option<ClientTlsConfig>,
+    ) -> io::Result<Arc<Self>> {
+        if !members.iter().any(|m| m.id == id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("node '{}' is not among the peers", id),
+            ));
+        }
+        let (log, state) = RaftLog::open(dir)?;
+
+        let replicas = members
+            .iter()
+            .filter(|m| m.id != id)
+            .map(|peer| {
+                let mut endpoint = Endpoint::from_shared(peer.addr.clone())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+                    .connect_timeout(RPC_TIMEOUT)
+                    .timeout(RPC_TIMEOUT);
+                if let Some(tls) = &tls {
+                    endpoint = endpoint
+                        .tls_config(tls.clone())
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                }
+                Ok(Replica {
+                    peer: peer.clone(),
+                    client: RaftClient::new(endpoint.connect_lazy()),
+                    wake: Notify::new(),
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let applied = log.snapshot_index();
+        let (status, _) = watch::channel(RaftStatus {
+            term: log.term(),
+            role: Role::Follower,
+            leader: None,
+            commit_index: applied,
+            ready: false,
+        });
+        let inner = Inner {
+            log,
+            role: Role::Follower,
+            leader: None,
+            commit_index: applied,
+            last_applied: applied,
+            state,
+            election_deadline: election_deadline(),
+            term_start: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            last_ack: HashMap::new(),
+        };
+
+        Ok(Arc::new(Self {
+            id: id.to_string(),
+            members,
+            replicas,
+            inner: Mutex::new(inner),
+            status,
+        }))
+    }
+
+    /// Starts the election timer and one replication task per other member.
+    pub fn start(self: &Arc<Self>) {
+        tokio::spawn(self.clone().run_timer());
+        for i in 0..self.replicas.len() {
+            tokio::spawn(self.clone().replicate(i));
+        }
+    }
+
+    pub fn service(self: &Arc<Self>) -> RaftServer<RaftService> {
+        RaftServer::new(RaftService { node: self.clone() })
+    }
+
+    /// A journal appending to the log for as long as this node leads `term`.
+    pub fn journal(self: &Arc<Self>, term: u64) -> Arc<dyn Journal> {
+        Arc::new(RaftJournal {
+            node: self.clone(),
+            term,
+        })
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        self.status.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<RaftStatus> {
+        self.status.subscribe()
+    }
+
+    /// Copy of the state as of the last applied entry.
+    pub fn state(&self) -> State {
+        self.inner.lock().unwrap().state.clone()
+    }
+
+    fn majority(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    fn publish(&self, inner: &Inner) {
+        let leader = inner
+            .leader
+            .as_ref()
+            .and_then(|id| self.members.iter().find(|m| m.id == *id))
+            .map(|m| m.addr.clone());
+        let status = RaftStatus {
+            term: inner.log.term(),
+            role: inner.role,
+            leader,
+            commit_index: inner.commit_index,
+            ready: inner.role == Role::Leader && inner.commit_index >= inner.term_start,
+        };
+        self.status.send_if_modified(|current| {
+            let changed = *current != status;
+            *current = status;
+            changed
+        });
+    }
+
+    /// Becomes a follower, moving to `term` if it is newer than ours.
+    fn step_down(&self, inner: &mut Inner, term: u64) -> io::Result<()> {
+        if term > inner.log.term() {
+            inner.log.set_hard_state(term, None)?;
+            inner.leader = None;
+        }
+        if inner.role != Role::Follower {
+            if inner.role == Role::Leader {
+                tracing::info!("Stepping down as leader, now in term {}", term);
+            }
+            inner.role = Role::Follower;
+            inner.election_deadline = election_deadline();
+        }
+        Ok(())
+    }
+
+    async fn run_timer(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            interval.tick().await;
+            let vote = {
+                let mut inner = self.inner.lock().unwrap();
+                let now = Instant::now();
+                match inner.role {
+                    Role::Leader => {
+                        self.check_quorum(&mut inner, now);
+                        None
+                    }
+                    _ if now >= inner.election_deadline => self.start_election(&mut inner),
+                    _ => None,
+                }
+            };
+            if let Some(req) = vote {
+                tokio::spawn(self.clone().run_election(req));
+            }
+        }
+    }
+
+    /// Steps down when cut off from a majority, so clients of a partitioned
+    /// leader move on to the new one instead of waiting for commits.
+    fn check_quorum(&self, inner: &mut Inner, now: Instant) {
+        let reachable = 1 + inner
+            .last_ack
+            .values()
+            .filter(|at| now.duration_since(**at) < LEADER_LEASE)
+            .count();
+        if reachable < self.majority() {
+            tracing::warn!("Lost contact with a majority, stepping down");
+            inner.role = Role::Follower;
+            inner.leader = None;
+            inner.election_deadline = election_deadline();
+            self.publish(inner);
+        }
+    }
+
+    fn start_election(&self, inner: &mut Inner) -> Option<VoteRequest> {
+        let term = inner.log.term() + 1;
+        inner.election_deadline = election_deadline();
+        if let Err(e) = inner.log.set_hard_state(term, Some(self.id.clone())) {
+            tracing::error!("Failed to persist vote, not starting election: {}", e);
+            return None;
+        }
+        inner.role = Role::Candidate;
+        inner.leader = None;
+        tracing::info!("Starting election for term {}", term);
+        self.publish(inner);
+
+        if self.majority() == 1 {
+            self.become_leader(inner);
+            return None;
+        }
+        Some(VoteRequest {
+            term,
+            candidate_id: self.id.clone(),
+            last_log_index: inner.log.last_index(),
+            last_log_term: inner.log.last_term(),
+        })
+    }
+
+    async fn run_election(self: Arc<Self>, req: VoteRequest) {
+        let mut votes = JoinSet::new();
+        for replica in &self.replicas {
+            let mut client = replica.client.clone();
+            let req = req.clone();
+            votes.spawn(async move { client.request_vote(req).await });
+        }
+
+        let mut granted = 1;
+        while let Some(result) = votes.join_next().await {
+            let Ok(Ok(resp)) = result else {
+                continue;
+            };
+            let resp = resp.into_inner();
+            let mut inner = self.inner.lock().unwrap();
+            if resp.term > inner.log.term() {
+                if let Err(e) = self.step_down(&mut inner, resp.term) {
+                    tracing::error!("Failed to persist term: {}", e);
+                }
+                self.publish(&inner);
+                return;
+            }
+            if inner.role != Role::Candidate || inner.log.term() != req.term {
+                return;
+            }
+            if resp.vote_granted {
+                granted += 1;
+                if granted >= self.majority() {
+                    self.become_leader(&mut inner);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn become_leader(&self, inner: &mut Inner) {
+        let term = inner.log.term();
+        let index = inner.log.last_index() + 1;
+        let noop = LogEntry {
+            index,
+            term,
+            record: None,
+        };
+        if let Err(e) = inner.log.append(vec![noop]) {
+            tracing::error!("Failed to append to raft log, not taking leadership: {}", e);
+            inner.role = Role::Follower;
+            self.publish(inner);
+            return;
+        }
+
+        inner.role = Role::Leader;
+        inner.leader = Some(self.id.clone());
+        inner.term_start = index;
+        let now = Instant::now();
+        for replica in &self.replicas {
+            let id = &replica.peer.id;
+            inner.next_index.insert(id.clone(), index);
+            inner.match_index.insert(id.clone(), 0);
+            inner.last_ack.insert(id.clone(), now);
+        }
+        tracing::info!("Elected leader for term {}", term);
+
+        self.advance_commit(inner);
+        self.publish(inner);
+        for replica in &self.replicas {
+            replica.wake.notify_one();
+        }
+    }
+
+    /// Commits the highest entry of the current term a majority has stored.
+    fn advance_commit(&self, inner: &mut Inner) {
+        let mut matched: Vec<u64> = inner.match_index.values().copied().collect();
+        matched.push(inner.log.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let candidate = matched[self.majority() - 1];
+        if candidate > inner.commit_index && inner.log.term_at(candidate) == Some(inner.log.term())
+        {
+            inner.commit_index = candidate;
+            self.apply(inner);
+        }
+    }
+
+    fn apply(&self, inner: &mut Inner) {
+        while inner.last_applied < inner.commit_index {
+            let index = inner.last_applied + 1;
+            if let Some(Some(record)) = inner.log.get(index).map(|entry| &entry.record) {
+                inner.state.apply(record);
+            }
+            inner.last_applied = index;
+        }
+        if inner.last_applied - inner.log.snapshot_index() >= COMPACT_EVERY {
+            if let Err(e) = inner.log.compact(inner.last_applied, &inner.state) {
+                tracing::error!("Failed to compact raft log: {}", e);
+            }
+        }
+    }
+
+    fn propose(&self, term: u64, record: Record) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.role != Role::Leader || inner.log.term() != term {
+            return Err(not_leader());
+        }
+        let entry = LogEntry {
+            index: inner.log.last_index() + 1,
+            term,
+            record: Some(record),
+        };
+        inner.log.append(vec![entry])?;
+        self.advance_commit(&mut inner);
+        self.publish(&inner);
+        for replica in &self.replicas {
+            replica.wake.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Waits until the log is committed up to `index`, failing if this node
+    /// stops leading `term` first.
+    async fn wait_committed(&self, term: u64, index: u64) -> io::Result<()> {
+        let mut status = self.status.subscribe();
+        loop {
+            {
+                let current = status.borrow_and_update();
+                if current.term != term || current.role != Role::Leader {
+                    return Err(not_leader());
+                }
+                if current.commit_index >= index {
+                    return Ok(());
+                }
+            }
+            status.changed().await.map_err(|_| not_leader())?;
+        }
+    }
+
+    async fn replicate(self: Arc<Self>, i: usize) {
+        let replica = &self.replicas[i];
+        loop {
+            let _ = tokio::time::timeout(HEARTBEAT_INTERVAL, replica.wake.notified()).await;
+            // Keep going for as long as the follower is behind.
+            while self.replicate_once(replica).await {}
+        }
+    }
+
+    /// Sends the next batch of entries (or the snapshot) to `replica`.
+    /// Returns whether there is more to send right away.
+    async fn replicate_once(&self, replica: &Replica) -> bool {
+        let message = {
+            let inner = self.inner.lock().unwrap();
+            if inner.role != Role::Leader {
+                return false;
+            }
+            let next = inner.next_index[&replica.peer.id];
+            if next <= inner.log.snapshot_index() {
+                let data = match inner.log.snapshot_bytes() {
+                    Ok(data) => data,
+                    Err(e) => {
+                        tracing::error!("Failed to read raft snapshot: {}", e);
+                        return false;
+                    }
+                };
+                Message::Snapshot(SnapshotRequest {
+                    term: inner.log.term(),
+                    leader_id: self.id.clone(),
+                    last_included_index: inner.log.snapshot_index(),
+                    last_included_term: inner.log.snapshot_term(),
+                    data,
+                })
+            } else {
+                let entries = inner
+                    .log
+                    .entries_from(next, MAX_ENTRIES_PER_APPEND)
+                    .iter()
+                    .map(encode_entry)
+                    .collect::<io::Result<Vec<_>>>();
+                let entries = match entries {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        tracing::error!("Failed to encode raft entries: {}", e);
+                        return false;
+                    }
+                };
+                Message::Append(AppendRequest {
+                    term: inner.log.term(),
+                    leader_id: self.id.clone(),
+                    prev_log_index: next - 1,
+                    prev_log_term: inner.log.term_at(next - 1).unwrap_or(0),
+                    entries,
+                    leader_commit: inner.commit_index,
+                })
+            }
+        };
+
+        let mut client = replica.client.clone();
+        match message {
+            Message::Append(req) => {
+                let term = req.term;
+                match client.append_entries(req).await {
+                    Ok(resp) => self.on_append_response(replica, term, resp.into_inner()),
+                    Err(e) => {
+                        tracing::debug!("AppendEntries to {} failed: {}", replica.peer.id, e);
+                        false
+                    }
+                }
+            }
+            Message::Snapshot(req) => {
+                let (term, index) = (req.term, req.last_included_index);
+                match client.install_snapshot(req).await {
+                    Ok(resp) => self.on_snapshot_response(replica, term, index, resp.into_inner()),
+                    Err(e) => {
+                        tracing::debug!("InstallSnapshot to {} failed: {}", replica.peer.id, e);
+                        false
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks a response to a request sent in `term`. Returns false if it
+    /// should be ignored.
+    fn accept_response(&self, inner: &mut Inner, term: u64, resp_term: u64) -> bool {
+        if resp_term > inner.log.term() {
+            if let Err(e) = self.step_down(inner, resp_term) {
+                tracing::error!("Failed to persist term: {}", e);
+            }
+            self.publish(inner);
+            return false;
+        }
+        inner.role == Role::Leader && inner.log.term() == term
+    }
+
+    fn on_append_response(&self, replica: &Replica, term: u64, resp: AppendResponse) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if !self.accept_response(&mut inner, term, resp.term) {
+            return false;
+        }
+        let id = &replica.peer.id;
+        inner.last_ack.insert(id.clone(), Instant::now());
+
+        if resp.success {
+            let matched = inner.match_index[id].max(resp.match_index);
+            inner.match_index.insert(id.clone(), matched);
+            inner.next_index.insert(id.clone(), matched + 1);
+            self.advance_commit(&mut inner);
+            self.publish(&inner);
+            matched < inner.log.last_index()
+        } else {
+            let next = inner.next_index[id];
+            let next = resp.conflict_index.clamp(1, next.saturating_sub(1).max(1));
+            let matched = inner.match_index[id].min(next - 1);
+            inner.next_index.insert(id.clone(), next);
+            inner.match_index.insert(id.clone(), matched);
+            true
+        }
+    }
+
+    fn on_snapshot_response(
+        &self,
+        replica: &Replica,
+        term: u64,
+        index: u64,
+        resp: SnapshotResponse,
+    ) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if !self.accept_response(&mut inner, term, resp.term) {
+            return false;
+        }
+        let id = &replica.peer.id;
+        inner.last_ack.insert(id.clone(), Instant::now());
+
+        let matched = inner.match_index[id].max(index);
+        inner.match_index.insert(id.clone(), matched);
+        inner.next_index.insert(id.clone(), matched + 1);
+        self.advance_commit(&mut inner);
+        self.publish(&inner);
+        true
+    }
+
+    fn handle_vote(&self, req: VoteRequest) -> io::Result<VoteResponse> {
+        let mut inner = self.inner.lock().unwrap();
+        if req.term > inner.log.term() {
+            self.step_down(&mut inner, req.term)?;
+        }
+        let term = inner.log.term();
+        let up_to_date = (req.last_log_term, req.last_log_index)
+            >= (inner.log.last_term(), inner.log.last_index());
+        let free = inner
+            .log
+            .voted_for()
+            .is_none_or(|id| id == req.candidate_id);
+
+        let granted = req.term == term && up_to_date && free;
+        if granted {
+            if inner.log.voted_for().is_none() {
+                inner
+                    .log
+                    .set_hard_state(term, Some(req.candidate_id.clone()))?;
+            }
+            inner.election_deadline = election_deadline();
+            tracing::debug!("Voted for {} in term {}", req.candidate_id, term);
+        }
+        self.publish(&inner);
+
+        Ok(VoteResponse {
+            term,
+            vote_granted: granted,
+        })
+    }
+
+    fn handle_append(&self, req: AppendRequest) -> io::Result<AppendResponse> {
+        let mut inner = self.inner.lock().unwrap();
+        let term = inner.log.term();
+        if req.term < term {
+            return Ok(AppendResponse {
+                term,
+                ..Default::default()
+            });
+        }
+        self.step_down(&mut inner, req.term)?;
+        inner.leader = Some(req.leader_id.clone());
+        inner.election_deadline = election_deadline();
+        let term = inner.log.term();
+
+        let reject = |inner: &Inner, conflict_index| {
+            self.publish(inner);
+            Ok(AppendResponse {
+                term,
+                success: false,
+                match_index: 0,
+                conflict_index,
+            })
+        };
+
+        // Entries up to the snapshot are committed and therefore match.
+        let snapshot_index = inner.log.snapshot_index();
+        let prev = req.prev_log_index;
+        if prev > inner.log.last_index() {
+            return reject(&inner, inner.log.last_index() + 1);
+        }
+        if prev > snapshot_index {
+            let prev_term = inner.log.term_at(prev).unwrap_or(0);
+            if prev_term != req.prev_log_term {
+                // Skip the whole conflicting term rather than one entry per
+                // round trip.
+                let mut first = prev;
+                while first > snapshot_index + 1 && inner.log.term_at(first - 1) == Some(prev_term)
+                {
+                    first -= 1;
+                }
+                return reject(&inner, first);
+            }
+        }
+
+        let matched = prev + req.entries.len() as u64;
+        let mut new = Vec::new();
+        for entry in req.entries {
+            if entry.index <= snapshot_index {
+                continue;
+            }
+            if new.is_empty() {
+                match inner.log.term_at(entry.index) {
+                    Some(t) if t == entry.term => continue,
+                    Some(_) => inner.log.truncate_from(entry.index)?,
+                    None => {}
+                }
+            }
+            new.push(decode_entry(entry)?);
+        }
+        if !new.is_empty() {
+            inner.log.append(new)?;
+        }
+
+        let commit = req.leader_commit.min(matched);
+        if commit > inner.commit_index {
+            inner.commit_index = commit;
+            self.apply(&mut inner);
+        }
+        self.publish(&inner);
+
+        Ok(AppendResponse {
+            term,
+            success: true,
+            match_index: matched,
+            conflict_index: 0,
+        })
+    }
+
+    fn handle_snapshot(&self, req: SnapshotRequest) -> io::Result<SnapshotResponse> {
+        let mut inner = self.inner.lock().unwrap();
+        let term = inner.log.term();
+        if req.term < term {
+            return Ok(SnapshotResponse { term });
+        }
+        self.step_down(&mut inner, req.term)?;
+        inner.leader = Some(req.leader_id.clone());
+        inner.election_deadline = election_deadline();
+
+        if req.last_included_index > inner.commit_index {
+            let (index, state) = inner.log.install(&req.data)?;
+            inner.state = state;
+            inner.commit_index = index;
+            inner.last_applied = index;
+            tracing::info!("Installed snapshot up to {} from {}", index, req.leader_id);
+        }
+        self.publish(&inner);
+
+        Ok(SnapshotResponse {
+            term: inner.log.term(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct RaftJournal {
+    node: Arc<RaftNode>,
+    /// Appends are refused once the node no longer leads this term, so a
+    /// deposed leader cannot slip records into its successor's log.
+    term: u64,
+}
+
+#[tonic::async_trait]
+impl Journal for RaftJournal {
+    fn append(&self, record: Record) -> io::Result<()> {
+        self.node.propose(self.term, record)
+    }
+
+    async fn sync(&self) -> io::Result<()> {
+        let index = self.node.inner.lock().unwrap().log.last_index();
+        self.node.wait_committed(self.term, index).await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RaftService {
+    node: Arc<RaftNode>,
+}
+
+#[tonic::async_trait]
+impl Raft for RaftService {
+    async fn request_vote(
+        &self,
+        request: Request<VoteRequest>,
+    ) -> Result<Response<VoteResponse>, Status> {
+        self.node
+            .handle_vote(request.into_inner())
+            .map(Response::new)
+            .map_err(internal)
+    }
+
+    async fn append_entries(
+        &self,
+        request: Request<AppendRequest>,
+    ) -> Result<Response<AppendResponse>, Status> {
+        self.node
+            .handle_append(request.into_inner())
+            .map(Response::new)
+            .map_err(internal)
+    }
+
+    async fn install_snapshot(
+        &self,
+        request: Request<SnapshotRequest>,
+    ) -> Result<Response<SnapshotResponse>, Status> {
+        self.node
+            .handle_snapshot(request.into_inner())
+            .map(Response::new)
+            .map_err(internal)
+    }
+}
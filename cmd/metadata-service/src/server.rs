@@ -1,17 +1,532 @@
-use std::collections::HashSet;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataValue;
+use tonic::service::interceptor::InterceptedService;
 use tonic::{Request, Response, Status};
 
-
+use crate::auth::{Authenticator, Denied, Principal};
+use crate::inode::InodeAllocator;
+use crate::journal::Journal;
+use crate::lock::{LockEntry, LockError, LockManager, LockMode};
+use crate::metrics::metrics;
+use crate::node::{NodeManager, NodeState};
+use crate::raft::{RaftNode, Role};
+use crate::session::SessionManager;
+use crate::store::{LockKey, Store, VolumeInfo};
+use crate::volume::{VolumeError, VolumeRegistry, VolumeSpec};
+use crate::watch::Watchers;
 use proto::metadata::{
     metadata_server::{Metadata, MetadataServer},
-    LockRequest, LockResponse,
+    AllocateInodesRequest, AllocateInodesResponse, AttachVolumeRequest, CreateVolumeRequest,
+    DeleteVolumeResponse, ForceReleaseRequest, ForceReleaseResponse, HeartbeatRequest,
+    ListLocksRequest, ListLocksResponse, ListNodesRequest, ListNodesResponse, ListSessionsRequest,
+    ListSessionsResponse, ListVolumesRequest, ListVolumesResponse, LockInfo, LockOwner,
+    LockRequest, LockResponse, LockStatus, Node, NodeResponse, OpenSessionRequest, RegisterRequest,
+    SessionInfo, SessionRequest, SessionResponse, Volume, VolumeRequest, VolumeResponse,
+    WatchEvent, WatchRequest,
 };
 
-#[derive(Debug, Default)]
+/// Used when a client does not say how long it is willing to wait.
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Upper bound on how long a single AcquireLock call may be parked.
+const MAX_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(10);
+const MIN_SESSION_TTL: Duration = Duration::from_secs(1);
+const MAX_SESSION_TTL: Duration = Duration::from_secs(300);
+/// How often expired sessions are looked for.
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+/// Events queued per Watch stream while the client is slow to read them.
+const WATCH_STREAM_BUFFER: usize = 64;
+const DEFAULT_INODE_BATCH: u32 = 64;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_INODE_BATCH: u32 = 4096;
+
+/// Lock and session state served while this instance may accept changes:
+/// always for a standalone service, only while leading for a replica.
+#[derive(Debug, Clone)]
+struct Core {
+    locks: LockManager,
+    sessions: SessionManager,
+    inodes: InodeAllocator,
+    volumes: VolumeRegistry,
+    nodes: NodeManager,
+    journal: Option<Arc<dyn Journal>>,
+    /// Changes on every start (and leader change) so clients can tell that
+    /// the server lost its in-memory state.
+    epoch: String,
+    /// Until then only reclaims of locks restored from the store are served.
+    grace_until: Option<Instant>,
+    watchers: Watchers,
+}
+
+impl Core {
+    /// Tells watchers about the keys `owner` may have modified under the
+    /// locks it just released.
+    fn publish_released(&self, owner: &str, released: &[(LockKey, LockMode)]) {
+        for (key, mode) in released {
+            if *mode == LockMode::Exclusive {
+                self.watchers.publish(key.clone(), owner);
+            }
+        }
+    }
+
+    /// Releases the locks of session `id` and closes it. Returns the locks,
+    /// or `None` if the session was not open. If that cannot be journaled,
+    /// watchers are told about the locks released so far and the session
+    /// stays open, holding the rest, so ending it can be retried.
+    fn end_session(&self, id: &str) -> io::Result<Option<Vec<(LockKey, LockMode)>>> {
+        if !self.sessions.is_open(id) {
+            return Ok(None);
+        }
+        let (released, result) = self.locks.release_owner(id);
+        if let Err(e) = result.and_then(|()| self.sessions.close(id)) {
+            self.publish_released(id, &released);
+            return Err(e);
+        }
+        Ok(Some(released))
+    }
+
+    /// Revokes the leases of a node declared dead and releases its volumes
+    /// for attachment elsewhere.
+    fn release_node(&self, node: &str) {
+        for id in self.sessions.of_node(node) {
+            match self.end_session(&id) {
+                Ok(Some(released)) => {
+                    tracing::warn!(
+                        "Closed session {} of dead node {}, released {} locks",
+                        id,
+                        node,
+                        released.len()
+                    );
+                    self.publish_released(&id, &released);
+                }
+                Ok(None) => {}
+                // Its lease runs out without heartbeats, and the reaper
+                // tries again.
+                Err(e) => tracing::error!(
+                    "Failed to close session {} of dead node {}: {}",
+                    id,
+                    node,
+                    e
+                ),
+            }
+        }
+        match self.volumes.detach_node(node) {
+            Ok(volumes) if !volumes.is_empty() => {
+                tracing::warn!("Detached volumes {:?} from dead node {}", volumes, node)
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!("Failed to detach volumes of dead node {}: {}", node, e),
+        }
+    }
+
+    /// Fails unless the caller may use session `id`: it belongs to the node
+    /// the caller acts for.
+    fn authorize_session(&self, principal: Option<&Principal>, id: &str) -> Result<(), Denied> {
+        let Some(principal) = principal.filter(|p| p.node.is_some()) else {
+            return Ok(());
+        };
+        match self.sessions.node(id) {
+            Some(node) => principal.check_node(&node),
+            None => Err(Denied(format!("Unknown session '{}'", id))),
+        }
+    }
+
+    /// Fails unless `volume` is attached to the node the caller acts for.
+    /// The empty volume, a filesystem spanning a whole device, is not in the
+    /// registry, so callers acting for a node may not use it.
+    fn authorize_volume(&self, principal: Option<&Principal>, volume: &str) -> Result<(), Denied> {
+        let Some(node) = principal.and_then(|p| p.node.as_ref()) else {
+            return Ok(());
+        };
+        match self.volumes.get(volume) {
+            Some(info) if info.attached.contains(node) => Ok(()),
+            _ => Err(Denied(format!(
+                "Volume '{}' is not attached to node '{}'",
+                volume, node
+            ))),
+        }
+    }
+
+    /// Waits until everything journaled so far is durable.
+    #[tracing::instrument(name = "journal.sync", skip(self))]
+    async fn sync(&self) -> Result<(), Status> {
+        match &self.journal {
+            Some(journal) => journal.sync().await.map_err(commit_failed),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct MetadataService {
-    locked_keys: Arc<Mutex<HashSet<u64>>>,
+    core: Arc<RwLock<Option<Core>>>,
+    raft: Option<Arc<RaftNode>>,
+    /// How long a new leader leaves clients to reclaim their locks before
+    /// it drops the rest.
+    grace: Duration,
+}
+
+impl Default for MetadataService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn lock_timeout(timeout_ms: u64) -> Duration {
+    if timeout_ms == 0 {
+        DEFAULT_LOCK_TIMEOUT
+    } else {
+        Duration::from_millis(timeout_ms).min(MAX_LOCK_TIMEOUT)
+    }
+}
+
+fn session_ttl(ttl_ms: u64) -> Duration {
+    if ttl_ms == 0 {
+        DEFAULT_SESSION_TTL
+    } else {
+        Duration::from_millis(ttl_ms).clamp(MIN_SESSION_TTL, MAX_SESSION_TTL)
+    }
+}
+
+fn inode_batch(count: u32) -> u32 {
+    if count == 0 {
+        DEFAULT_INODE_BATCH
+    } else {
+        count.min(MAX_INODE_BATCH)
+    }
+}
+
+fn volume_message(id: String, info: VolumeInfo) -> Volume {
+    Volume {
+        id,
+        device: info.device,
+        size: info.size,
+        offset: info.offset,
+        attached_nodes: info.attached.into_iter().collect(),
+    }
+}
+
+/// UNAVAILABLE for a change that could not be journaled or made durable.
+fn commit_failed(e: io::Error) -> Status {
+    Status::unavailable(format!("Failed to commit change: {}", e))
+}
+
+fn volume_status(id: &str, err: VolumeError) -> Status {
+    let message = format!("Volume '{}': {}", id, err);
+    match err {
+        VolumeError::NotFound => Status::not_found(message),
+        VolumeError::AlreadyExists => Status::already_exists(message),
+        VolumeError::Overlaps(_) | VolumeError::Attached(_) => Status::failed_precondition(message),
+        VolumeError::Invalid(_) => Status::invalid_argument(message),
+        VolumeError::Journal(_) => Status::unavailable(message),
+    }
+}
+
+fn node_state(state: NodeState) -> proto::metadata::NodeState {
+    match state {
+        NodeState::Joining => proto::metadata::NodeState::Joining,
+        NodeState::Active => proto::metadata::NodeState::Active,
+        NodeState::Suspect => proto::metadata::NodeState::Suspect,
+        NodeState::Dead => proto::metadata::NodeState::Dead,
+    }
+}
+
+fn lock_owner(core: &Core, entry: LockEntry) -> LockOwner {
+    LockOwner {
+        node: core.sessions.node(&entry.owner).unwrap_or_default(),
+        owner: entry.owner,
+        shared: entry.mode == LockMode::Shared,
+        age_ms: entry.age.as_millis() as u64,
+    }
+}
+
+/// The response to a lock request, or the error that kept the grant from
+/// being journaled.
+fn lock_response(key: &LockKey, result: Result<u64, LockError>) -> io::Result<LockResponse> {
+    Ok(match result {
+        Ok(token) => LockResponse {
+            success: true,
+            message: format!("Lock acquired for '{}'", key),
+            status: LockStatus::Ok.into(),
+            fencing_token: token,
+        },
+        Err(LockError::Timeout) => LockResponse {
+            success: false,
+            message: format!("Timed out waiting for lock on '{}'", key),
+            status: LockStatus::Timeout.into(),
+            ..Default::default()
+        },
+        Err(LockError::Deadlock) => LockResponse {
+            success: false,
+            message: format!("Lock on '{}' would deadlock", key),
+            status: LockStatus::Deadlock.into(),
+            ..Default::default()
+        },
+        Err(LockError::NotHeld) => LockResponse {
+            success: false,
+            message: format!("No lock held for '{}'", key),
+            status: LockStatus::NotHeld.into(),
+            ..Default::default()
+        },
+        Err(LockError::Journal(e)) => return Err(e),
+    })
+}
+
+impl MetadataService {
+    fn standalone(core: Core) -> Self {
+        Self {
+            core: Arc::new(RwLock::new(Some(core))),
+            raft: None,
+            grace: Duration::ZERO,
+        }
+    }
+
+    /// A service that keeps all state in memory.
+    pub fn new() -> Self {
+        Self::standalone(Core {
+            locks: LockManager::new(),
+            sessions: SessionManager::new(),
+            inodes: InodeAllocator::new(),
+            volumes: VolumeRegistry::new(),
+            nodes: NodeManager::new(HEARTBEAT_INTERVAL),
+            journal: None,
+            epoch: uuid::Uuid::new_v4().to_string(),
+            grace_until: None,
+            watchers: Watchers::new(),
+        })
+    }
+
+    /// A service persisting its state in `data_dir`. If the store holds
+    /// state from a previous run, a grace period of `grace` starts during
+    /// which clients may reclaim their locks before new grants are issued.
+    pub fn open<P: AsRef<Path>>(data_dir: P, grace: Duration) -> std::io::Result<Self> {
+        let store = Arc::new(Store::open(data_dir)?);
+        let state = store.state();
+
+        let grace_until = if state.is_empty() {
+            None
+        } else {
+            tracing::info!(
+                "Restored {} sessions and locks on {} keys, grace period of {:?}",
+                state.sessions.len(),
+                state.grants.len(),
+                grace
+            );
+            Some(Instant::now() + grace)
+        };
+
+        let journal: Arc<dyn Journal> = store;
+        Ok(Self::standalone(Core {
+            locks: LockManager::with_journal(journal.clone(), &state),
+            sessions: SessionManager::with_journal(journal.clone(), &state),
+            inodes: InodeAllocator::with_journal(journal.clone(), &state),
+            volumes: VolumeRegistry::with_journal(journal.clone(), &state),
+            nodes: NodeManager::with_journal(HEARTBEAT_INTERVAL, journal.clone(), &state),
+            journal: Some(journal),
+            epoch: uuid::Uuid::new_v4().to_string(),
+            grace_until,
+            watchers: Watchers::new(),
+        }))
+    }
+
+    /// A service replicating its state through `raft`. It serves requests
+    /// only while `raft` leads the group; other members point clients at
+    /// the leader. Whenever this member takes over, clients have `grace` to
+    /// reclaim their locks before the others are dropped.
+    pub fn replicated(raft: Arc<RaftNode>, grace: Duration) -> Self {
+        if let Err(e) = metrics().enable_raft() {
+            tracing::warn!("Failed to register Raft metrics: {}", e);
+        }
+        Self {
+            core: Arc::new(RwLock::new(None)),
+            raft: Some(raft),
+            grace,
+        }
+    }
+
+    /// Starts the tasks that expire sessions, end the grace period and follow
+    /// leadership changes. Must be called from within a tokio runtime.
+    pub fn spawn_background_tasks(&self) {
+        let grace = self.core.read().unwrap().as_ref().and_then(|core| {
+            let deadline = core.grace_until?;
+            Some((deadline, core.locks.clone()))
+        });
+        if let Some((deadline, locks)) = grace {
+            tokio::spawn(async move {
+                tokio::time::sleep_until(deadline).await;
+                let dropped = locks.drop_unreclaimed();
+                tracing::info!("Grace period over, dropped {} unreclaimed locks", dropped);
+            });
+        }
+
+        if let Some(raft) = self.raft.clone() {
+            tokio::spawn(follow_leadership(raft, self.core.clone(), self.grace));
+        }
+
+        let slot = self.core.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REAP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(core) = slot.read().unwrap().clone() else {
+                    continue;
+                };
+                let mut expired = 0;
+                for id in core.sessions.expired() {
+                    match core.end_session(&id) {
+                        Ok(Some(released)) => {
+                            expired += 1;
+                            tracing::warn!(
+                                "Session {} expired, released {} locks",
+                                id,
+                                released.len()
+                            );
+                            core.publish_released(&id, &released);
+                        }
+                        Ok(None) => {}
+                        // Still open, so retried on the next round.
+                        Err(e) => {
+                            tracing::error!("Failed to close expired session {}: {}", id, e)
+                        }
+                    }
+                }
+                metrics().leases_expired(expired);
+                for node in core.nodes.take_dead() {
+                    core.release_node(&node);
+                }
+            }
+        });
+    }
+
+    /// Whether clients can be served: by this instance, or by the leader
+    /// this replica redirects them to.
+    pub fn is_ready(&self) -> bool {
+        self.core().is_some()
+            || self
+                .raft
+                .as_ref()
+                .is_some_and(|raft| raft.status().leader.is_some())
+    }
+
+    /// Refreshes the gauges of [`metrics()`] from the current state.
+    pub fn update_metrics(&self) {
+        match self.core() {
+            Some(core) => metrics().observe_tables(&core.locks.list(), core.sessions.list().len()),
+            None => metrics().observe_idle(),
+        }
+        if let Some(raft) = &self.raft {
+            metrics().observe_raft(&raft.status());
+        }
+    }
+
+    /// The state to serve from; `None` if this replica does not lead the
+    /// group.
+    fn core(&self) -> Option<Core> {
+        self.core.read().unwrap().clone()
+    }
+
+    /// UNAVAILABLE, naming the leader if this replica knows it.
+    fn not_leader(&self) -> Status {
+        let mut status = Status::unavailable("Not the metadata-service leader");
+        let leader = self.raft.as_ref().and_then(|raft| raft.status().leader);
+        if let Some(value) = leader.and_then(|addr| addr.parse::<MetadataValue<_>>().ok()) {
+            status
+                .metadata_mut()
+                .insert(proto::LEADER_METADATA_KEY, value);
+        }
+        status
+    }
+
+    fn session_response(core: &Core, id: &str, ttl: Option<Duration>) -> SessionResponse {
+        match ttl {
+            Some(ttl) => SessionResponse {
+                success: true,
+                message: format!("Session '{}' is active", id),
+                session_id: id.to_string(),
+                ttl_ms: ttl.as_millis() as u64,
+                server_epoch: core.epoch.clone(),
+            },
+            None => SessionResponse {
+                success: false,
+                message: format!("Unknown session '{}'", id),
+                session_id: id.to_string(),
+                server_epoch: core.epoch.clone(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Installs a fresh [`Core`] built from the replicated state whenever this
+/// replica becomes a ready leader, and removes it when it stops leading.
+/// Locks of the replicated state not reclaimed within `grace` are dropped.
+async fn follow_leadership(raft: Arc<RaftNode>, slot: Arc<RwLock<Option<Core>>>, grace: Duration) {
+    let mut status = raft.subscribe();
+    let mut serving = None;
+    loop {
+        let current = status.borrow_and_update().clone();
+        let term = (current.role == Role::Leader && current.ready).then_some(current.term);
+        if term != serving {
+            let core = term.map(|term| {
+                let state = raft.state();
+                let journal = raft.journal(term);
+                tracing::info!(
+                    "Serving as leader for term {} with {} sessions and locks on {} keys",
+                    term,
+                    state.sessions.len(),
+                    state.grants.len()
+                );
+                Core {
+                    locks: LockManager::with_journal(journal.clone(), &state),
+                    sessions: SessionManager::with_journal(journal.clone(), &state),
+                    inodes: InodeAllocator::with_journal(journal.clone(), &state),
+                    volumes: VolumeRegistry::with_journal(journal.clone(), &state),
+                    nodes: NodeManager::with_journal(HEARTBEAT_INTERVAL, journal.clone(), &state),
+                    journal: Some(journal),
+                    epoch: uuid::Uuid::new_v4().to_string(),
+                    grace_until: None,
+                    watchers: Watchers::new(),
+                }
+            });
+            let restored = core
+                .as_ref()
+                .filter(|core| !core.locks.list().is_empty())
+                .map(|core| core.epoch.clone());
+            let previous = std::mem::replace(&mut *slot.write().unwrap(), core);
+            if let Some(previous) = previous {
+                previous.watchers.close();
+            }
+            if let Some(epoch) = restored {
+                tokio::spawn(drop_unreclaimed_after(grace, slot.clone(), epoch));
+            }
+            serving = term;
+        }
+        if status.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Drops the locks nobody reclaimed once `grace` is over, unless the
+/// [`Core`] with `epoch` no longer serves by then.
+async fn drop_unreclaimed_after(grace: Duration, slot: Arc<RwLock<Option<Core>>>, epoch: String) {
+    tokio::time::sleep(grace).await;
+    let Some(core) = slot
+        .read()
+        .unwrap()
+        .clone()
+        .filter(|core| core.epoch == epoch)
+    else {
+        return;
+    };
+    let dropped = core.locks.drop_unreclaimed();
+    tracing::info!("Grace period over, dropped {} unreclaimed locks", dropped);
 }
 
 #[tonic::async_trait]
@@ -20,32 +535,101 @@ impl Metadata for MetadataService {
         &self,
         request: Request<LockRequest>,
     ) -> Result<Response<LockResponse>, Status> {
-        let key = request.into_inner().key;
-
-        let mut locks = self.locked_keys.lock().await;
-        if locks.contains(&key) {
+        let started = Instant::now();
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let principal = Principal::of(&request);
+        let req = request.into_inner();
+        // Locks go with the session that holds them; one owned by no session
+        // would never be released.
+        if !core.sessions.is_open(&req.owner) {
             return Ok(Response::new(LockResponse {
                 success: false,
-                message: format!("Key '{}' is already locked", key),
+                message: format!("Unknown session '{}'", req.owner),
+                ..Default::default()
             }));
         }
+        core.authorize_session(principal.as_ref(), &req.owner)?;
+        core.authorize_volume(principal.as_ref(), &req.volume)?;
+        let key = LockKey::new(&req.volume, req.key);
 
-        locks.insert(key.clone());
+        if req.reclaim {
+            let result = core.locks.reclaim(&key, &req.owner);
+            return Ok(Response::new(
+                lock_response(&key, result).map_err(commit_failed)?,
+            ));
+        }
 
-        Ok(Response::new(LockResponse {
-            success: true,
-            message: format!("Lock acquired for '{}'", key),
-        }))
+        let mut timeout = lock_timeout(req.timeout_ms);
+        if let Some(grace_until) = core.grace_until {
+            let now = Instant::now();
+            if grace_until > now {
+                let remaining = grace_until - now;
+                if remaining >= timeout {
+                    metrics().lock_acquired("grace", started.elapsed());
+                    return Ok(Response::new(LockResponse {
+                        success: false,
+                        message: "Server is in its restart grace period".to_string(),
+                        status: LockStatus::Grace.into(),
+                        ..Default::default()
+                    }));
+                }
+                tokio::time::sleep_until(grace_until).await;
+                timeout -= remaining;
+            }
+        }
+
+        let mode = if req.shared {
+            LockMode::Shared
+        } else {
+            LockMode::Exclusive
+        };
+        let result = core
+            .locks
+            .acquire(&key, &req.owner, req.txn, mode, timeout)
+            .await;
+        if result.is_ok() {
+            if let Err(status) = core.sync().await {
+                // Not acknowledged, so not granted: a retry must not find
+                // the lock already held, and the next waiter may have it.
+                if let Err(e) = core.locks.release(&key, &req.owner) {
+                    tracing::error!("Failed to revoke uncommitted lock on {}: {}", key, e);
+                }
+                metrics().lock_acquired("error", started.elapsed());
+                return Err(status);
+            }
+        }
+        let outcome = match &result {
+            Ok(_) => "granted",
+            Err(LockError::Timeout) => "timeout",
+            Err(LockError::Deadlock) => "deadlock",
+            Err(LockError::NotHeld) => "not_held",
+            Err(LockError::Journal(_)) => "error",
+        };
+        metrics().lock_acquired(outcome, started.elapsed());
+
+        Ok(Response::new(
+            lock_response(&key, result).map_err(commit_failed)?,
+        ))
     }
 
     async fn release_lock(
         &self,
         request: Request<LockRequest>,
     ) -> Result<Response<LockResponse>, Status> {
-        let key = request.into_inner().key;
-
-        let mut locks = self.locked_keys.lock().await;
-        let removed = locks.remove(&key);
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let principal = Principal::of(&request);
+        let req = request.into_inner();
+        core.authorize_session(principal.as_ref(), &req.owner)?;
+        let key = LockKey::new(&req.volume, req.key);
+        let released = core
+            .locks
+            .release(&key, &req.owner)
+            .map_err(commit_failed)?;
+        if let Some(mode) = released {
+            core.sync().await?;
+            core.publish_released(&req.owner, &[(key.clone(), mode)]);
+        }
+        let removed = released.is_some();
 
         Ok(Response::new(LockResponse {
             success: removed,
@@ -54,10 +638,474 @@ impl Metadata for MetadataService {
             } else {
                 format!("No lock held for '{}'", key)
             },
+            status: if removed {
+                LockStatus::Ok.into()
+            } else {
+                LockStatus::NotHeld.into()
+            },
+            ..Default::default()
+        }))
+    }
+
+    async fn open_session(
+        &self,
+        request: Request<OpenSessionRequest>,
+    ) -> Result<Response<SessionResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let principal = Principal::of(&request);
+        let req = request.into_inner();
+        if let Some(principal) = &principal {
+            principal.check_node(&req.node)?;
+        }
+        let ttl = session_ttl(req.ttl_ms);
+        let id = core
+            .sessions
+            .open(&req.client, &req.node, ttl)
+            .map_err(commit_failed)?;
+        core.sync().await?;
+
+        Ok(Response::new(Self::session_response(&core, &id, Some(ttl))))
+    }
+
+    async fn keep_alive(
+        &self,
+        request: Request<SessionRequest>,
+    ) -> Result<Response<SessionResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let principal = Principal::of(&request);
+        let id = request.into_inner().session_id;
+        if core.sessions.is_open(&id) {
+            core.authorize_session(principal.as_ref(), &id)?;
+        }
+        let ttl = core.sessions.keep_alive(&id);
+
+        Ok(Response::new(Self::session_response(&core, &id, ttl)))
+    }
+
+    async fn close_session(
+        &self,
+        request: Request<SessionRequest>,
+    ) -> Result<Response<SessionResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let principal = Principal::of(&request);
+        let id = request.into_inner().session_id;
+        if core.sessions.is_open(&id) {
+            core.authorize_session(principal.as_ref(), &id)?;
+        }
+        let Some(released) = core.end_session(&id).map_err(commit_failed)? else {
+            return Ok(Response::new(Self::session_response(&core, &id, None)));
+        };
+        core.sync().await?;
+        core.publish_released(&id, &released);
+
+        Ok(Response::new(SessionResponse {
+            success: true,
+            message: format!("Session '{}' closed, released {} locks", id, released.len()),
+            session_id: id,
+            server_epoch: core.epoch.clone(),
+            ..Default::default()
+        }))
+    }
+
+    type WatchStream = ReceiverStream<Result<WatchEvent, Status>>;
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let principal = Principal::of(&request);
+        let session = request.into_inner().session_id;
+        core.authorize_session(principal.as_ref(), &session)?;
+        let mut changes = core.watchers.subscribe();
+        let (tx, rx) = mpsc::channel(WATCH_STREAM_BUFFER);
+
+        tokio::spawn(async move {
+            loop {
+                let change = tokio::select! {
+                    _ = tx.closed() => return,
+                    change = changes.recv() => change,
+                };
+                let event = match change {
+                    Ok(change) if change.origin == session => continue,
+                    Ok(change) => WatchEvent {
+                        key: change.key.ino,
+                        reset: false,
+                        volume: change.key.volume,
+                    },
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!("Watcher {} missed {} changes", session, missed);
+                        WatchEvent {
+                            reset: true,
+                            ..Default::default()
+                        }
+                    }
+                    // This instance stopped serving; the client reconnects
+                    // to the new leader.
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                if tx.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn allocate_inodes(
+        &self,
+        request: Request<AllocateInodesRequest>,
+    ) -> Result<Response<AllocateInodesResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let principal = Principal::of(&request);
+        let req = request.into_inner();
+        if !core.sessions.is_open(&req.session_id) {
+            return Ok(Response::new(AllocateInodesResponse {
+                success: false,
+                message: format!("Unknown session '{}'", req.session_id),
+                ..Default::default()
+            }));
+        }
+
+        core.authorize_session(principal.as_ref(), &req.session_id)?;
+        core.authorize_volume(principal.as_ref(), &req.volume)?;
+
+        let count = inode_batch(req.count);
+        let first = core
+            .inodes
+            .allocate(&req.volume, req.floor, count as u64)
+            .map_err(|e| Status::unavailable(format!("Failed to reserve inodes: {}", e)))?;
+        core.sync().await?;
+        tracing::debug!(
+            "Reserved inodes {}..{} of volume '{}' for session {}",
+            first,
+            first + count as u64,
+            req.volume,
+            req.session_id
+        );
+
+        Ok(Response::new(AllocateInodesResponse {
+            success: true,
+            message: format!("Reserved {} inodes", count),
+            first,
+            count,
+        }))
+    }
+
+    async fn create_volume(
+        &self,
+        request: Request<CreateVolumeRequest>,
+    ) -> Result<Response<VolumeResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        if let Some(principal) = Principal::of(&request) {
+            principal.check_admin()?;
+        }
+        let req = request.into_inner();
+        let spec = VolumeSpec {
+            device: req.device,
+            size: req.size,
+            offset: (!req.auto_offset).then_some(req.offset),
+        };
+        let info = core
+            .volumes
+            .create(&req.id, spec)
+            .map_err(|e| volume_status(&req.id, e))?;
+        core.sync().await?;
+
+        Ok(Response::new(VolumeResponse {
+            volume: Some(volume_message(req.id, info)),
+        }))
+    }
+
+    async fn delete_volume(
+        &self,
+        request: Request<VolumeRequest>,
+    ) -> Result<Response<DeleteVolumeResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        if let Some(principal) = Principal::of(&request) {
+            principal.check_admin()?;
+        }
+        let id = request.into_inner().id;
+        core.volumes
+            .delete(&id)
+            .map_err(|e| volume_status(&id, e))?;
+        core.sync().await?;
+
+        Ok(Response::new(DeleteVolumeResponse {}))
+    }
+
+    async fn get_volume(
+        &self,
+        request: Request<VolumeRequest>,
+    ) -> Result<Response<VolumeResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let id = request.into_inner().id;
+        let info = core
+            .volumes
+            .get(&id)
+            .ok_or_else(|| volume_status(&id, VolumeError::NotFound))?;
+
+        Ok(Response::new(VolumeResponse {
+            volume: Some(volume_message(id, info)),
+        }))
+    }
+
+    async fn list_volumes(
+        &self,
+        _request: Request<ListVolumesRequest>,
+    ) -> Result<Response<ListVolumesResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let volumes = core
+            .volumes
+            .list()
+            .into_iter()
+            .map(|(id, info)| volume_message(id, info))
+            .collect();
+
+        Ok(Response::new(ListVolumesResponse { volumes }))
+    }
+
+    async fn attach_volume(
+        &self,
+        request: Request<AttachVolumeRequest>,
+    ) -> Result<Response<VolumeResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        if let Some(principal) = Principal::of(&request) {
+            principal.check_admin()?;
+        }
+        let req = request.into_inner();
+        let info = core
+            .volumes
+            .attach(&req.id, &req.node)
+            .map_err(|e| volume_status(&req.id, e))?;
+        core.sync().await?;
+
+        Ok(Response::new(VolumeResponse {
+            volume: Some(volume_message(req.id, info)),
+        }))
+    }
+
+    async fn detach_volume(
+        &self,
+        request: Request<AttachVolumeRequest>,
+    ) -> Result<Response<VolumeResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        if let Some(principal) = Principal::of(&request) {
+            principal.check_admin()?;
+        }
+        let req = request.into_inner();
+        let info = core
+            .volumes
+            .detach(&req.id, &req.node)
+            .map_err(|e| volume_status(&req.id, e))?;
+        core.sync().await?;
+
+        Ok(Response::new(VolumeResponse {
+            volume: Some(volume_message(req.id, info)),
+        }))
+    }
+
+    async fn register(
+        &self,
+        request: Request<RegisterRequest>,
+    ) -> Result<Response<NodeResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let principal = Principal::of(&request);
+        let req = request.into_inner();
+        if req.node.is_empty() {
+            return Err(Status::invalid_argument("Node id is required"));
+        }
+        if let Some(principal) = &principal {
+            principal.check_node(&req.node)?;
+        }
+        let state = core
+            .nodes
+            .register(&req.node, &req.address)
+            .map_err(commit_failed)?;
+        core.sync().await?;
+
+        Ok(Response::new(NodeResponse {
+            success: true,
+            message: format!("Node '{}' registered", req.node),
+            state: node_state(state).into(),
+            heartbeat_interval_ms: core.nodes.heartbeat_interval().as_millis() as u64,
+        }))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> Result<Response<NodeResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let principal = Principal::of(&request);
+        let node = request.into_inner().node;
+        if let Some(principal) = &principal {
+            principal.check_node(&node)?;
+        }
+        let interval = core.nodes.heartbeat_interval().as_millis() as u64;
+
+        Ok(Response::new(match core.nodes.heartbeat(&node) {
+            Some(state) => NodeResponse {
+                success: true,
+                message: format!("Node '{}' is alive", node),
+                state: node_state(state).into(),
+                heartbeat_interval_ms: interval,
+            },
+            None => NodeResponse {
+                success: false,
+                message: format!("Node '{}' is unknown or was declared dead", node),
+                state: proto::metadata::NodeState::Dead.into(),
+                heartbeat_interval_ms: interval,
+            },
+        }))
+    }
+
+    async fn list_nodes(
+        &self,
+        _request: Request<ListNodesRequest>,
+    ) -> Result<Response<ListNodesResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let nodes = core
+            .nodes
+            .list()
+            .into_iter()
+            .map(|(id, status)| Node {
+                id,
+                address: status.address,
+                state: node_state(status.state).into(),
+                since_heartbeat_ms: status.since_heartbeat.as_millis() as u64,
+            })
+            .collect();
+
+        Ok(Response::new(ListNodesResponse { nodes }))
+    }
+
+    async fn list_locks(
+        &self,
+        _request: Request<ListLocksRequest>,
+    ) -> Result<Response<ListLocksResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let locks = core
+            .locks
+            .list()
+            .into_iter()
+            .map(|l| LockInfo {
+                key: l.key.ino,
+                volume: l.key.volume,
+                holders: l
+                    .holders
+                    .into_iter()
+                    .map(|e| lock_owner(&core, e))
+                    .collect(),
+                waiters: l
+                    .waiters
+                    .into_iter()
+                    .map(|e| lock_owner(&core, e))
+                    .collect(),
+            })
+            .collect();
+
+        Ok(Response::new(ListLocksResponse { locks }))
+    }
+
+    async fn list_sessions(
+        &self,
+        _request: Request<ListSessionsRequest>,
+    ) -> Result<Response<ListSessionsResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        let sessions = core
+            .sessions
+            .list()
+            .into_iter()
+            .map(|(id, status)| SessionInfo {
+                id,
+                client: status.client,
+                node: status.node,
+                ttl_ms: status.ttl.as_millis() as u64,
+                expires_in_ms: status.expires_in.as_millis() as u64,
+            })
+            .collect();
+
+        Ok(Response::new(ListSessionsResponse { sessions }))
+    }
+
+    async fn force_release_lock(
+        &self,
+        request: Request<ForceReleaseRequest>,
+    ) -> Result<Response<ForceReleaseResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        if let Some(principal) = Principal::of(&request) {
+            principal.check_admin()?;
+        }
+        let req = request.into_inner();
+        let key = LockKey::new(&req.volume, req.key);
+        let (released, result) = if req.owner.is_empty() {
+            core.locks.release_key(&key)
+        } else {
+            match core.locks.release(&key, &req.owner) {
+                Ok(mode) => (
+                    mode.map(|mode| vec![(req.owner.clone(), mode)])
+                        .unwrap_or_default(),
+                    Ok(()),
+                ),
+                Err(e) => (Vec::new(), Err(e)),
+            }
+        };
+        if released.is_empty() && result.is_ok() {
+            return Err(Status::not_found(format!("No such lock on {}", key)));
+        }
+        let committed = match result {
+            Ok(()) => core.sync().await,
+            Err(e) => Err(commit_failed(e)),
+        };
+        // Released grants stay released even if the rest failed, so their
+        // holders' changes are reported either way.
+        for (owner, mode) in &released {
+            tracing::warn!("Force-released lock on {} held by '{}'", key, owner);
+            core.publish_released(owner, &[(key.clone(), *mode)]);
+        }
+        committed?;
+
+        Ok(Response::new(ForceReleaseResponse {
+            released: released.into_iter().map(|(owner, _)| owner).collect(),
+        }))
+    }
+
+    async fn expire_session(
+        &self,
+        request: Request<SessionRequest>,
+    ) -> Result<Response<SessionResponse>, Status> {
+        let core = self.core().ok_or_else(|| self.not_leader())?;
+        if let Some(principal) = Principal::of(&request) {
+            principal.check_admin()?;
+        }
+        let id = request.into_inner().session_id;
+        let Some(released) = core.end_session(&id).map_err(commit_failed)? else {
+            return Err(Status::not_found(format!("Unknown session '{}'", id)));
+        };
+        core.sync().await?;
+        core.publish_released(&id, &released);
+        tracing::warn!("Expired session {}, released {} locks", id, released.len());
+
+        Ok(Response::new(SessionResponse {
+            success: true,
+            message: format!(
+                "Session '{}' expired, released {} locks",
+                id,
+                released.len()
+            ),
+            session_id: id,
+            server_epoch: core.epoch.clone(),
+            ..Default::default()
         }))
     }
 }
 
-pub fn build_metadata_server() -> MetadataServer<MetadataService> {
-    MetadataServer::new(MetadataService::default())
+/// The Metadata service, rejecting callers `auth` does not accept.
+pub fn build_metadata_server(
+    service: MetadataService,
+    auth: Authenticator,
+) -> InterceptedService<MetadataServer<MetadataService>, Authenticator> {
+    MetadataServer::with_interceptor(service, auth)
 }
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::journal::Journal;
+use crate::store::{Record, State};
+
+/// An open session, as reported to administrators.
+#[derive(Debug, Clone)]
+pub struct SessionStatus {
+    pub client: String,
+    pub node: String,
+    pub ttl: Duration,
+    /// Time left until the lease runs out unless renewed.
+    pub expires_in: Duration,
+}
+
+#[derive(Debug)]
+struct Session {
+    client: String,
+    node: String,
+    ttl: Duration,
+    expires_at: Instant,
+}
+
+/// Client sessions with renewable leases. Locks are owned by session ids, so
+/// when a lease runs out the caller releases everything the session held.
+#[derive(Debug, Default, Clone)]
+pub struct SessionManager {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    journal: Option<Arc<dyn Journal>>,
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restores the sessions recorded in `state`. Their leases restart now,
+    /// giving clients a full TTL to notice the restart and renew.
+    pub fn with_journal(journal: Arc<dyn Journal>, state: &State) -> Self {
+        let now = Instant::now();
+        let sessions = state
+            .sessions
+            .iter()
+            .map(|(id, info)| {
+                let ttl = Duration::from_millis(info.ttl_ms);
+                (
+                    id.clone(),
+                    Session {
+                        client: info.client.clone(),
+                        node: info.node.clone(),
+                        ttl,
+                        expires_at: now + ttl,
+                    },
+                )
+            })
+            .collect();
+        Self {
+            sessions: Arc::new(Mutex::new(sessions)),
+            journal: Some(journal),
+        }
+    }
+
+    /// Journals `record` before the change is applied; a session change we
+    /// could not persist is not made.
+    fn journal(&self, record: Record) -> io::Result<()> {
+        match &self.journal {
+            Some(journal) => journal.append(record),
+            None => Ok(()),
+        }
+    }
+
+    /// Opens a session for `client`, belonging to `node` if not empty.
+    pub fn open(&self, client: &str, node: &str, ttl: Duration) -> io::Result<String> {
+        let id = uuid::Uuid::new_v4().to_string();
+        self.journal(Record::SessionOpened {
+            id: id.clone(),
+            client: client.to_string(),
+            node: node.to_string(),
+            ttl_ms: ttl.as_millis() as u64,
+        })?;
+        self.sessions.lock().unwrap().insert(
+            id.clone(),
+            Session {
+                client: client.to_string(),
+                node: node.to_string(),
+                ttl,
+                expires_at: Instant::now() + ttl,
+            },
+        );
+        tracing::info!("Opened session {} for '{}'", id, client);
+        Ok(id)
+    }
+
+    /// Extends the lease of `id`. Returns the TTL, or `None` if the session
+    /// does not exist (anymore) or its lease already ran out.
+    pub fn keep_alive(&self, id: &str) -> Option<Duration> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(id)?;
+        let now = Instant::now();
+        if session.expires_at <= now {
+            return None;
+        }
+        session.expires_at = now + session.ttl;
+        Some(session.ttl)
+    }
+
+    /// Node the session belongs to, empty if none; `None` if it is not open.
+    pub fn node(&self, id: &str) -> Option<String> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|s| s.node.clone())
+    }
+
+    pub fn is_open(&self, id: &str) -> bool {
+        self.sessions.lock().unwrap().contains_key(id)
+    }
+
+    /// Closes `id`. Returns whether it was open; fails, leaving it open, if
+    /// closing it cannot be journaled.
+    pub fn close(&self, id: &str) -> io::Result<bool> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if !sessions.contains_key(id) {
+            return Ok(false);
+        }
+        self.journal(Record::SessionClosed { id: id.to_string() })?;
+        let session = sessions.remove(id).unwrap();
+        tracing::info!("Closed session {} for '{}'", id, session.client);
+        Ok(true)
+    }
+
+    /// Every open session, ordered by id.
+    pub fn list(&self) -> Vec<(String, SessionStatus)> {
+        let now = Instant::now();
+        let mut sessions: Vec<(String, SessionStatus)> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, s)| {
+                let status = SessionStatus {
+                    client: s.client.clone(),
+                    node: s.node.clone(),
+                    ttl: s.ttl,
+                    expires_in: s.expires_at.saturating_duration_since(now),
+                };
+                (id.clone(), status)
+            })
+            .collect();
+        sessions.sort_by(|a, b| a.0.cmp(&b.0));
+        sessions
+    }
+
+    /// Ids of the sessions of `node`.
+    pub fn of_node(&self, node: &str) -> Vec<String> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, s)| !node.is_empty() && s.node == node)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Ids of the sessions whose lease ran out. They can no longer be kept
+    /// alive, but stay open until closed, so the caller can release their
+    /// locks first.
+    pub fn expired(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, s)| s.expires_at <= now)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,395 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const LOG_FILE: &str = "journal.log";
+const SNAPSHOT_FILE: &str = "snapshot.bin";
+/// Number of records appended to the log before it is folded into a new
+/// snapshot and truncated.
+const SNAPSHOT_EVERY: u64 = 1024;
+
+/// One durable change to the coordination state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Record {
+    SessionOpened {
+        id: String,
+        client: String,
+        node: String,
+        ttl_ms: u64,
+    },
+    SessionClosed {
+        id: String,
+    },
+    LockGranted {
+        key: LockKey,
+        owner: String,
+        shared: bool,
+        token: u64,
+    },
+    LockReleased {
+        key: LockKey,
+        owner: String,
+    },
+    /// Inode numbers of `volume` up to and including `last` have been
+    /// handed out.
+    InodesAllocated {
+        volume: String,
+        last: u64,
+    },
+    VolumeCreated {
+        id: String,
+        device: String,
+        size: u64,
+        offset: u64,
+    },
+    VolumeDeleted {
+        id: String,
+    },
+    VolumeAttached {
+        id: String,
+        node: String,
+    },
+    VolumeDetached {
+        id: String,
+        node: String,
+    },
+    NodeRegistered {
+        id: String,
+        address: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub client: String,
+    /// Registered node the session belongs to; empty if none.
+    pub node: String,
+    pub ttl_ms: u64,
+}
+
+/// What a lock protects: inode `ino` of `volume`. Inode numbers are only
+/// unique per volume; the empty volume is a filesystem spanning a whole
+/// device.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LockKey {
+    pub volume: String,
+    pub ino: u64,
+}
+
+impl LockKey {
+    pub fn new(volume: &str, ino: u64) -> Self {
+        Self {
+            volume: volume.to_string(),
+            ino,
+        }
+    }
+}
+
+impl std::fmt::Display for LockKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.volume.is_empty() {
+            write!(f, "{}", self.ino)
+        } else {
+            write!(f, "{}/{}", self.volume, self.ino)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    pub owner: String,
+    pub shared: bool,
+    pub token: u64,
+}
+
+/// Where a volume lives on shared storage and who uses it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VolumeInfo {
+    pub device: String,
+    pub size: u64,
+    pub offset: u64,
+    /// Nodes the volume is attached to.
+    pub attached: BTreeSet<String>,
+}
+
+/// Everything the metadata-service must remember across restarts: open
+/// sessions, current lock grants, the last fencing token of every key, the
+/// highest inode number handed out per volume, the volume registry and the
+/// registered nodes with their addresses.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct State {
+    pub sessions: HashMap<String, SessionInfo>,
+    pub grants: HashMap<LockKey, Vec<Grant>>,
+    pub tokens: HashMap<LockKey, u64>,
+    pub last_inodes: HashMap<String, u64>,
+    pub volumes: HashMap<String, VolumeInfo>,
+    pub nodes: HashMap<String, String>,
+}
+
+impl State {
+    pub fn apply(&mut self, record: &Record) {
+        match record {
+            Record::SessionOpened {
+                id,
+                client,
+                node,
+                ttl_ms,
+            } => {
+                self.sessions.insert(
+                    id.clone(),
+                    SessionInfo {
+                        client: client.clone(),
+                        node: node.clone(),
+                        ttl_ms: *ttl_ms,
+                    },
+                );
+            }
+            Record::SessionClosed { id } => {
+                self.sessions.remove(id);
+            }
+            Record::LockGranted {
+                key,
+                owner,
+                shared,
+                token,
+            } => {
+                self.grants.entry(key.clone()).or_default().push(Grant {
+                    owner: owner.clone(),
+                    shared: *shared,
+                    token: *token,
+                });
+                let last = self.tokens.entry(key.clone()).or_default();
+                *last = (*last).max(*token);
+            }
+            Record::LockReleased { key, owner } => {
+                if let Some(grants) = self.grants.get_mut(key) {
+                    if let Some(pos) = grants.iter().position(|g| g.owner == *owner) {
+                        grants.remove(pos);
+                    }
+                    if grants.is_empty() {
+                        self.grants.remove(key);
+                    }
+                }
+            }
+            Record::InodesAllocated { volume, last } => {
+                let current = self.last_inodes.entry(volume.clone()).or_default();
+                *current = (*current).max(*last);
+            }
+            Record::VolumeCreated {
+                id,
+                device,
+                size,
+                offset,
+            } => {
+                self.volumes.insert(
+                    id.clone(),
+                    VolumeInfo {
+                        device: device.clone(),
+                        size: *size,
+                        offset: *offset,
+                        attached: BTreeSet::new(),
+                    },
+                );
+            }
+            Record::VolumeDeleted { id } => {
+                self.volumes.remove(id);
+            }
+            Record::VolumeAttached { id, node } => {
+                if let Some(volume) = self.volumes.get_mut(id) {
+                    volume.attached.insert(node.clone());
+                }
+            }
+            Record::VolumeDetached { id, node } => {
+                if let Some(volume) = self.volumes.get_mut(id) {
+                    volume.attached.remove(node);
+                }
+            }
+            Record::NodeRegistered { id, address } => {
+                self.nodes.insert(id.clone(), address.clone());
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty() && self.grants.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    last_seq: u64,
+    state: State,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    seq: u64,
+    record: Record,
+}
+
+#[derive(Debug)]
+struct Inner {
+    log: File,
+    state: State,
+    seq: u64,
+    since_snapshot: u64,
+}
+
+/// Append-only journal of [`Record`]s with periodic snapshots.
+///
+/// Every record is written length-prefixed and synced before `append`
+/// returns. Records carry a sequence number so a log that outlived its
+/// snapshot (crash between the two steps of compaction) replays cleanly.
+#[derive(Debug)]
+pub struct Store {
+    dir: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl Store {
+    /// Opens (or creates) the store in `dir` and replays it.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let (mut seq, mut state) = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(buf) => {
+                let snapshot: Snapshot = bincode::deserialize(&buf).map_err(invalid_data)?;
+                (snapshot.last_seq, snapshot.state)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, State::default()),
+            Err(e) => return Err(e),
+        };
+
+        let mut log = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOG_FILE))?;
+        let mut buf = Vec::new();
+        log.read_to_end(&mut buf)?;
+
+        let mut offset = 0;
+        let mut replayed = 0;
+        while offset + 4 <= buf.len() {
+            let len = u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap()) as usize;
+            let Some(bytes) = buf.get(offset + 4..offset + 4 + len) else {
+                break;
+            };
+            let Ok(entry) = bincode::deserialize::<Entry>(bytes) else {
+                break;
+            };
+            if entry.seq > seq {
+                state.apply(&entry.record);
+                seq = entry.seq;
+                replayed += 1;
+            }
+            offset += 4 + len;
+        }
+
+        if offset < buf.len() {
+            // A torn record at the tail from a crash mid-append; drop it.
+            tracing::warn!(
+                "Discarding {} trailing bytes of {}",
+                buf.len() - offset,
+                LOG_FILE
+            );
+            log.set_len(offset as u64)?;
+        }
+        log.seek(SeekFrom::Start(offset as u64))?;
+
+        tracing::info!(
+            "Opened store in {} (seq {}, {} records replayed)",
+            dir.display(),
+            seq,
+            replayed
+        );
+
+        Ok(Self {
+            dir,
+            inner: Mutex::new(Inner {
+                log,
+                state,
+                seq,
+                since_snapshot: replayed,
+            }),
+        })
+    }
+
+    /// Copy of the state as of the last appended record.
+    pub fn state(&self) -> State {
+        self.inner.lock().unwrap().state.clone()
+    }
+
+    pub fn append(&self, record: Record) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = Entry {
+            seq: inner.seq + 1,
+            record,
+        };
+        let bytes = bincode::serialize(&entry).map_err(invalid_data)?;
+
+        let mut framed = Vec::with_capacity(4 + bytes.len());
+        framed.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        framed.extend_from_slice(&bytes);
+        let end = inner.log.stream_position()?;
+        if let Err(e) = inner
+            .log
+            .write_all(&framed)
+            .and_then(|()| inner.log.sync_data())
+        {
+            // Cut off what made it to the file, so the next record does not
+            // follow a torn one.
+            if let Err(e) = inner.log.set_len(end) {
+                tracing::error!("Failed to truncate the journal after a failed write: {}", e);
+            }
+            inner.log.seek(SeekFrom::Start(end))?;
+            return Err(e);
+        }
+
+        inner.seq = entry.seq;
+        inner.state.apply(&entry.record);
+        inner.since_snapshot += 1;
+
+        // The record is durable and applied by now; a failed snapshot only
+        // leaves a longer log, and is tried again with the next record.
+        if inner.since_snapshot >= SNAPSHOT_EVERY {
+            if let Err(e) = self.snapshot(&mut inner) {
+                tracing::warn!("Failed to write snapshot: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self, inner: &mut Inner) -> io::Result<()> {
+        let snapshot = Snapshot {
+            last_seq: inner.seq,
+            state: inner.state.clone(),
+        };
+        let bytes = bincode::serialize(&snapshot).map_err(invalid_data)?;
+
+        let tmp = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE))?;
+        // The rename has to be durable before the log it replaces is gone.
+        File::open(&self.dir)?.sync_all()?;
+
+        inner.log.set_len(0)?;
+        inner.log.seek(SeekFrom::Start(0))?;
+        inner.log.sync_all()?;
+        inner.since_snapshot = 0;
+
+        tracing::debug!("Wrote snapshot at seq {}", inner.seq);
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+//! Logging and tracing: events go to stderr, filtered by `RUST_LOG`, and
+//! spans optionally to an OpenTelemetry collector over OTLP. Callers pass
+//! their trace context in W3C `traceparent` request metadata.
+
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TraceError;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{trace, Resource};
+use tonic::codegen::http;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::{EnvFilter, Targets};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Only requests of these services get a span; Raft and health checks
+/// would drown the traces worth looking at.
+const TRACED_SERVICES: &[&str] = &["/metadata.Metadata/"];
+
+/// Flushes the spans not exported yet when dropped.
+pub struct Telemetry {
+    exporting: bool,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if self.exporting {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
+}
+
+/// Installs the global subscriber. With `otlp_endpoint` (e.g.
+/// `http://otel-collector:4317`) spans of this crate are exported there,
+/// as `service.name` `metadata-service` unless `OTEL_SERVICE_NAME` says
+/// otherwise.
+pub fn init(otlp_endpoint: Option<&str>) -> Result<Telemetry, TraceError> {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let fmt = tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env());
+    let otel = match otlp_endpoint {
+        Some(endpoint) => {
+            let resource = Resource::default().merge(&Resource::new([KeyValue::new(
+                "service.name",
+                std::env::var("OTEL_SERVICE_NAME")
+                    .unwrap_or_else(|_| "metadata-service".to_string()),
+            )]));
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(resource))
+                .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(tracer)
+                    .with_filter(
+                        Targets::new().with_target("metadata_service", tracing::Level::INFO),
+                    ),
+            )
+        }
+        None => None,
+    };
+
+    let _ = tracing_subscriber::registry()
+        .with(fmt)
+        .with(otel)
+        .try_init();
+    if let Some(endpoint) = otlp_endpoint {
+        tracing::info!("Exporting traces to {}", endpoint);
+    }
+    Ok(Telemetry {
+        exporting: otlp_endpoint.is_some(),
+    })
+}
+
+struct HeaderExtractor<'a>(&'a http::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Span of one gRPC request, continuing the trace of the caller. Passed to
+/// [`tonic::transport::Server::trace_fn`].
+pub fn request_span(request: &http::Request<()>) -> tracing::Span {
+    let path = request.uri().path();
+    if !TRACED_SERVICES
+        .iter()
+        .any(|service| path.starts_with(service))
+    {
+        return tracing::Span::none();
+    }
+    let span = tracing::info_span!(
+        "grpc.request",
+        otel.name = path.trim_start_matches('/'),
+        otel.kind = "server",
+        rpc.system = "grpc",
+    );
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(parent);
+    span
+}
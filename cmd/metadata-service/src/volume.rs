@@ -0,0 +1,354 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::journal::Journal;
+use crate::store::{Record, State, VolumeInfo};
+
+#[derive(Debug)]
+pub enum VolumeError {
+    NotFound,
+    /// A volume with the id but different parameters exists.
+    AlreadyExists,
+    /// The requested range overlaps the named volume on the same device.
+    Overlaps(String),
+    /// The volume is still attached to these nodes.
+    Attached(Vec<String>),
+    Invalid(String),
+    Journal(io::Error),
+}
+
+impl std::fmt::Display for VolumeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VolumeError::NotFound => write!(f, "volume not found"),
+            VolumeError::AlreadyExists => {
+                write!(f, "volume already exists with different parameters")
+            }
+            VolumeError::Overlaps(other) => write!(f, "overlaps volume '{}'", other),
+            VolumeError::Attached(nodes) => {
+                write!(f, "volume is attached to {}", nodes.join(", "))
+            }
+            VolumeError::Invalid(msg) => write!(f, "{}", msg),
+            VolumeError::Journal(e) => write!(f, "failed to persist volume change: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for VolumeError {}
+
+/// A volume to create. With no `offset` the volume is placed in the first
+/// gap on the device that fits it.
+#[derive(Debug, Clone)]
+pub struct VolumeSpec {
+    pub device: String,
+    pub size: u64,
+    pub offset: Option<u64>,
+}
+
+/// Volumes carved out of shared devices and the nodes they are attached to.
+///
+/// Every call is idempotent, as retried provisioning requests expect:
+/// creating a volume that exists with the same parameters, deleting one
+/// that does not exist or attaching it twice all succeed.
+#[derive(Debug, Default, Clone)]
+pub struct VolumeRegistry {
+    volumes: Arc<Mutex<HashMap<String, VolumeInfo>>>,
+    journal: Option<Arc<dyn Journal>>,
+}
+
+impl VolumeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_journal(journal: Arc<dyn Journal>, state: &State) -> Self {
+        Self {
+            volumes: Arc::new(Mutex::new(state.volumes.clone())),
+            journal: Some(journal),
+        }
+    }
+
+    /// Journals `record` before the change is applied; a registry change we
+    /// could not persist must not be acknowledged.
+    fn journal(&self, record: Record) -> Result<(), VolumeError> {
+        match &self.journal {
+            Some(journal) => journal.append(record).map_err(VolumeError::Journal),
+            None => Ok(()),
+        }
+    }
+
+    pub fn create(&self, id: &str, spec: VolumeSpec) -> Result<VolumeInfo, VolumeError> {
+        if id.is_empty() || spec.device.is_empty() || spec.size == 0 {
+            return Err(VolumeError::Invalid(
+                "volume id, device and size are required".to_string(),
+            ));
+        }
+
+        let mut volumes = self.volumes.lock().unwrap();
+        if let Some(existing) = volumes.get(id) {
+            let same = existing.device == spec.device
+                && existing.size == spec.size
+                && spec.offset.is_none_or(|offset| offset == existing.offset);
+            return if same {
+                Ok(existing.clone())
+            } else {
+                Err(VolumeError::AlreadyExists)
+            };
+        }
+
+        let mut taken: Vec<(u64, u64, &String)> = volumes
+            .iter()
+            .filter(|(_, v)| v.device == spec.device)
+            .map(|(other, v)| (v.offset, v.offset + v.size, other))
+            .collect();
+        taken.sort();
+
+        let offset = match spec.offset {
+            Some(offset) => {
+                let end = offset
+                    .checked_add(spec.size)
+                    .ok_or_else(|| VolumeError::Invalid("volume exceeds 2^64 bytes".into()))?;
+                if let Some((_, _, other)) = taken
+                    .iter()
+                    .find(|(start, stop, _)| offset < *stop && *start < end)
+                {
+                    return Err(VolumeError::Overlaps((*other).clone()));
+                }
+                offset
+            }
+            None => {
+                let mut offset = 0;
+                for (start, stop, _) in &taken {
+                    if offset + spec.size <= *start {
+                        break;
+                    }
+                    offset = offset.max(*stop);
+                }
+                offset
+            }
+        };
+
+        self.journal(Record::VolumeCreated {
+            id: id.to_string(),
+            device: spec.device.clone(),
+            size: spec.size,
+            offset,
+        })?;
+        let volume = VolumeInfo {
+            device: spec.device,
+            size: spec.size,
+            offset,
+            attached: BTreeSet::new(),
+        };
+        volumes.insert(id.to_string(), volume.clone());
+        tracing::info!(
+            "Created volume {} on {} at offset {} ({} bytes)",
+            id,
+            volume.device,
+            offset,
+            volume.size
+        );
+        Ok(volume)
+    }
+
+    /// Removes the volume unless a node still has it attached.
+    pub fn delete(&self, id: &str) -> Result<(), VolumeError> {
+        let mut volumes = self.volumes.lock().unwrap();
+        let Some(volume) = volumes.get(id) else {
+            return Ok(());
+        };
+        if !volume.attached.is_empty() {
+            return Err(VolumeError::Attached(
+                volume.attached.iter().cloned().collect(),
+            ));
+        }
+
+        self.journal(Record::VolumeDeleted { id: id.to_string() })?;
+        volumes.remove(id);
+        tracing::info!("Deleted volume {}", id);
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<VolumeInfo> {
+        self.volumes.lock().unwrap().get(id).cloned()
+    }
+
+    /// All volumes, ordered by id.
+    pub fn list(&self) -> Vec<(String, VolumeInfo)> {
+        let mut volumes: Vec<_> = self
+            .volumes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, v)| (id.clone(), v.clone()))
+            .collect();
+        volumes.sort_by(|a, b| a.0.cmp(&b.0));
+        volumes
+    }
+
+    pub fn attach(&self, id: &str, node: &str) -> Result<VolumeInfo, VolumeError> {
+        if node.is_empty() {
+            return Err(VolumeError::Invalid("node is required".to_string()));
+        }
+        let mut volumes = self.volumes.lock().unwrap();
+        let volume = volumes.get_mut(id).ok_or(VolumeError::NotFound)?;
+        if !volume.attached.contains(node) {
+            self.journal(Record::VolumeAttached {
+                id: id.to_string(),
+                node: node.to_string(),
+            })?;
+            volume.attached.insert(node.to_string());
+            tracing::info!("Attached volume {} to {}", id, node);
+        }
+        Ok(volume.clone())
+    }
+
+    /// Detaches every volume attached to `node` and returns their ids.
+    pub fn detach_node(&self, node: &str) -> Result<Vec<String>, VolumeError> {
+        let ids: Vec<String> = self
+            .volumes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, v)| v.attached.contains(node))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &ids {
+            match self.detach(id, node) {
+                // Deleted meanwhile; nothing to detach.
+                Ok(_) | Err(VolumeError::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ids)
+    }
+
+    pub fn detach(&self, id: &str, node: &str) -> Result<VolumeInfo, VolumeError> {
+        let mut volumes = self.volumes.lock().unwrap();
+        let volume = volumes.get_mut(id).ok_or(VolumeError::NotFound)?;
+        if volume.attached.contains(node) {
+            self.journal(Record::VolumeDetached {
+                id: id.to_string(),
+                node: node.to_string(),
+            })?;
+            volume.attached.remove(node);
+            tracing::info!("Detached volume {} from {}", id, node);
+        }
+        Ok(volume.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Store;
+
+    const MIB: u64 = 1 << 20;
+
+    fn spec(size: u64, offset: Option<u64>) -> VolumeSpec {
+        VolumeSpec {
+            device: "/dev/shared".to_string(),
+            size,
+            offset,
+        }
+    }
+
+    #[test]
+    fn overlapping_volumes_are_rejected() {
+        let registry = VolumeRegistry::new();
+        registry.create("a", spec(4 * MIB, Some(MIB))).unwrap();
+
+        for offset in [0, MIB, 4 * MIB] {
+            match registry.create("b", spec(2 * MIB, Some(offset))) {
+                Err(VolumeError::Overlaps(other)) => assert_eq!(other, "a"),
+                result => panic!("offset {}: {:?}", offset, result),
+            }
+        }
+        // Touching is fine, as are other devices.
+        registry.create("b", spec(MIB, Some(0))).unwrap();
+        registry.create("c", spec(MIB, Some(5 * MIB))).unwrap();
+        let elsewhere = VolumeSpec {
+            device: "/dev/other".to_string(),
+            ..spec(MIB, Some(MIB))
+        };
+        registry.create("d", elsewhere).unwrap();
+    }
+
+    #[test]
+    fn placement_fills_the_first_gap_that_fits() {
+        let registry = VolumeRegistry::new();
+        registry.create("a", spec(MIB, Some(0))).unwrap();
+        registry.create("b", spec(MIB, Some(3 * MIB))).unwrap();
+        assert_eq!(
+            registry.create("c", spec(2 * MIB, None)).unwrap().offset,
+            MIB
+        );
+        assert_eq!(
+            registry.create("d", spec(MIB, None)).unwrap().offset,
+            4 * MIB
+        );
+    }
+
+    #[test]
+    fn create_is_idempotent_only_with_the_same_parameters() {
+        let registry = VolumeRegistry::new();
+        let created = registry.create("a", spec(MIB, None)).unwrap();
+        assert_eq!(registry.create("a", spec(MIB, None)).unwrap(), created);
+        assert!(matches!(
+            registry.create("a", spec(2 * MIB, None)),
+            Err(VolumeError::AlreadyExists)
+        ));
+    }
+
+    #[test]
+    fn attached_volume_cannot_be_deleted() {
+        let registry = VolumeRegistry::new();
+        registry.create("a", spec(MIB, None)).unwrap();
+        assert!(matches!(
+            registry.attach("missing", "n1"),
+            Err(VolumeError::NotFound)
+        ));
+
+        registry.attach("a", "n1").unwrap();
+        let volume = registry.attach("a", "n2").unwrap();
+        assert_eq!(volume.attached.len(), 2);
+        match registry.delete("a") {
+            Err(VolumeError::Attached(nodes)) => assert_eq!(nodes, ["n1", "n2"]),
+            result => panic!("{:?}", result),
+        }
+
+        registry.detach("a", "n1").unwrap();
+        assert_eq!(registry.detach_node("n2").unwrap(), ["a"]);
+        assert!(registry.get("a").unwrap().attached.is_empty());
+        registry.delete("a").unwrap();
+        assert!(registry.get("a").is_none());
+        registry.delete("a").unwrap();
+    }
+
+    #[test]
+    fn registry_survives_a_restart() {
+        let dir = std::env::temp_dir().join(format!("metadata-volumes-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        {
+            let store = Arc::new(Store::open(&dir).unwrap());
+            let registry = VolumeRegistry::with_journal(store.clone(), &store.state());
+            registry.create("a", spec(MIB, None)).unwrap();
+            registry.create("b", spec(MIB, None)).unwrap();
+            registry.create("c", spec(MIB, None)).unwrap();
+            registry.attach("a", "n1").unwrap();
+            registry.attach("b", "n1").unwrap();
+            registry.detach("b", "n1").unwrap();
+            registry.delete("c").unwrap();
+        }
+
+        let store = Arc::new(Store::open(&dir).unwrap());
+        let registry = VolumeRegistry::with_journal(store.clone(), &store.state());
+        let ids: Vec<String> = registry.list().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, ["a", "b"]);
+        assert!(registry.get("a").unwrap().attached.contains("n1"));
+        assert!(registry.get("b").unwrap().attached.is_empty());
+        assert_eq!(registry.get("b").unwrap().offset, MIB);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,63 @@
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use crate::store::LockKey;
+
+/// Changes buffered per watcher before it is considered lagging and told to
+/// drop everything it cached.
+const WATCH_BUFFER: usize = 1024;
+
+/// A key that `origin` may have modified.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub key: LockKey,
+    /// Session whose exclusive lock on `key` was released.
+    pub origin: String,
+}
+
+/// Fans changes out to every client watching for them.
+#[derive(Debug, Clone)]
+pub struct Watchers {
+    /// Taken out by [`Watchers::close`], which ends all subscriptions.
+    tx: Arc<Mutex<Option<broadcast::Sender<Change>>>>,
+}
+
+impl Default for Watchers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Watchers {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(WATCH_BUFFER);
+        Self {
+            tx: Arc::new(Mutex::new(Some(tx))),
+        }
+    }
+
+    pub fn publish(&self, key: LockKey, origin: &str) {
+        if let Some(tx) = &*self.tx.lock().unwrap() {
+            // Nobody watching is not an error.
+            let _ = tx.send(Change {
+                key,
+                origin: origin.to_string(),
+            });
+        }
+    }
+
+    /// Subscribes to future changes. Returns a receiver that is already
+    /// closed if the watchers were.
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        match &*self.tx.lock().unwrap() {
+            Some(tx) => tx.subscribe(),
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    /// Ends every subscription, e.g. when this instance stops leading and
+    /// clients have to watch the new leader instead.
+    pub fn close(&self) {
+        self.tx.lock().unwrap().take();
+    }
+}
@@ -0,0 +1,206 @@
+//! Runs a three-member metadata-service group on localhost, kills the leader
+//! and checks that the locks it granted survive on the new leader. Members
+//! and the test share the certificate in `tests/tls`, valid for 127.0.0.1.
+
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use proto::metadata::{
+    metadata_client::MetadataClient, LockRequest, LockStatus, OpenSessionRequest, SessionRequest,
+};
+use tokio::time::Instant;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+
+const NODES: usize = 3;
+const KEY: u64 = 42;
+
+struct Node {
+    addr: String,
+    child: Option<Child>,
+}
+
+impl Node {
+    fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Kills the processes and removes their data when the test ends, however
+/// it ends.
+struct Cluster {
+    nodes: Vec<Node>,
+    dir: PathBuf,
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for node in &mut self.nodes {
+            node.kill();
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn tls_file(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/tls")
+        .join(name)
+}
+
+async fn connect(addr: &str) -> Result<MetadataClient<Channel>, tonic::transport::Error> {
+    let read = |name| std::fs::read(tls_file(name)).unwrap();
+    let tls = ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(read("ca.crt")))
+        .identity(Identity::from_pem(read("node.crt"), read("node.key")));
+    let channel = Endpoint::from_shared(addr.to_string())?
+        .tls_config(tls)?
+        .connect()
+        .await?;
+    Ok(MetadataClient::new(channel))
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn start_cluster() -> Cluster {
+    let dir = std::env::temp_dir().join(format!("metadata-raft-failover-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let ports: Vec<u16> = (0..NODES).map(|_| free_port()).collect();
+    let peers = ports
+        .iter()
+        .enumerate()
+        .map(|(i, port)| format!("n{}=https://127.0.0.1:{}", i, port))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let nodes = ports
+        .iter()
+        .enumerate()
+        .map(|(i, port)| {
+            let child = Command::new(env!("CARGO_BIN_EXE_metadata-service"))
+                .arg(format!("--node-id=n{}", i))
+                .arg(format!("--peers={}", peers))
+                .arg(format!("--listen=127.0.0.1:{}", port))
+                .arg(format!("--data-dir={}", dir.join(i.to_string()).display()))
+                .arg(format!("--tls-cert={}", tls_file("node.crt").display()))
+                .arg(format!("--tls-key={}", tls_file("node.key").display()))
+                .arg(format!("--tls-ca={}", tls_file("ca.crt").display()))
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("failed to start metadata-service");
+            Node {
+                addr: format!("https://127.0.0.1:{}", port),
+                child: Some(child),
+            }
+        })
+        .collect();
+
+    Cluster { nodes, dir }
+}
+
+/// Polls the live members until one of them accepts a new session, and
+/// returns its index, a client for it and the session id.
+async fn find_leader(cluster: &Cluster) -> (usize, MetadataClient<Channel>, String) {
+    let deadline = Instant::now() + Duration::from_secs(15);
+    while Instant::now() < deadline {
+        for (i, node) in cluster.nodes.iter().enumerate() {
+            if node.child.is_none() {
+                continue;
+            }
+            let Ok(mut client) = connect(&node.addr).await else {
+                continue;
+            };
+            let req = OpenSessionRequest {
+                client: "raft-failover-test".to_string(),
+                ttl_ms: 30_000,
+                ..Default::default()
+            };
+            if let Ok(resp) = client.open_session(req).await {
+                return (i, client, resp.into_inner().session_id);
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("no leader elected");
+}
+
+#[tokio::test]
+async fn granted_locks_survive_leader_failure() {
+    let mut cluster = start_cluster();
+
+    let (leader, mut client, holder) = find_leader(&cluster).await;
+    let granted = client
+        .acquire_lock(LockRequest {
+            key: KEY,
+            owner: holder.clone(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(granted.success, "{}", granted.message);
+
+    // The grant was acknowledged, so a majority has it. Losing the leader
+    // must not lose it.
+    cluster.nodes[leader].kill();
+
+    let (new_leader, mut client, other) = find_leader(&cluster).await;
+    assert_ne!(new_leader, leader);
+
+    let contended = client
+        .acquire_lock(LockRequest {
+            key: KEY,
+            owner: other,
+            timeout_ms: 500,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(contended.status(), LockStatus::Timeout);
+
+    let session = client
+        .keep_alive(SessionRequest {
+            session_id: holder.clone(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(session.success, "{}", session.message);
+
+    let reclaimed = client
+        .acquire_lock(LockRequest {
+            key: KEY,
+            owner: holder.clone(),
+            reclaim: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(reclaimed.success, "{}", reclaimed.message);
+    assert_eq!(reclaimed.fencing_token, granted.fencing_token);
+
+    let released = client
+        .release_lock(LockRequest {
+            key: KEY,
+            owner: holder,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(released.success, "{}", released.message);
+}
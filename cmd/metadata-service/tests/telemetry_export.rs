@@ -0,0 +1,107 @@
+//! Exports request spans to a stand-in OTLP collector on localhost and
+//! checks they arrive under the caller's trace.
+
+use std::time::Duration;
+
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+    TraceService, TraceServiceServer,
+};
+use opentelemetry_proto::tonic::collector::trace::v1::{
+    ExportTraceServiceRequest, ExportTraceServiceResponse,
+};
+use tokio::sync::mpsc;
+use tonic::codegen::http;
+use tonic::{Request, Response, Status};
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const PARENT_ID: &str = "00f067aa0ba902b7";
+
+/// Accepts every export and passes the spans on.
+struct Collector {
+    spans: mpsc::UnboundedSender<ExportTraceServiceRequest>,
+}
+
+#[tonic::async_trait]
+impl TraceService for Collector {
+    async fn export(
+        &self,
+        request: Request<ExportTraceServiceRequest>,
+    ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+        let _ = self.spans.send(request.into_inner());
+        Ok(Response::new(ExportTraceServiceResponse {
+            partial_success: None,
+        }))
+    }
+}
+
+fn request(path: &str) -> http::Request<()> {
+    http::Request::builder()
+        .uri(format!("http://metadata{}", path))
+        .header("traceparent", format!("00-{}-{}-01", TRACE_ID, PARENT_ID))
+        .body(())
+        .unwrap()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn request_spans_reach_the_collector() {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(TraceServiceServer::new(Collector { spans: tx }))
+            .serve(addr),
+    );
+    // Let the collector start listening before anything is exported.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let telemetry = metadata_service::telemetry::init(Some(&format!("http://{}", addr))).unwrap();
+    drop(metadata_service::telemetry::request_span(&request(
+        "/metadata.Metadata/AcquireLock",
+    )));
+    // Not a traced service.
+    drop(metadata_service::telemetry::request_span(&request(
+        "/grpc.health.v1.Health/Check",
+    )));
+    // Dropping it flushes the spans, which blocks.
+    tokio::task::spawn_blocking(move || drop(telemetry))
+        .await
+        .unwrap();
+
+    let export = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+        .await
+        .expect("No spans exported")
+        .unwrap();
+    let spans: Vec<_> = export
+        .resource_spans
+        .iter()
+        .flat_map(|r| &r.scope_spans)
+        .flat_map(|s| &s.spans)
+        .collect();
+    assert_eq!(spans.len(), 1, "{:?}", spans);
+    let span = spans[0];
+    assert_eq!(span.name, "metadata.Metadata/AcquireLock");
+    assert_eq!(hex(&span.trace_id), TRACE_ID);
+    assert_eq!(hex(&span.parent_span_id), PARENT_ID);
+
+    let service = export.resource_spans[0]
+        .resource
+        .as_ref()
+        .unwrap()
+        .attributes
+        .iter()
+        .find(|kv| kv.key == "service.name")
+        .and_then(|kv| kv.value.clone())
+        .and_then(|v| v.value);
+    assert!(
+        format!("{:?}", service).contains("metadata-service"),
+        "{:?}",
+        service
+    );
+}